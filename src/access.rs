@@ -0,0 +1,96 @@
+use crate::error::ApiError;
+use crate::proxy::{self, networks_contain, parse_networks};
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use ipnet::IpNet;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+/// Networks allowed to reach the operator endpoints: backups, scrubbing, the audit log and webhooks
+#[derive(Clone, Debug, Default)]
+pub struct AdminNetworks(Vec<IpNet>);
+
+impl AdminNetworks {
+    /// Parses addresses such as `127.0.0.1` and networks such as `192.168.1.0/24`
+    pub fn parse(items: &[String]) -> Result<Self, String> {
+        parse_networks(items).map(AdminNetworks)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        networks_contain(&self.0, ip)
+    }
+}
+
+/// Taken by the handlers of operator endpoints, which can then only be reached by clients in
+/// `admin.allowed_networks`.
+///
+/// The client is the address forwarded by a trusted proxy where there is one, so a proxy on the
+/// same host does not make every request it forwards look local.
+pub struct AdminAccess;
+
+impl FromRequest for AdminAccess {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let allowed = req.app_data::<web::Data<AdminNetworks>>();
+        ready(match (allowed, proxy::client_addr(req)) {
+            (Some(allowed), Some(ip)) if allowed.contains(ip) => Ok(AdminAccess),
+            // The request span already names the client, in the form `privacy.ip_mode` allows
+            _ => {
+                tracing::warn!("operator endpoint refused");
+                Err(ApiError::Forbidden)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::TrustedProxies;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+
+    async fn backup(_: AdminAccess) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_operator_endpoints_only_answer_allowed_networks() {
+        let allowed = AdminNetworks::parse(&["127.0.0.1".to_string(), "::1".to_string()]).unwrap();
+        let trusted = TrustedProxies::parse(&["127.0.0.1".to_string()]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(allowed))
+                .app_data(web::Data::new(trusted))
+                .route("/admin/backup", web::get().to(backup)),
+        )
+        .await;
+        let call = |peer: &str, forwarded: Option<&str>| {
+            let mut req = test::TestRequest::get().uri("/admin/backup").peer_addr(peer.parse().unwrap());
+            if let Some(forwarded) = forwarded {
+                req = req.insert_header(("X-Forwarded-For", forwarded));
+            }
+            test::call_service(&app, req.to_request())
+        };
+
+        assert_eq!(call("127.0.0.1:4711", None).await.status(), StatusCode::OK);
+        assert_eq!(call("[::1]:4711", None).await.status(), StatusCode::OK);
+        assert_eq!(call("192.0.2.10:4711", None).await.status(), StatusCode::FORBIDDEN);
+        // Claiming to be local does not help, and a local proxy does not make its clients local
+        assert_eq!(call("192.0.2.10:4711", Some("127.0.0.1")).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("127.0.0.1:4711", Some("192.0.2.10")).await.status(), StatusCode::FORBIDDEN);
+
+        // Without any allowed networks the endpoints are off
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(AdminNetworks::default())).route("/admin/backup", web::get().to(backup)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/admin/backup").peer_addr("127.0.0.1:4711".parse().unwrap());
+        let response = test::call_service(&app, req.to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "forbidden");
+    }
+}
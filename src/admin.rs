@@ -0,0 +1,605 @@
+use crate::audit::{self, AuditRecord};
+use crate::backup::{self, BackupError, Manifest};
+use crate::config::{Command, Config};
+use crate::database::{Database, DatabaseError, Snapshot};
+use crate::entry::{self, Entry, CLI_SOURCE};
+use crate::error::ApiError;
+use crate::import::{self, ImportProgress};
+use crate::scrub::{self, ScrubProgress};
+use crate::transfer::{self, TransferProgress};
+use crate::storage::{DeleteFileResult, Storage, StorageError};
+use crate::{trash, upload};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_util::io::{ReaderStream, StreamReader};
+use uuid::Uuid;
+
+/// Recorded as the actor of changes made from the command line: `cli`, followed by the
+/// login name of whoever ran it when known
+pub fn cli_actor() -> String {
+    match std::env::var("USER") {
+        Ok(user) if !user.is_empty() => format!("{}:{}", CLI_SOURCE, user),
+        _ => CLI_SOURCE.to_string(),
+    }
+}
+
+/// Audit record of a change the command line made to `entry`
+pub fn audit_record(action: &str, entry: &Entry) -> AuditRecord {
+    AuditRecord {
+        entry_uuid: Some(entry.uuid),
+        file_name: Some(entry.file_name.clone()),
+        ..AuditRecord::new(&cli_actor(), action)
+    }
+}
+
+/// Unreferenced files modified more recently than this may be uploads still in progress
+const ORPHAN_GRACE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// An entry named on the command line does not exist
+    NoSuchEntry(Uuid),
+    Io(PathBuf, io::Error),
+    /// A database or storage failure, reported the same way as over HTTP
+    Api(ApiError),
+    Backup(BackupError),
+    /// A stored file does not match what was expected of it
+    Mismatch { key: String, reason: String },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::NoSuchEntry(uuid) => write!(f, "no entry {}", uuid),
+            CommandError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            CommandError::Api(e) => write!(f, "{}", e),
+            CommandError::Backup(e) => write!(f, "{}", e),
+            CommandError::Mismatch { key, reason } => write!(f, "{}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<ApiError> for CommandError {
+    fn from(e: ApiError) -> Self {
+        CommandError::Api(e)
+    }
+}
+
+impl From<BackupError> for CommandError {
+    fn from(e: BackupError) -> Self {
+        CommandError::Backup(e)
+    }
+}
+
+impl From<DatabaseError> for CommandError {
+    fn from(e: DatabaseError) -> Self {
+        CommandError::Api(e.into())
+    }
+}
+
+impl From<StorageError> for CommandError {
+    fn from(e: StorageError) -> Self {
+        CommandError::Api(e.into())
+    }
+}
+
+/// Something `fsck` found wrong with an entry's files
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    MissingFile { uuid: Uuid, version: u32 },
+    SizeMismatch { uuid: Uuid, version: u32, recorded: u64, stored: u64 },
+    /// The entry points at a version that has no version record
+    MissingVersion { uuid: Uuid, version: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingFile { uuid, version } => write!(f, "{} v{}: file missing from storage", uuid, version),
+            Problem::SizeMismatch { uuid, version, recorded, stored } => write!(
+                f,
+                "{} v{}: recorded size {} but storage holds {} bytes",
+                uuid, version, recorded, stored
+            ),
+            Problem::MissingVersion { uuid, version } => {
+                write!(f, "{}: current version {} has no version record", uuid, version)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub entries: usize,
+    pub versions: usize,
+    pub problems: Vec<Problem>,
+    /// Storage keys that no entry or thumbnail refers to
+    pub orphans: Vec<String>,
+    /// Unreferenced files left alone because they were modified within the grace period
+    pub recent: usize,
+}
+
+/// Runs an admin command and returns the process exit code
+pub async fn run(
+    command: Command,
+    db: &dyn Database,
+    storage: &Arc<Box<dyn Storage>>,
+    config: &Config,
+) -> Result<i32, CommandError> {
+    match command {
+        Command::Ls { trash, json } => {
+            let entries = if trash { db.list_trash().await? } else { live_entries(db).await? };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries).expect("entries are serializable"));
+            } else {
+                for entry in &entries {
+                    println!(
+                        "{}  {:>10}  {}  {}",
+                        entry.uuid,
+                        format_size(entry.file_size),
+                        entry.timestamp.format("%Y-%m-%d %H:%M"),
+                        entry.file_name
+                    );
+                }
+            }
+            Ok(0)
+        }
+        Command::Rm { uuids, purge } => {
+            let mut failed = false;
+            for uuid in uuids {
+                match remove(db, &***storage, uuid, purge).await {
+                    Ok(entry) if purge => println!("Purged {} ({})", uuid, entry.file_name),
+                    Ok(entry) => println!("Moved {} ({}) to the trash", uuid, entry.file_name),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            Ok(if failed { 1 } else { 0 })
+        }
+        Command::Import { paths, mode } => {
+            let mut failed = false;
+            for path in paths {
+                if path.is_dir() {
+                    let report = |progress: &ImportProgress| {
+                        eprintln!(
+                            "Imported {}/{} files, {} of {} ({} skipped, {} failed)",
+                            progress.done_files(),
+                            progress.total_files,
+                            format_size(progress.done_bytes),
+                            format_size(progress.total_bytes),
+                            progress.skipped,
+                            progress.failed
+                        )
+                    };
+                    let result = import::import_tree(db, storage, &path, mode, report, |_, e| eprintln!("Error: {}", e)).await;
+                    match result {
+                        Ok(progress) => failed |= progress.failed > 0,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            failed = true;
+                        }
+                    }
+                    continue;
+                }
+                match import_file(db, storage, &path).await {
+                    Ok(entry) => println!("{}  {}", entry.uuid, path.display()),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            Ok(if failed { 1 } else { 0 })
+        }
+        Command::Export { uuids, to } => {
+            let entries = if uuids.is_empty() {
+                live_entries(db).await?
+            } else {
+                let mut entries = Vec::new();
+                for uuid in uuids {
+                    entries.push(db.get_entry(uuid).await?.ok_or(CommandError::NoSuchEntry(uuid))?);
+                }
+                entries
+            };
+            tokio::fs::create_dir_all(&to).await.map_err(|e| CommandError::Io(to.clone(), e))?;
+            for entry in &entries {
+                let path = export_entry(&***storage, entry, &to).await?;
+                println!("{}  {}", entry.uuid, path.display());
+            }
+            Ok(0)
+        }
+        Command::Fsck { delete_orphans } => {
+            let report = fsck(db, &***storage).await?;
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            for key in &report.orphans {
+                if delete_orphans {
+                    match storage.delete_file(key).await {
+                        DeleteFileResult::Failure(e) => eprintln!("Error: failed to delete {}: {}", key, e),
+                        _ => println!("{}: unreferenced file deleted", key),
+                    }
+                } else {
+                    println!("{}: not referenced by any entry", key);
+                }
+            }
+            println!(
+                "Checked {} entries and {} versions: {} problems, {} unreferenced files",
+                report.entries,
+                report.versions,
+                report.problems.len(),
+                report.orphans.len()
+            );
+            if report.recent > 0 {
+                println!("Skipped {} unreferenced files modified in the last 10 minutes", report.recent);
+            }
+            let clean = report.problems.is_empty() && (report.orphans.is_empty() || delete_orphans);
+            Ok(if clean { 0 } else { 1 })
+        }
+        Command::Scrub => {
+            let report = |progress: &ScrubProgress| {
+                eprintln!(
+                    "Verified {}/{} versions, {} read ({} failed)",
+                    progress.verified,
+                    progress.total_versions,
+                    format_size(progress.read_bytes),
+                    progress.failed
+                )
+            };
+            let progress = scrub::scrub(db, &***storage, Utc::now(), config.scrub.max_bytes_per_second, report).await?;
+            let failures = db.list_scrub_failures().await?;
+            for failure in &failures {
+                match &failure.detail {
+                    Some(detail) => println!("{} v{}: {} ({})", failure.uuid, failure.version, failure.status, detail),
+                    None => println!("{} v{}: {}", failure.uuid, failure.version, failure.status),
+                }
+            }
+            println!("Verified {} versions: {} failed", progress.verified, failures.len());
+            Ok(if failures.is_empty() { 0 } else { 1 })
+        }
+        Command::Stats => {
+            let usage = db.usage().await?;
+            let files = storage.list_files().await?.len();
+            println!("Entries:          {}", usage.entries);
+            println!("Trashed entries:  {}", usage.trashed_entries);
+            println!("Stored:           {} ({} bytes)", format_size(usage.stored_bytes), usage.stored_bytes);
+            println!("Downloads:        {} ({} served)", usage.downloads, format_size(usage.served_bytes));
+            println!("Files in storage: {}", files);
+            match storage.available_space().await {
+                Ok(free) => println!("Free space:       {}", format_size(free)),
+                Err(e) => println!("Free space:       unknown ({})", e),
+            }
+            println!("Webhooks:         {}", db.list_webhooks().await?.len());
+            Ok(0)
+        }
+        Command::Backup { file } => {
+            let snapshot = db.snapshot().await?;
+            // Standard output may be the archive itself, so the summary goes to stderr
+            let manifest = if file.as_os_str() == "-" {
+                backup::write_backup(&snapshot, &***storage, tokio::io::stdout()).await?
+            } else {
+                write_backup_file(&snapshot, &***storage, &file).await?
+            };
+            eprintln!("Backed up {}", describe_backup(&manifest));
+            Ok(0)
+        }
+        Command::Restore { file } => {
+            let manifest = if file.as_os_str() == "-" {
+                backup::restore(db, storage, tokio::io::stdin()).await?
+            } else {
+                let input = tokio::fs::File::open(&file).await.map_err(|e| CommandError::Io(file.clone(), e))?;
+                backup::restore(db, storage, input).await?
+            };
+            // The restore replaced the audit log with the archived one, which this belongs after
+            let record = AuditRecord {
+                detail: Some(format!("backup of {} from {}", manifest.created_at.to_rfc3339(), file.display())),
+                ..AuditRecord::new(&cli_actor(), "backup.restore")
+            };
+            audit::append(db, record).await;
+            println!("Restored {}, backed up at {}", describe_backup(&manifest), manifest.created_at.to_rfc3339());
+            Ok(0)
+        }
+        Command::Transfer { to, verify } => {
+            let (storage_config, database_config) = match Config::load_backends(&to) {
+                Ok(backends) => backends,
+                Err(e) => {
+                    eprintln!("Error: {}: {}", to.display(), e);
+                    return Ok(2);
+                }
+            };
+            if same_path(&storage_config.path, &config.storage.path) || same_path(&database_config.path, &config.database.path) {
+                eprintln!("Error: the target in {} uses the same storage or database as this instance", to.display());
+                return Ok(2);
+            }
+
+            let target_db = crate::database::init_database(&database_config).await?;
+            target_db.migrate().await?;
+            let target_storage = crate::storage::init_storage(&storage_config)?;
+            let report = |progress: &TransferProgress| {
+                eprintln!(
+                    "Transferred {}/{} files, {} of {} ({} already present)",
+                    progress.done_files(),
+                    progress.total_files,
+                    format_size(progress.done_bytes),
+                    format_size(progress.total_bytes),
+                    progress.skipped
+                )
+            };
+            let result = transfer::transfer(db, &***storage, &**target_db, &target_storage, verify, report).await;
+            let _ = target_db.close().await;
+            let progress = result?;
+            println!(
+                "Copied {} files and replaced the target database with {} entries and {} webhooks",
+                progress.copied, progress.entries, progress.webhooks
+            );
+            Ok(0)
+        }
+        Command::Serve | Command::Migrate { .. } => unreachable!("handled by main"),
+    }
+}
+
+/// Writes a backup to `path` under a temporary name, renaming it only once it is complete and on disk
+async fn write_backup_file(snapshot: &Snapshot, storage: &dyn Storage, path: &Path) -> Result<Manifest, CommandError> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| CommandError::Io(path, e)
+    };
+
+    let out = tokio::fs::File::create(&partial).await.map_err(io_error(&partial))?;
+    let manifest = match backup::write_backup(snapshot, storage, out).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e.into());
+        }
+    };
+    let file = tokio::fs::File::open(&partial).await.map_err(io_error(&partial))?;
+    file.sync_all().await.map_err(io_error(&partial))?;
+    tokio::fs::rename(&partial, path).await.map_err(io_error(path))?;
+    Ok(manifest)
+}
+
+fn describe_backup(manifest: &Manifest) -> String {
+    format!(
+        "{} entries ({} versions, {}) and {} webhooks",
+        manifest.entries,
+        manifest.versions,
+        format_size(manifest.bytes),
+        manifest.webhooks
+    )
+}
+
+/// Whether two configured paths name the same file or directory, following symlinks where they exist
+fn same_path(a: &Option<PathBuf>, b: &Option<PathBuf>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => a == b,
+        },
+        _ => false,
+    }
+}
+
+/// Every live entry, in the order the database lists them
+async fn live_entries(db: &dyn Database) -> Result<Vec<Entry>, CommandError> {
+    let mut entries = Vec::new();
+    for uuid in db.list_uuids().await? {
+        // Skip entries trashed or purged since the listing
+        if let Some(entry) = db.get_entry(uuid).await? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Moves an entry to the trash, or deletes it and its files for good with `purge`
+async fn remove(
+    db: &dyn Database,
+    storage: &dyn Storage,
+    uuid: Uuid,
+    purge: bool,
+) -> Result<Entry, CommandError> {
+    let entry = db.get_entry(uuid).await?.ok_or(CommandError::NoSuchEntry(uuid))?;
+    if purge {
+        trash::purge_entry(db, storage, entry.clone()).await?;
+    } else if !db.trash_entry(uuid, Utc::now()).await? {
+        return Err(ApiError::Conflict { code: "already_trashed", message: format!("{} is already in the trash", uuid) }.into());
+    }
+    audit::append(db, audit_record(if purge { "entry.purge" } else { "entry.trash" }, &entry)).await;
+    Ok(entry)
+}
+
+/// Stores a file from disk as a new entry named after it
+pub async fn import_file(
+    db: &dyn Database,
+    storage: &Arc<Box<dyn Storage>>,
+    path: &Path,
+) -> Result<Entry, CommandError> {
+    let io_error = |e| CommandError::Io(path.to_path_buf(), e);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io_error(io::Error::new(io::ErrorKind::InvalidInput, "not a file")))?;
+    let file = tokio::fs::File::open(path).await.map_err(io_error)?;
+
+    let uuid = Uuid::new_v4();
+    let key = entry::blob_key(uuid, 1);
+    let stored = upload::store_stream(&mut ReaderStream::new(file), storage, &key, &file_name, 0).await?;
+
+    let entry = Entry {
+        uuid,
+        file_name,
+        file_size: stored.size,
+        source_ip: CLI_SOURCE.to_string(),
+        timestamp: Utc::now(),
+        deleted_at: None,
+        version: 1,
+        sha256: Some(stored.sha256),
+        mime_type: stored.mime_type,
+    };
+    if let Err(e) = db.insert_entry(entry.clone()).await {
+        let _ = storage.delete_file(&key).await;
+        return Err(e.into());
+    }
+    let record = AuditRecord { detail: Some(path.display().to_string()), ..audit_record("entry.import", &entry) };
+    audit::append(db, record).await;
+    Ok(entry)
+}
+
+/// Copies an entry's current version into `dir`, never overwriting existing files.
+///
+/// The file keeps its original name, prefixed with the UUID if that name is already taken.
+pub async fn export_entry(storage: &dyn Storage, entry: &Entry, dir: &Path) -> Result<PathBuf, CommandError> {
+    // Names come from uploaders, so only the final component is trusted
+    let name = Path::new(&entry.file_name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| entry.uuid.to_string());
+
+    let mut input = StreamReader::new(storage.read_file(&entry.blob_key()).await?);
+
+    let mut destination = dir.join(&name);
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    let mut output = match options.open(&destination).await {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            destination = dir.join(format!("{}-{}", entry.uuid, name));
+            options.open(&destination).await
+        }
+        result => result,
+    }
+    .map_err(|e| CommandError::Io(destination.clone(), e))?;
+
+    tokio::io::copy(&mut input, &mut output).await.map_err(|e| CommandError::Io(destination.clone(), e))?;
+    Ok(destination)
+}
+
+/// Cross-checks the database against storage without changing either
+pub async fn fsck(db: &dyn Database, storage: &dyn Storage) -> Result<FsckReport, CommandError> {
+    let mut report = FsckReport::default();
+    let mut referenced = HashSet::new();
+
+    let mut entries = live_entries(db).await?;
+    entries.extend(db.list_trash().await?);
+    for entry in &entries {
+        report.entries += 1;
+        let versions = db.list_versions(entry.uuid).await?;
+        if !versions.iter().any(|v| v.version == entry.version) {
+            report.problems.push(Problem::MissingVersion { uuid: entry.uuid, version: entry.version });
+        }
+
+        for version in versions {
+            report.versions += 1;
+            let key = entry::blob_key(entry.uuid, version.version);
+            match storage.file_info(&key).await? {
+                Some(info) if info.size != version.file_size => report.problems.push(Problem::SizeMismatch {
+                    uuid: entry.uuid,
+                    version: version.version,
+                    recorded: version.file_size,
+                    stored: info.size,
+                }),
+                Some(_) => {}
+                None => report.problems.push(Problem::MissingFile { uuid: entry.uuid, version: version.version }),
+            }
+            referenced.insert(key);
+        }
+    }
+
+    let now = SystemTime::now();
+    for key in storage.list_files().await? {
+        // Thumbnails belong to the blob whose key they extend
+        let owner = key.split_once(".thumb").map_or(key.as_str(), |(blob, _)| blob);
+        if referenced.contains(owner) {
+            continue;
+        }
+        match storage.file_info(&key).await? {
+            Some(info) if now.duration_since(info.modified).unwrap_or_default() < ORPHAN_GRACE => report.recent += 1,
+            _ => report.orphans.push(key),
+        }
+    }
+    report.orphans.sort();
+    Ok(report)
+}
+
+/// Byte count in the largest binary unit that keeps it at or above 1
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+
+    #[tokio::test]
+    async fn test_import_export_and_fsck() {
+        let dir = std::env::temp_dir().join(format!("lfs-admin-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("in")).unwrap();
+        let storage: Arc<Box<dyn Storage>> = Arc::new(Box::new(LocalStorage::new(dir.join("storage"))));
+        let db = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+
+        std::fs::write(dir.join("in/notes.txt"), b"hello from disk").unwrap();
+        let entry = import_file(&db, &storage, &dir.join("in/notes.txt")).await.unwrap();
+        assert_eq!((entry.file_name.as_str(), entry.file_size), ("notes.txt", 15));
+        assert_eq!(db.get_entry(entry.uuid).await.unwrap().unwrap().mime_type, "text/plain");
+
+        // Exporting twice keeps the first copy and disambiguates the second
+        let first = export_entry(&**storage, &entry, &dir.join("in")).await.unwrap();
+        assert_eq!(first, dir.join("in").join(format!("{}-notes.txt", entry.uuid)));
+        let second = export_entry(&**storage, &entry, &dir).await.unwrap();
+        assert_eq!(std::fs::read(second).unwrap(), b"hello from disk");
+
+        let report = fsck(&db, &**storage).await.unwrap();
+        assert_eq!((report.entries, report.versions), (1, 1));
+        assert!(report.problems.is_empty() && report.orphans.is_empty(), "{:?}", report);
+
+        // A truncated blob is reported; a fresh stray file is left alone as a possible live upload
+        std::fs::write(storage.get_file_path(&entry.blob_key()), b"hello").unwrap();
+        std::fs::write(storage.get_file_path("stray"), b"?").unwrap();
+        let report = fsck(&db, &**storage).await.unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::SizeMismatch { uuid: entry.uuid, version: 1, recorded: 15, stored: 5 }]
+        );
+        assert_eq!((report.orphans.len(), report.recent), (0, 1));
+
+        std::fs::remove_file(storage.get_file_path(&entry.blob_key())).unwrap();
+        let report = fsck(&db, &**storage).await.unwrap();
+        assert_eq!(report.problems, vec![Problem::MissingFile { uuid: entry.uuid, version: 1 }]);
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
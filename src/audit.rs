@@ -0,0 +1,295 @@
+use crate::config::AuditConfig;
+use crate::database::Database;
+use crate::error::ApiError;
+use crate::proxy::TrustedProxies;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use tracing_actix_web::RequestId;
+use uuid::Uuid;
+
+/// Recorded as the actor of changes the server makes on its own, such as expiring the trash
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// Recorded as the actor of requests that carry no identity
+pub const ANONYMOUS_ACTOR: &str = "anonymous";
+
+/// One change, or attempted change, in the append-only audit log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Position in the log, assigned when the record is appended
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Who made the change: the identity header set by a trusted proxy, `cli`, `system` or `anonymous`
+    pub actor: String,
+    pub client_ip: Option<String>,
+    /// What was attempted, such as `entry.upload` or `webhook.delete`
+    pub action: String,
+    pub entry_uuid: Option<Uuid>,
+    pub file_name: Option<String>,
+    /// Anything else needed to tell what changed, such as a webhook URL
+    pub detail: Option<String>,
+    /// `ok`, or the code of the error the change failed with
+    pub result: String,
+    /// Ties the record to the log lines of the request that made it
+    pub request_id: Option<String>,
+}
+
+impl AuditRecord {
+    /// A successful change made outside of an HTTP request
+    pub fn new(actor: &str, action: &str) -> Self {
+        AuditRecord {
+            id: 0,
+            timestamp: Utc::now(),
+            actor: actor.to_string(),
+            client_ip: None,
+            action: action.to_string(),
+            entry_uuid: None,
+            file_name: None,
+            detail: None,
+            result: "ok".to_string(),
+            request_id: None,
+        }
+    }
+}
+
+/// Narrows a listing of the audit log; unset fields match every record
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub entry: Option<Uuid>,
+    /// Records at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Records before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Records after this `id`, for paging through the log
+    pub after: Option<u64>,
+}
+
+/// Appends `record`, logging rather than failing if it cannot be written; the change it
+/// describes has already happened
+pub async fn append(db: &dyn Database, record: AuditRecord) {
+    let action = record.action.clone();
+    if let Err(e) = db.append_audit(record).await {
+        tracing::error!(error = %e, action, "failed to write audit record");
+    }
+}
+
+/// Records fetched per query while exporting
+const EXPORT_BATCH: usize = 1000;
+
+/// Every record matching `filter` as newline-delimited JSON, fetched in batches so the log
+/// never has to fit in memory
+pub fn export_stream(db: Arc<Box<dyn Database>>, filter: AuditFilter) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    stream::try_unfold((db, filter, false), |(db, mut filter, done)| async move {
+        if done {
+            return Ok(None);
+        }
+        let records = db.list_audit(filter.clone(), EXPORT_BATCH).await.map_err(io::Error::other)?;
+        let done = records.len() < EXPORT_BATCH;
+        filter.after = records.last().map(|record| record.id).or(filter.after);
+
+        let mut lines = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        Ok(Some((Bytes::from(lines), (db, filter, done))))
+    })
+}
+
+/// What a request acted on, filled in by its handler as it finds out
+#[derive(Clone, Default)]
+struct AuditTarget {
+    entry_uuid: Option<Uuid>,
+    file_name: Option<String>,
+    detail: Option<String>,
+}
+
+fn update_target(req: &HttpRequest, update: impl FnOnce(&mut AuditTarget)) {
+    let mut extensions = req.extensions_mut();
+    if extensions.get::<AuditTarget>().is_none() {
+        extensions.insert(AuditTarget::default());
+    }
+    update(extensions.get_mut::<AuditTarget>().expect("inserted above"));
+}
+
+/// Names the entry a request acts on in its audit record
+pub fn note_entry(req: &HttpRequest, uuid: Uuid, file_name: &str) {
+    update_target(req, |target| {
+        target.entry_uuid = Some(uuid);
+        target.file_name = Some(file_name.to_string());
+    });
+}
+
+/// Adds a detail to the audit record of a request
+pub fn note_detail(req: &HttpRequest, detail: impl Into<String>) {
+    update_target(req, |target| target.detail = Some(detail.into()));
+}
+
+/// Stable name of the action a route performs; routes added without a name here are recorded
+/// by method and pattern, so no change goes unlogged
+fn action_name(method: &Method, pattern: &str) -> String {
+    let name = match (method.as_str(), pattern) {
+        ("POST", "/upload") => "entry.upload",
+        ("PUT", "/entry/{uuid}/contents") => "entry.add_version",
+        ("DELETE", "/entry/{uuid}") => "entry.trash",
+        ("DELETE", "/entry/{uuid}/versions") => "entry.prune_versions",
+        ("POST", "/trash/{uuid}/restore") => "entry.restore",
+        ("DELETE", "/trash/{uuid}") => "entry.purge",
+        ("POST", "/webhooks") => "webhook.create",
+        ("DELETE", "/webhooks/{id}") => "webhook.delete",
+        ("POST", "/webhooks/{id}/test") => "webhook.test",
+        ("POST", "/admin/scrub") => "scrub.start",
+        _ => return format!("{} {}", method, pattern),
+    };
+    name.to_string()
+}
+
+/// Identity the request was made with, read from `audit.actor_header`.
+///
+/// Anyone can send the header, so it is only believed when the request came straight from a
+/// trusted proxy, which is expected to set or strip it.
+fn actor(req: &ServiceRequest) -> String {
+    let trusted = match (req.peer_addr(), req.app_data::<web::Data<TrustedProxies>>()) {
+        (Some(peer), Some(trusted)) => trusted.contains(peer.ip()),
+        _ => false,
+    };
+    req.app_data::<web::Data<AuditConfig>>()
+        .and_then(|config| config.actor_header.as_deref())
+        .filter(|_| trusted)
+        .and_then(|name| req.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|actor| !actor.is_empty())
+        .unwrap_or(ANONYMOUS_ACTOR)
+        .to_string()
+}
+
+/// Writes an audit record for every request that may change something, once its response is ready.
+///
+/// Must run inside `TracingLogger` so the request ID already exists.
+pub async fn record_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let actor = actor(&req);
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+    let response = next.call(req).await?;
+
+    // Requests that matched no route changed nothing
+    let (request, pattern) = (response.request(), response.request().match_pattern());
+    let (Some(pattern), Some(db), true) = (pattern, request.app_data::<web::Data<Arc<Box<dyn Database>>>>(), mutating) else {
+        return Ok(response);
+    };
+    let target = request.extensions().get::<AuditTarget>().cloned().unwrap_or_default();
+    let status = response.status();
+    let result = match response.response().error() {
+        Some(e) => match e.as_error::<ApiError>() {
+            Some(e) => e.code().to_string(),
+            None => format!("http_{}", status.as_u16()),
+        },
+        None if status.is_success() => "ok".to_string(),
+        None => format!("http_{}", status.as_u16()),
+    };
+
+    let record = AuditRecord {
+        id: 0,
+        timestamp: Utc::now(),
+        actor,
+        client_ip: crate::proxy::client_ip(request),
+        action: action_name(request.method(), &pattern),
+        entry_uuid: target.entry_uuid.or_else(|| request.match_info().get("uuid").and_then(|raw| raw.parse().ok())),
+        file_name: target.file_name,
+        detail: target.detail,
+        result,
+        request_id,
+    };
+    append(db.get_ref().as_ref().as_ref(), record).await;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_database::SqliteDatabase;
+    use actix_web::{middleware, test, App, HttpResponse};
+    use tracing_actix_web::TracingLogger;
+
+    async fn rename(req: HttpRequest, path: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
+        note_entry(&req, path.into_inner(), "report.pdf");
+        note_detail(&req, "renamed to final.pdf");
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    async fn missing() -> Result<HttpResponse, ApiError> {
+        Err(ApiError::EntryNotFound)
+    }
+
+    #[actix_web::test]
+    async fn test_mutating_requests_are_audited() {
+        let path = std::env::temp_dir().join(format!("lfs-audit-{}.db", Uuid::new_v4()));
+        let db: Arc<Box<dyn Database>> = Arc::new(Box::new(SqliteDatabase::new(path.to_str().unwrap()).await.unwrap()));
+        db.migrate().await.unwrap();
+        let config = AuditConfig { actor_header: Some("X-Remote-User".to_string()) };
+        let trusted = TrustedProxies::parse(&["10.0.0.1".to_string()]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(middleware::from_fn(record_requests))
+                .wrap(TracingLogger::default())
+                .app_data(web::Data::new(db.clone()))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(trusted))
+                .route("/entry/{uuid}", web::get().to(HttpResponse::Ok))
+                .route("/entry/{uuid}", web::post().to(rename))
+                .route("/entry/{uuid}", web::delete().to(missing)),
+        )
+        .await;
+
+        let uuid = Uuid::new_v4();
+        let uri = format!("/entry/{}", uuid);
+        test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        let proxy = "10.0.0.1:4711".parse().unwrap();
+        let request = test::TestRequest::post().uri(&uri).peer_addr(proxy).insert_header(("X-Remote-User", "alice"));
+        test::call_service(&app, request.to_request()).await;
+        // Only a trusted proxy may say who the user is
+        let stranger = "203.0.113.9:4711".parse().unwrap();
+        let request = test::TestRequest::post().uri(&uri).peer_addr(stranger).insert_header(("X-Remote-User", "mallory"));
+        test::call_service(&app, request.to_request()).await;
+        test::call_service(&app, test::TestRequest::delete().uri(&uri).to_request()).await;
+        test::call_service(&app, test::TestRequest::delete().uri("/nowhere").to_request()).await;
+
+        let records = db.list_audit(AuditFilter::default(), 10).await.unwrap();
+        assert_eq!(records.len(), 3, "reads and unmatched routes must not be audited");
+        assert_eq!(
+            (records[0].actor.as_str(), records[0].action.as_str(), records[0].result.as_str()),
+            ("alice", "POST /entry/{uuid}", "ok")
+        );
+        assert_eq!(records[0].file_name.as_deref(), Some("report.pdf"));
+        assert_eq!(records[0].detail.as_deref(), Some("renamed to final.pdf"));
+        assert!(records[0].request_id.is_some());
+        assert_eq!(
+            (records[1].actor.as_str(), records[1].client_ip.as_deref()),
+            (ANONYMOUS_ACTOR, Some("203.0.113.9"))
+        );
+        assert_eq!(
+            (records[2].actor.as_str(), records[2].entry_uuid, records[2].result.as_str()),
+            (ANONYMOUS_ACTOR, Some(uuid), "entry_not_found")
+        );
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use crate::audit::AuditRecord;
+use crate::database::{Database, DatabaseError, Snapshot};
+use crate::entry::{self, Entry, FileVersion};
+use crate::error::ApiError;
+use crate::storage::{ByteStream, Storage, StorageError};
+use crate::upload;
+use crate::import::ImportRecord;
+use crate::stats::Download;
+use crate::webhook::{Webhook, WebhookDelivery, WebhookEvent};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::oneshot;
+use tokio_tar::{Archive, Builder, EntryType, Header};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Layout version written into every manifest; restores refuse any other
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const ENTRIES: &str = "entries.ndjson";
+const WEBHOOKS: &str = "webhooks.ndjson";
+const DELIVERIES: &str = "deliveries.ndjson";
+const IMPORTS: &str = "imports.ndjson";
+const DOWNLOADS: &str = "downloads.ndjson";
+const AUDIT: &str = "audit.ndjson";
+/// Every stored file lives under this prefix, named by its storage key
+const BLOBS: &str = "blobs/";
+/// Written last, in the format `sha256sum --check` reads
+const CHECKSUMS: &str = "SHA256SUMS";
+
+/// Bytes buffered between the archive writer and an HTTP response
+const STREAM_BUFFER: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    /// A database or storage failure
+    Api(ApiError),
+    /// A stored file is missing or does not match what the database records for it
+    Damaged { key: String, reason: String },
+    /// The archive was not written by `write_backup`, is truncated, or fails verification
+    Invalid(String),
+    /// Restores only go into a database with no entries or webhooks
+    NotEmpty,
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Io(e) => write!(f, "{}", e),
+            BackupError::Api(e) => write!(f, "{}", e),
+            BackupError::Damaged { key, reason } => write!(f, "stored file {}: {}; run `lfs fsck`", key, reason),
+            BackupError::Invalid(reason) => write!(f, "not a usable backup: {}", reason),
+            BackupError::NotEmpty => write!(f, "restoring needs an empty database, but this one has entries or webhooks"),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl From<ApiError> for BackupError {
+    fn from(e: ApiError) -> Self {
+        BackupError::Api(e)
+    }
+}
+
+impl From<DatabaseError> for BackupError {
+    fn from(e: DatabaseError) -> Self {
+        BackupError::Api(e.into())
+    }
+}
+
+impl From<StorageError> for BackupError {
+    fn from(e: StorageError) -> Self {
+        BackupError::Api(e.into())
+    }
+}
+
+/// First file of every archive, describing what the rest holds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    pub entries: u64,
+    pub versions: u64,
+    pub webhooks: u64,
+    /// Combined size of every stored file
+    pub bytes: u64,
+}
+
+/// One line of `entries.ndjson`
+#[derive(Serialize, Deserialize)]
+struct EntryRecord {
+    #[serde(flatten)]
+    entry: Entry,
+    versions: Vec<FileVersion>,
+}
+
+/// One line of `webhooks.ndjson`; unlike the API representation it keeps the signing secret
+#[derive(Serialize, Deserialize)]
+struct WebhookRecord {
+    id: uuid::Uuid,
+    url: String,
+    secret: String,
+    events: Vec<WebhookEvent>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<&Webhook> for WebhookRecord {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookRecord {
+            id: webhook.id,
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            events: webhook.events.clone(),
+            created_at: webhook.created_at,
+        }
+    }
+}
+
+impl From<WebhookRecord> for Webhook {
+    fn from(record: WebhookRecord) -> Self {
+        Webhook {
+            id: record.id,
+            url: record.url,
+            secret: record.secret,
+            events: record.events,
+            created_at: record.created_at,
+        }
+    }
+}
+
+/// Hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.hasher.update(&buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+fn file_header(size: u64, mtime: DateTime<Utc>) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(u64::try_from(mtime.timestamp()).unwrap_or(0));
+    header
+}
+
+/// Serializes each item as one JSON line
+fn ndjson<T: Serialize>(items: impl IntoIterator<Item = T>) -> io::Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for item in items {
+        serde_json::to_writer(&mut lines, &item)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+fn missing_blob(key: &str) -> BackupError {
+    BackupError::Damaged { key: key.to_string(), reason: "missing from storage".to_string() }
+}
+
+/// Size of the file stored under `key`, treating a missing one as damage rather than an I/O error
+async fn blob_size(storage: &dyn Storage, key: &str) -> Result<u64, BackupError> {
+    match storage.file_info(key).await? {
+        Some(info) => Ok(info.size),
+        None => Err(missing_blob(key)),
+    }
+}
+
+/// Opens the file stored under `key`, treating a missing one as damage rather than an I/O error
+async fn open_blob(storage: &dyn Storage, key: &str) -> Result<StreamReader<ByteStream, Bytes>, BackupError> {
+    match storage.read_file(key).await {
+        Ok(stream) => Ok(StreamReader::new(stream)),
+        Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Err(missing_blob(key)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes everything in `snapshot`, and the stored file of every version in it, to `out` as a tar archive.
+///
+/// Stored files are never changed once written, so reading them after the snapshot was taken still
+/// matches it. Every file is checked against the size and checksum the database records; a backup
+/// of a damaged store fails instead of producing an archive that cannot be restored.
+pub async fn write_backup<W>(snapshot: &Snapshot, storage: &dyn Storage, out: W) -> Result<Manifest, BackupError>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let created_at = Utc::now();
+
+    // Check sizes before writing anything, so most damage is found without a half-written archive
+    let mut blobs = Vec::new();
+    for (entry, versions) in &snapshot.entries {
+        for version in versions {
+            let key = entry::blob_key(entry.uuid, version.version);
+            let size = blob_size(storage, &key).await?;
+            if size != version.file_size {
+                let reason = format!("{} bytes, but version {} records {}", size, version.version, version.file_size);
+                return Err(BackupError::Damaged { key, reason });
+            }
+            blobs.push((key, version));
+        }
+    }
+
+    let manifest = Manifest {
+        format: FORMAT,
+        created_at,
+        entries: snapshot.entries.len() as u64,
+        versions: blobs.len() as u64,
+        webhooks: snapshot.webhooks.len() as u64,
+        bytes: blobs.iter().map(|(_, version)| version.file_size).sum(),
+    };
+    let entries = ndjson(snapshot.entries.iter().map(|(entry, versions)| EntryRecord {
+        entry: entry.clone(),
+        versions: versions.clone(),
+    }))?;
+    let webhooks = ndjson(snapshot.webhooks.iter().map(WebhookRecord::from))?;
+    let metadata = [
+        (MANIFEST, serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?),
+        (ENTRIES, entries),
+        (WEBHOOKS, webhooks),
+        (DELIVERIES, ndjson(&snapshot.deliveries)?),
+        (IMPORTS, ndjson(&snapshot.imports)?),
+        (DOWNLOADS, ndjson(&snapshot.downloads)?),
+        (AUDIT, ndjson(&snapshot.audit)?),
+    ];
+
+    let mut archive = Builder::new(out);
+    for (path, contents) in metadata {
+        archive.append_data(&mut file_header(contents.len() as u64, created_at), path, contents.as_slice()).await?;
+    }
+
+    let mut checksums = String::new();
+    for (key, version) in blobs {
+        let mut reader = HashingReader { inner: open_blob(storage, &key).await?, hasher: Sha256::new() };
+        let path = format!("{}{}", BLOBS, key);
+        archive.append_data(&mut file_header(version.file_size, created_at), &path, &mut reader).await?;
+
+        let sha256 = hex::encode(reader.hasher.finalize());
+        if version.sha256.as_ref().is_some_and(|recorded| *recorded != sha256) {
+            let reason = format!("contents do not match the SHA-256 recorded for version {}", version.version);
+            return Err(BackupError::Damaged { key, reason });
+        }
+        let _ = writeln!(checksums, "{}  {}", sha256, path);
+    }
+    archive
+        .append_data(&mut file_header(checksums.len() as u64, created_at), CHECKSUMS, checksums.as_bytes())
+        .await?;
+
+    let mut out = archive.into_inner().await?;
+    out.flush().await?;
+    Ok(manifest)
+}
+
+/// Writes a backup in the background and streams the archive as it is produced.
+///
+/// A failure part-way ends the stream with an error, so clients see a broken transfer rather than
+/// a short archive.
+pub fn stream_backup(snapshot: Snapshot, storage: Arc<Box<dyn Storage>>) -> impl Stream<Item = io::Result<Bytes>> {
+    let (writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+    let (done, outcome) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = done.send(write_backup(&snapshot, &**storage, writer).await);
+    });
+
+    let failure = futures_util::stream::once(outcome).filter_map(|outcome| async move {
+        match outcome {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => {
+                tracing::error!(error = %e, "backup failed");
+                Some(Err(io::Error::other(e.to_string())))
+            }
+            Err(_) => Some(Err(io::Error::other("backup task stopped"))),
+        }
+    });
+    ReaderStream::new(reader).chain(failure)
+}
+
+fn invalid(reason: impl Into<String>) -> BackupError {
+    BackupError::Invalid(reason.into())
+}
+
+/// Parses one of the archive's NDJSON files
+fn parse_lines<T: for<'de> Deserialize<'de>>(name: &str, contents: &[u8]) -> Result<Vec<T>, BackupError> {
+    contents
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(i, line)| serde_json::from_slice(line).map_err(|e| invalid(format!("{} line {}: {}", name, i + 1, e))))
+        .collect()
+}
+
+/// Rebuilds the database and storage from an archive written by `write_backup`.
+///
+/// The database must be empty. Every file is checked against the archive's checksums and the
+/// SHA-256 recorded for its version before anything is written to the database; if any check
+/// fails, the files restored so far are removed again and the database is left untouched.
+pub async fn restore<R>(db: &dyn Database, storage: &Arc<Box<dyn Storage>>, input: R) -> Result<Manifest, BackupError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    if !db.list_uuids().await?.is_empty() || !db.list_trash().await?.is_empty() || !db.list_webhooks().await?.is_empty() {
+        return Err(BackupError::NotEmpty);
+    }
+
+    let mut written = Vec::new();
+    let result = restore_into(db, storage, input, &mut written).await;
+    if result.is_err() {
+        for key in &written {
+            let _ = storage.delete_file(key).await;
+        }
+    }
+    result
+}
+
+async fn restore_into<R>(
+    db: &dyn Database,
+    storage: &Arc<Box<dyn Storage>>,
+    input: R,
+    written: &mut Vec<String>,
+) -> Result<Manifest, BackupError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut archive = Archive::new(input);
+    let mut files = archive.entries()?;
+    let mut manifest: Option<Manifest> = None;
+    let mut entries: Option<Vec<EntryRecord>> = None;
+    let mut webhooks: Option<Vec<WebhookRecord>> = None;
+    let mut deliveries: Option<Vec<WebhookDelivery>> = None;
+    let mut imports: Option<Vec<ImportRecord>> = None;
+    let mut downloads: Option<Vec<Download>> = None;
+    let mut audit: Option<Vec<AuditRecord>> = None;
+    let mut checksums: Option<String> = None;
+    // SHA-256 recorded for every file the entries refer to; `None` for files stored before hashing
+    let mut expected: HashMap<String, Option<String>> = HashMap::new();
+    // SHA-256 of every file actually restored
+    let mut restored: HashMap<String, String> = HashMap::new();
+
+    while let Some(file) = files.next().await {
+        let mut file = file?;
+        if file.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = file.path()?.to_string_lossy().into_owned();
+
+        if let Some(key) = path.strip_prefix(BLOBS) {
+            // Entries come before files, so anything unknown here belongs to no entry
+            let recorded = expected.get(key).ok_or_else(|| invalid(format!("{} belongs to no entry", path)))?;
+            if restored.contains_key(key) {
+                return Err(invalid(format!("{} appears twice", path)));
+            }
+            written.push(key.to_string());
+            let stored = upload::store_stream(&mut ReaderStream::new(&mut file), storage, key, "", 0).await?;
+            if recorded.as_ref().is_some_and(|recorded| *recorded != stored.sha256) {
+                return Err(invalid(format!("{} does not match the SHA-256 recorded for it", path)));
+            }
+            restored.insert(key.to_string(), stored.sha256);
+            continue;
+        }
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+        match path.as_str() {
+            MANIFEST => {
+                let parsed: Manifest =
+                    serde_json::from_slice(&contents).map_err(|e| invalid(format!("{}: {}", MANIFEST, e)))?;
+                if parsed.format != FORMAT {
+                    return Err(invalid(format!("format {} is not supported, only {}", parsed.format, FORMAT)));
+                }
+                manifest = Some(parsed);
+            }
+            ENTRIES => {
+                let records: Vec<EntryRecord> = parse_lines(ENTRIES, &contents)?;
+                for record in &records {
+                    for version in &record.versions {
+                        expected.insert(entry::blob_key(record.entry.uuid, version.version), version.sha256.clone());
+                    }
+                }
+                entries = Some(records);
+            }
+            WEBHOOKS => webhooks = Some(parse_lines(WEBHOOKS, &contents)?),
+            DELIVERIES => deliveries = Some(parse_lines(DELIVERIES, &contents)?),
+            IMPORTS => imports = Some(parse_lines(IMPORTS, &contents)?),
+            DOWNLOADS => downloads = Some(parse_lines(DOWNLOADS, &contents)?),
+            AUDIT => audit = Some(parse_lines(AUDIT, &contents)?),
+            CHECKSUMS => {
+                checksums = Some(String::from_utf8(contents).map_err(|_| invalid(format!("{} is not UTF-8", CHECKSUMS)))?)
+            }
+            other => return Err(invalid(format!("unexpected file {}", other))),
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid(format!("{} is missing", MANIFEST)))?;
+    let entries = entries.ok_or_else(|| invalid(format!("{} is missing", ENTRIES)))?;
+    let webhooks = webhooks.ok_or_else(|| invalid(format!("{} is missing", WEBHOOKS)))?;
+    let deliveries = deliveries.ok_or_else(|| invalid(format!("{} is missing", DELIVERIES)))?;
+    let imports = imports.ok_or_else(|| invalid(format!("{} is missing", IMPORTS)))?;
+    // Archives written before downloads were recorded have no download log
+    let downloads = downloads.unwrap_or_default();
+    // Nor do those written before the audit log
+    let audit = audit.unwrap_or_default();
+    // The checksums come last, so a truncated archive is caught here
+    let checksums = checksums.ok_or_else(|| invalid(format!("{} is missing; the archive may be truncated", CHECKSUMS)))?;
+
+    let mut listed = 0;
+    for line in checksums.lines().filter(|line| !line.is_empty()) {
+        let (sha256, path) = line.split_once("  ").ok_or_else(|| invalid(format!("malformed {} line '{}'", CHECKSUMS, line)))?;
+        let key = path.strip_prefix(BLOBS).unwrap_or(path);
+        if restored.get(key).map(String::as_str) != Some(sha256) {
+            return Err(invalid(format!("{} does not match its checksum", path)));
+        }
+        listed += 1;
+    }
+    if let Some(key) = expected.keys().find(|key| !restored.contains_key(*key)) {
+        return Err(invalid(format!("{}{} is missing", BLOBS, key)));
+    }
+    if listed != restored.len()
+        || entries.len() as u64 != manifest.entries
+        || restored.len() as u64 != manifest.versions
+        || webhooks.len() as u64 != manifest.webhooks
+    {
+        return Err(invalid(format!("contents do not match {}", MANIFEST)));
+    }
+
+    let snapshot = Snapshot {
+        entries: entries.into_iter().map(|record| (record.entry, record.versions)).collect(),
+        webhooks: webhooks.into_iter().map(Webhook::from).collect(),
+        deliveries,
+        imports,
+        downloads,
+        audit,
+    };
+    db.load_snapshot(snapshot).await?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::fixtures::{add_entry, scratch_instance};
+    use crate::conformance::read;
+    use uuid::Uuid;
+
+    async fn store(storage: &Arc<Box<dyn Storage>>, key: &str, contents: &'static [u8]) -> upload::StoredFile {
+        let mut data = futures_util::stream::iter([Ok::<_, io::Error>(Bytes::from_static(contents))]);
+        upload::store_stream(&mut data, storage, key, "notes.txt", 0).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_backup_restores_into_a_fresh_instance() {
+        let dir = std::env::temp_dir().join(format!("lfs-backup-{}", Uuid::new_v4()));
+        let (db, storage) = scratch_instance(&dir.join("source")).await;
+
+        let uuid = add_entry(&db, &storage, b"first draft").await.uuid;
+        let second = store(&storage, &entry::blob_key(uuid, 2), b"second draft").await;
+        let revision = FileVersion {
+            version: 2,
+            file_size: second.size,
+            sha256: Some(second.sha256),
+            mime_type: second.mime_type,
+            timestamp: Utc::now(),
+        };
+        db.add_version(uuid, revision).await.unwrap();
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: "https://example.com/hook".to_string(),
+            secret: "s3cret".to_string(),
+            events: vec![WebhookEvent::Uploaded],
+            created_at: Utc::now(),
+        };
+        db.insert_webhook(webhook.clone()).await.unwrap();
+        let download = Download { uuid, version: 1, bytes: 11, client_ip: None, timestamp: Utc::now(), first_byte: 0 };
+        db.record_download(download).await.unwrap();
+        let mut record = AuditRecord::new("alice", "entry.upload");
+        record.entry_uuid = Some(uuid);
+        let audit_id = db.append_audit(record).await.unwrap();
+
+        let archive_path = dir.join("backup.tar");
+        let out = tokio::fs::File::create(&archive_path).await.unwrap();
+        let manifest = write_backup(&db.snapshot().await.unwrap(), &**storage, out).await.unwrap();
+        assert_eq!((manifest.entries, manifest.versions, manifest.webhooks, manifest.bytes), (1, 2, 1, 23));
+
+        // A damaged archive is rejected without leaving anything behind
+        let mut archive = std::fs::read(&archive_path).unwrap();
+        let at = archive.windows(12).position(|window| window == b"second draft").unwrap();
+        archive[at] = b'S';
+        let (target_db, target_storage) = scratch_instance(&dir.join("target")).await;
+        let damaged = restore(&target_db, &target_storage, archive.as_slice()).await;
+        assert!(matches!(damaged, Err(BackupError::Invalid(_))), "{:?}", damaged);
+        assert!(target_storage.list_files().await.unwrap().is_empty(), "files left after a failed restore");
+        assert!(target_db.list_uuids().await.unwrap().is_empty());
+
+        let input = tokio::fs::File::open(&archive_path).await.unwrap();
+        restore(&target_db, &target_storage, input).await.unwrap();
+        let restored = target_db.get_entry(uuid).await.unwrap().expect("entry not restored");
+        assert_eq!((restored.version, restored.file_size), (2, 12));
+        assert_eq!(target_db.list_versions(uuid).await.unwrap().len(), 2);
+        assert_eq!(target_db.get_webhook(webhook.id).await.unwrap().unwrap().secret, "s3cret");
+        assert_eq!(target_db.download_stats(uuid).await.unwrap().downloads, 1);
+        let audit = target_db.list_audit(Default::default(), 10).await.unwrap();
+        assert_eq!(audit.iter().map(|record| (record.id, record.actor.as_str())).collect::<Vec<_>>(), [(audit_id, "alice")]);
+        assert_eq!(read(&**target_storage, &entry::blob_key(uuid, 1)).await, b"first draft");
+
+        // Restoring twice would mix two instances
+        let input = tokio::fs::File::open(&archive_path).await.unwrap();
+        assert!(matches!(restore(&target_db, &target_storage, input).await, Err(BackupError::NotEmpty)));
+
+        drop((db, target_db));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,795 @@
+use crate::access::AdminNetworks;
+use crate::import::ImportMode;
+use crate::logging::LogFormat;
+use crate::privacy::IpMode;
+use crate::proxy::TrustedProxies;
+use crate::thumbnail::ThumbnailConfig;
+use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+/// Config file read when neither `--config` nor `LFS_CONFIG` names one
+const DEFAULT_CONFIG_FILE: &str = "lfs.toml";
+
+/// Backends each section accepts, listed in error messages
+const STORAGE_TYPES: &[&str] = &["local"];
+const DATABASE_TYPES: &[&str] = &["sqlite"];
+
+#[derive(Parser)]
+#[command(name = "lfs", version, about = "Local file sharing server")]
+pub struct Cli {
+    /// TOML config file [env: LFS_CONFIG] [default: ./lfs.toml if present]
+    #[arg(long, short, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, print the effective settings and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// What the binary does; `serve` when no command is given. Every other command works directly
+// on the configured backends and can run alongside a live server. (A doc comment here would
+// replace the program description in `--help`.)
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP server
+    Serve,
+    /// Apply pending database migrations
+    Migrate {
+        /// List migrations and when they were applied instead of applying them
+        #[arg(long)]
+        status: bool,
+    },
+    /// List entries
+    Ls {
+        /// List trashed entries instead of live ones
+        #[arg(long)]
+        trash: bool,
+        /// Print entries as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move entries to the trash
+    Rm {
+        #[arg(required = true, value_name = "UUID")]
+        uuids: Vec<Uuid>,
+        /// Delete the entries and all their files permanently instead
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Upload files from disk as new entries, including everything below any directories given
+    Import {
+        #[arg(required = true, value_name = "PATH")]
+        paths: Vec<PathBuf>,
+        /// How files below a directory are brought into storage
+        #[arg(long, value_enum, default_value = "copy")]
+        mode: ImportMode,
+    },
+    /// Copy the current version of entries into a directory under their original names
+    Export {
+        /// Entries to export; every live entry if none are given
+        #[arg(value_name = "UUID")]
+        uuids: Vec<Uuid>,
+        /// Directory to write the files to
+        #[arg(long, short, value_name = "DIR", default_value = ".")]
+        to: PathBuf,
+    },
+    /// Check that every recorded version has its file and that storage holds no unreferenced files
+    Fsck {
+        /// Delete files in storage that no entry refers to
+        #[arg(long)]
+        delete_orphans: bool,
+    },
+    /// Re-read every stored file and check it against its recorded size and checksum
+    Scrub,
+    /// Show entry counts and space used
+    Stats,
+    /// Write every entry, version, webhook and stored file to a tar archive
+    Backup {
+        /// Archive to write, or `-` for standard output
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+    /// Rebuild an empty instance from an archive written by `backup`, verifying every file
+    Restore {
+        /// Archive to read, or `-` for standard input
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+    /// Copy every stored file and database row to other backends; run again to catch up
+    Transfer {
+        /// Config file whose [storage] and [database] sections describe the target
+        #[arg(long, value_name = "FILE")]
+        to: PathBuf,
+        /// Re-hash files already in the target instead of trusting their size
+        #[arg(long)]
+        verify: bool,
+    },
+}
+
+/// Settings that can be given on the command line, taking precedence over the file and environment
+#[derive(Args, Default)]
+pub struct Overrides {
+    /// Address to listen on
+    #[arg(long, global = true, value_name = "IP")]
+    pub bind: Option<String>,
+    /// Port to listen on
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    /// Number of worker threads (0 = one per CPU)
+    #[arg(long, global = true)]
+    pub workers: Option<usize>,
+    /// Directory for local storage
+    #[arg(long, global = true, value_name = "DIR")]
+    pub storage_path: Option<PathBuf>,
+    /// SQLite database file
+    #[arg(long, global = true, value_name = "FILE")]
+    pub database_path: Option<PathBuf>,
+    /// Log filter, e.g. `info` or `info,lfs=debug`
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+/// Every problem found while loading the configuration, reported together
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Complete server configuration.
+///
+/// Built from defaults, then the TOML file, then environment variables, then command-line flags;
+/// each layer only overrides the settings it mentions.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub storage: StorageConfig,
+    pub database: DatabaseConfig,
+    pub trash: TrashConfig,
+    pub scrub: ScrubConfig,
+    pub stats: StatsConfig,
+    pub audit: AuditConfig,
+    pub admin: AdminConfig,
+    pub privacy: PrivacyConfig,
+    pub thumbnails: ThumbnailConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    /// Worker threads; 0 starts one per CPU
+    pub workers: usize,
+    /// Seconds in-flight requests get to finish after a stop signal before they are aborted
+    pub shutdown_timeout: u64,
+    /// Addresses or networks of reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are believed
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: "0.0.0.0".to_string(), port: 8080, workers: 0, shutdown_timeout: 30, trusted_proxies: Vec::new() }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser; `["*"]` allows any
+    pub allowed_origins: Vec<String>,
+    /// Seconds browsers may cache a preflight response
+    pub max_age: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig { allowed_origins: vec!["*".to_string()], max_age: 3600 }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain; HTTPS is enabled when this and `key` are set
+    pub cert: Option<PathBuf>,
+    /// PEM private key
+    pub key: Option<PathBuf>,
+    /// Generate a self-signed certificate at `cert` and `key` if they do not exist yet
+    pub self_signed: bool,
+    /// Port on which plain HTTP requests are redirected to HTTPS; unset disables the redirect
+    pub redirect_http_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() || self.key.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest accepted upload in bytes; 0 means unlimited
+    pub max_upload_size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub path: Option<PathBuf>,
+    /// `/readyz` fails once less than this many bytes are free; 0 disables the check
+    pub min_free_bytes: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig { kind: "local".to_string(), path: None, min_free_bytes: 100 * 1024 * 1024 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub path: Option<PathBuf>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { kind: "sqlite".to_string(), path: None }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// Days before trashed entries are purged automatically; 0 keeps them until purged by hand
+    pub retention_days: u32,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig { retention_days: 30 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubConfig {
+    /// Hours after which a stored file is re-read and checked again; 0 only scrubs on request
+    pub interval_hours: u32,
+    /// Upper bound on how fast the scrubber reads; 0 reads as fast as storage allows
+    pub max_bytes_per_second: u64,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig { interval_hours: 7 * 24, max_bytes_per_second: 0 }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Keep the client address of every download in the download log
+    pub record_client_ip: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Request header naming the user, as set by an authenticating proxy; unset records everyone as
+    /// `anonymous`. Only believed on requests from `server.trusted_proxies`
+    pub actor_header: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Addresses or networks of clients allowed to use the backup, scrub, audit log and webhook
+    /// endpoints; an empty list turns those endpoints off
+    pub allowed_networks: Vec<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig { allowed_networks: vec!["127.0.0.1".to_string(), "::1".to_string()] }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// What is stored of client addresses on entries, downloads and audit records and in logs
+    pub ip_mode: IpMode,
+    /// Key for `ip_mode = "hash"`; changing it makes new hashes unrelated to old ones
+    #[serde(serialize_with = "redact")]
+    pub ip_hash_key: Option<String>,
+    /// Days after which stored client addresses are removed; 0 keeps them
+    pub ip_retention_days: u32,
+}
+
+/// Keeps secrets out of the settings printed by `--check-config`
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `EnvFilter` directives such as `info` or `info,lfs=debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { level: "info".to_string(), format: LogFormat::Pretty }
+    }
+}
+
+impl Config {
+    /// Loads and validates the configuration for this process
+    pub fn load(cli: &Cli) -> Result<Config, ConfigError> {
+        let lookup = |name: &str| std::env::var(name).ok();
+        let file = cli.config.clone().or_else(|| lookup("LFS_CONFIG").map(PathBuf::from));
+        Config::load_from(file.as_deref(), lookup, &cli.overrides)
+    }
+
+    /// Layers the file, the variables returned by `env` and `overrides` over the defaults
+    fn load_from(
+        file: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+        overrides: &Overrides,
+    ) -> Result<Config, ConfigError> {
+        let mut problems = Vec::new();
+
+        let mut config = match file {
+            Some(path) => Config::read_file(path, &mut problems),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::read_file(Path::new(DEFAULT_CONFIG_FILE), &mut problems)
+            }
+            None => Config::default(),
+        };
+        config.apply_env(env, &mut problems);
+        config.apply_overrides(overrides);
+        config.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    /// Reads the `[storage]` and `[database]` sections of another config file, for commands that
+    /// work with a second set of backends. Environment variables and flags only apply to this
+    /// process's own configuration, so they are ignored here.
+    pub fn load_backends(path: &Path) -> Result<(StorageConfig, DatabaseConfig), ConfigError> {
+        let mut problems = Vec::new();
+        let config = Config::read_file(path, &mut problems);
+        if problems.is_empty() {
+            let (storage, database) = (&config.storage, &config.database);
+            validate_backend("storage", &storage.kind, &storage.path, STORAGE_TYPES, "/path/to/storage", false, &mut problems);
+            validate_backend("database", &database.kind, &database.path, DATABASE_TYPES, "/path/to/database.db", false, &mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok((config.storage, config.database))
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    fn read_file(path: &Path, problems: &mut Vec<String>) -> Config {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                problems.push(format!("cannot read config file {}: {}", path.display(), e));
+                return Config::default();
+            }
+        };
+        toml::from_str(&text).unwrap_or_else(|e| {
+            problems.push(format!("{}: {}", path.display(), e.to_string().trim_end()));
+            Config::default()
+        })
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>, problems: &mut Vec<String>) {
+        fn number<T: FromStr>(name: &str, value: Option<String>, problems: &mut Vec<String>) -> Option<T> {
+            let value = value?;
+            match value.trim().parse() {
+                Ok(parsed) => Some(parsed),
+                Err(_) => {
+                    problems.push(format!("{} must be a whole number, got '{}'", name, value));
+                    None
+                }
+            }
+        }
+        fn flag(name: &str, value: Option<String>, problems: &mut Vec<String>) -> Option<bool> {
+            match value?.as_str() {
+                "true" | "1" => Some(true),
+                "false" | "0" => Some(false),
+                other => {
+                    problems.push(format!("{} must be true or false, got '{}'", name, other));
+                    None
+                }
+            }
+        }
+        fn list(value: String) -> Vec<String> {
+            value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+        }
+
+        if let Some(bind) = env("BIND_ADDRESS") {
+            self.server.bind = bind;
+        }
+        if let Some(port) = number("PORT", env("PORT"), problems) {
+            self.server.port = port;
+        }
+        if let Some(workers) = number("WORKERS", env("WORKERS"), problems) {
+            self.server.workers = workers;
+        }
+        if let Some(timeout) = number("SHUTDOWN_TIMEOUT", env("SHUTDOWN_TIMEOUT"), problems) {
+            self.server.shutdown_timeout = timeout;
+        }
+        if let Some(proxies) = env("TRUSTED_PROXIES") {
+            self.server.trusted_proxies = list(proxies);
+        }
+        if let Some(origins) = env("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = list(origins);
+        }
+        if let Some(cert) = env("TLS_CERT_PATH") {
+            self.tls.cert = Some(cert.into());
+        }
+        if let Some(key) = env("TLS_KEY_PATH") {
+            self.tls.key = Some(key.into());
+        }
+        if let Some(self_signed) = flag("TLS_SELF_SIGNED", env("TLS_SELF_SIGNED"), problems) {
+            self.tls.self_signed = self_signed;
+        }
+        if let Some(port) = number("TLS_REDIRECT_HTTP_PORT", env("TLS_REDIRECT_HTTP_PORT"), problems) {
+            self.tls.redirect_http_port = Some(port);
+        }
+        if let Some(size) = number("MAX_UPLOAD_SIZE", env("MAX_UPLOAD_SIZE"), problems) {
+            self.limits.max_upload_size = size;
+        }
+        if let Some(kind) = env("STORAGE_TYPE") {
+            self.storage.kind = kind;
+        }
+        if let Some(path) = env("STORAGE_PATH") {
+            self.storage.path = Some(path.into());
+        }
+        if let Some(bytes) = number("STORAGE_MIN_FREE_BYTES", env("STORAGE_MIN_FREE_BYTES"), problems) {
+            self.storage.min_free_bytes = bytes;
+        }
+        if let Some(kind) = env("DATABASE_TYPE") {
+            self.database.kind = kind;
+        }
+        if let Some(path) = env("DATABASE_PATH") {
+            self.database.path = Some(path.into());
+        }
+        if let Some(days) = number("TRASH_RETENTION_DAYS", env("TRASH_RETENTION_DAYS"), problems) {
+            self.trash.retention_days = days;
+        }
+        if let Some(hours) = number("SCRUB_INTERVAL_HOURS", env("SCRUB_INTERVAL_HOURS"), problems) {
+            self.scrub.interval_hours = hours;
+        }
+        if let Some(rate) = number("SCRUB_MAX_BYTES_PER_SECOND", env("SCRUB_MAX_BYTES_PER_SECOND"), problems) {
+            self.scrub.max_bytes_per_second = rate;
+        }
+        if let Some(record) = flag("STATS_RECORD_CLIENT_IP", env("STATS_RECORD_CLIENT_IP"), problems) {
+            self.stats.record_client_ip = record;
+        }
+        if let Some(header) = env("AUDIT_ACTOR_HEADER") {
+            self.audit.actor_header = Some(header).filter(|header| !header.is_empty());
+        }
+        if let Some(networks) = env("ADMIN_ALLOWED_NETWORKS") {
+            self.admin.allowed_networks = list(networks);
+        }
+        if let Some(mode) = env("PRIVACY_IP_MODE") {
+            match mode.as_str() {
+                "full" => self.privacy.ip_mode = IpMode::Full,
+                "truncate" => self.privacy.ip_mode = IpMode::Truncate,
+                "hash" => self.privacy.ip_mode = IpMode::Hash,
+                "none" => self.privacy.ip_mode = IpMode::None,
+                other => problems.push(format!(
+                    "PRIVACY_IP_MODE must be 'full', 'truncate', 'hash' or 'none', got '{}'",
+                    other
+                )),
+            }
+        }
+        if let Some(key) = env("PRIVACY_IP_HASH_KEY") {
+            self.privacy.ip_hash_key = Some(key).filter(|key| !key.is_empty());
+        }
+        if let Some(days) = number("PRIVACY_IP_RETENTION_DAYS", env("PRIVACY_IP_RETENTION_DAYS"), problems) {
+            self.privacy.ip_retention_days = days;
+        }
+        if let Some(sizes) = env("THUMBNAIL_SIZES") {
+            let sizes: Vec<Option<u32>> = list(sizes)
+                .into_iter()
+                .map(|size| number("THUMBNAIL_SIZES", Some(size), problems))
+                .collect();
+            if sizes.iter().all(Option::is_some) {
+                self.thumbnails.sizes = sizes.into_iter().flatten().collect();
+            }
+        }
+        if let Some(level) = env("LOG_LEVEL") {
+            self.logging.level = level;
+        }
+        if let Some(format) = env("LOG_FORMAT") {
+            match format.as_str() {
+                "pretty" => self.logging.format = LogFormat::Pretty,
+                "json" => self.logging.format = LogFormat::Json,
+                other => problems.push(format!("LOG_FORMAT must be 'pretty' or 'json', got '{}'", other)),
+            }
+        }
+    }
+
+    fn apply_overrides(&mut self, overrides: &Overrides) {
+        if let Some(bind) = &overrides.bind {
+            self.server.bind = bind.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if let Some(workers) = overrides.workers {
+            self.server.workers = workers;
+        }
+        if let Some(path) = &overrides.storage_path {
+            self.storage.path = Some(path.clone());
+        }
+        if let Some(path) = &overrides.database_path {
+            self.database.path = Some(path.clone());
+        }
+        if let Some(level) = &overrides.log_level {
+            self.logging.level = level.clone();
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.server.bind.parse::<IpAddr>().is_err() {
+            problems.push(format!("server.bind must be an IP address such as 0.0.0.0, got '{}'", self.server.bind));
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be between 1 and 65535".to_string());
+        }
+        if let Err(e) = TrustedProxies::parse(&self.server.trusted_proxies) {
+            problems.push(format!("server.trusted_proxies entry {}", e));
+        }
+
+        let origins = &self.cors.allowed_origins;
+        if origins.iter().any(|origin| origin == "*") {
+            if origins.len() > 1 {
+                problems.push("cors.allowed_origins cannot mix \"*\" with specific origins".to_string());
+            }
+        } else {
+            for origin in origins {
+                if !(origin.starts_with("http://") || origin.starts_with("https://")) || origin.ends_with('/') {
+                    problems.push(format!(
+                        "cors.allowed_origins entry '{}' must look like http://host[:port] without a trailing slash",
+                        origin
+                    ));
+                }
+            }
+        }
+
+        if self.tls.enabled() {
+            match (&self.tls.cert, &self.tls.key) {
+                (Some(cert), Some(key)) if !self.tls.self_signed => {
+                    for (name, path) in [("tls.cert", cert), ("tls.key", key)] {
+                        if !path.is_file() {
+                            problems.push(format!(
+                                "{} file {} does not exist; set tls.self_signed = true to generate one",
+                                name,
+                                path.display()
+                            ));
+                        }
+                    }
+                }
+                (Some(_), Some(_)) => {}
+                _ => problems.push("tls.cert and tls.key must be set together".to_string()),
+            }
+        }
+        match self.tls.redirect_http_port {
+            Some(_) if !self.tls.enabled() => {
+                problems.push("tls.redirect_http_port requires tls.cert and tls.key".to_string())
+            }
+            Some(port) if port == 0 || port == self.server.port => problems.push(format!(
+                "tls.redirect_http_port must be a free port other than server.port, got {}",
+                port
+            )),
+            _ => {}
+        }
+
+        if self.privacy.ip_mode == IpMode::Hash && self.privacy.ip_hash_key.as_ref().is_none_or(|key| key.len() < 16) {
+            problems.push("privacy.ip_hash_key must be set to at least 16 characters when privacy.ip_mode is 'hash'".to_string());
+        }
+
+        if let Some(header) = &self.audit.actor_header {
+            if actix_web::http::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                problems.push(format!("audit.actor_header must be an HTTP header name, got '{}'", header));
+            }
+        }
+        if let Err(e) = AdminNetworks::parse(&self.admin.allowed_networks) {
+            problems.push(format!("admin.allowed_networks entry {}", e));
+        }
+
+        validate_backend("storage", &self.storage.kind, &self.storage.path, STORAGE_TYPES, "/path/to/storage", true, problems);
+        validate_backend("database", &self.database.kind, &self.database.path, DATABASE_TYPES, "/path/to/database.db", true, problems);
+
+        if self.thumbnails.sizes.is_empty() {
+            problems.push("thumbnails.sizes must list at least one size".to_string());
+        }
+        for &size in &self.thumbnails.sizes {
+            if !(16..=2048).contains(&size) {
+                problems.push(format!("thumbnail size {} is outside the supported range 16 to 2048", size));
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!(
+                "logging.level '{}' is invalid ({}); use a level such as info or directives like info,lfs=debug",
+                self.logging.level, e
+            ));
+        }
+    }
+}
+
+/// Checks a backend section; `from_env` mentions the environment variable that can set its path
+fn validate_backend(
+    section: &str,
+    kind: &str,
+    path: &Option<PathBuf>,
+    supported: &[&str],
+    example: &str,
+    from_env: bool,
+    problems: &mut Vec<String>,
+) {
+    if !supported.contains(&kind) {
+        problems.push(format!(
+            "{}.type '{}' is not supported; supported values: {}",
+            section,
+            kind,
+            supported.join(", ")
+        ));
+    }
+    if path.is_none() {
+        let mut problem = format!("{}.path must be set, e.g. `path = \"{}\"` under [{}]", section, example, section);
+        if from_env {
+            problem.push_str(&format!(" or {}_PATH={}", section.to_uppercase(), example));
+        }
+        problems.push(problem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_config_layers_file_env_and_flags() {
+        let path = std::env::temp_dir().join(format!("lfs-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"
+                [server]
+                port = 9000
+                workers = 2
+
+                [storage]
+                path = "/srv/lfs/files"
+
+                [database]
+                path = "/srv/lfs/db.sqlite"
+
+                [thumbnails]
+                sizes = [64, 128]
+            "#,
+        )
+        .unwrap();
+
+        let overrides = Overrides { port: Some(9443), ..Default::default() };
+        let config = Config::load_from(
+            Some(&path),
+            env(&[("PORT", "9100"), ("WORKERS", "4"), ("CORS_ALLOWED_ORIGINS", "http://a.lan, https://b.lan:8443")]),
+            &overrides,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Flags beat the environment, which beats the file, which beats the defaults
+        assert_eq!(config.server.port, 9443);
+        assert_eq!(config.server.workers, 4);
+        assert_eq!(config.server.bind, "0.0.0.0");
+        assert_eq!(config.storage.path, Some(PathBuf::from("/srv/lfs/files")));
+        assert_eq!(config.thumbnails.sizes, vec![64, 128]);
+        assert_eq!(config.cors.allowed_origins, vec!["http://a.lan", "https://b.lan:8443"]);
+        assert_eq!(config.trash.retention_days, 30);
+    }
+
+    #[test]
+    fn test_config_reports_every_problem() {
+        let path = std::env::temp_dir().join(format!("lfs-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[server]\nprot = 1\n").unwrap();
+        let err = Config::load_from(Some(&path), env(&[]), &Overrides::default()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("unknown field `prot`"), "{}", err);
+
+        let err = Config::load_from(
+            None,
+            env(&[
+                ("PORT", "http"),
+                ("STORAGE_TYPE", "s3"),
+                ("DATABASE_PATH", "/tmp/db.sqlite"),
+                ("TLS_CERT_PATH", "/nonexistent/cert.pem"),
+                ("TLS_SELF_SIGNED", "maybe"),
+                ("CORS_ALLOWED_ORIGINS", "*,http://a.lan"),
+                ("THUMBNAIL_SIZES", "128,4096"),
+                ("TRUSTED_PROXIES", "10.0.0.0/8, proxy.lan"),
+                ("AUDIT_ACTOR_HEADER", "X Remote User"),
+                ("ADMIN_ALLOWED_NETWORKS", "localhost"),
+                ("PRIVACY_IP_MODE", "hash"),
+            ]),
+            &Overrides::default(),
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        for expected in [
+            "PORT must be a whole number",
+            "storage.type 's3' is not supported",
+            "storage.path must be set",
+            "tls.cert and tls.key must be set together",
+            "TLS_SELF_SIGNED must be true or false",
+            "cannot mix \"*\"",
+            "thumbnail size 4096",
+            "server.trusted_proxies entry 'proxy.lan'",
+            "audit.actor_header must be an HTTP header name",
+            "admin.allowed_networks entry 'localhost'",
+            "privacy.ip_hash_key must be set",
+        ] {
+            assert!(message.contains(expected), "missing '{}' in:\n{}", expected, message);
+        }
+        assert!(!message.contains("database.path"), "{}", message);
+    }
+}
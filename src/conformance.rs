@@ -0,0 +1,898 @@
+//! Backend-agnostic conformance checks for `Storage` and `Database` implementations.
+//!
+//! Every backend is expected to pass these suites unchanged. A new backend only needs
+//! a test that constructs an instance against scratch resources and hands it to
+//! `storage_suite` or `database_suite`.
+
+use crate::audit::{AuditFilter, AuditRecord};
+use crate::database::{Database, Snapshot};
+use crate::entry::{Entry, FileVersion};
+use crate::import::ImportRecord;
+use crate::scrub::{ScrubResult, ScrubStatus};
+use crate::stats::Download;
+use crate::storage::{ByteStream, DeleteFileResult, FileInfo, Storage, StorageError, WriteFileResult};
+use crate::webhook::{Webhook, WebhookDelivery, WebhookEvent};
+use bytes::Bytes;
+use chrono::{TimeZone, Utc};
+use futures_util::future::join_all;
+use futures_util::TryStreamExt;
+use std::io;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Builds a stream that yields `data` in chunks of at most `chunk_size` bytes
+fn chunked_stream(data: &[u8], chunk_size: usize) -> ByteStream {
+    let chunks: Vec<Result<Bytes, io::Error>> = data
+        .chunks(chunk_size.max(1))
+        .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+        .collect();
+    Box::pin(tokio_stream::iter(chunks))
+}
+
+/// Deterministic, non-repeating-looking payload so misordered chunks are detectable
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u32).wrapping_mul(31).wrapping_add(seed as u32) as u8)
+        .collect()
+}
+
+async fn write(storage: &dyn Storage, uuid: &str, data: ByteStream) {
+    match storage.write_file(uuid, data).await {
+        WriteFileResult::Success => (),
+        WriteFileResult::Failure(e) => panic!("write_file({}) failed: {}", uuid, e),
+    }
+}
+
+pub(crate) async fn read(storage: &dyn Storage, uuid: &str) -> Vec<u8> {
+    let stream = storage
+        .read_file(uuid)
+        .await
+        .unwrap_or_else(|e| panic!("read_file({}) failed: {}", uuid, e));
+    let chunks: Vec<Bytes> = stream
+        .try_collect()
+        .await
+        .unwrap_or_else(|e| panic!("reading {} back failed: {}", uuid, e));
+    chunks.concat()
+}
+
+/// Whether reading `uuid` fails the way a missing file must
+async fn is_missing(storage: &dyn Storage, uuid: &str) -> bool {
+    matches!(
+        storage.read_file(uuid).await,
+        Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound
+    )
+}
+
+async fn info(storage: &dyn Storage, uuid: &str) -> Option<FileInfo> {
+    storage
+        .file_info(uuid)
+        .await
+        .unwrap_or_else(|e| panic!("file_info({}) failed: {}", uuid, e))
+}
+
+/// Runs every storage check against `storage`.
+///
+/// The backend should start empty; all files created here are removed again on success.
+pub async fn storage_suite(storage: &dyn Storage) {
+    storage_round_trip(storage).await;
+    storage_empty_file(storage).await;
+    storage_large_stream(storage).await;
+    storage_missing_file(storage).await;
+    storage_stream_error(storage).await;
+    storage_concurrent_writes(storage).await;
+    storage_available_space(storage).await;
+}
+
+async fn storage_round_trip(storage: &dyn Storage) {
+    let uuid = Uuid::new_v4().to_string();
+    let data = pattern(4096, 1);
+
+    write(storage, &uuid, chunked_stream(&data, 1000)).await;
+    assert_eq!(read(storage, &uuid).await, data, "read back different contents");
+    let written = info(storage, &uuid).await.expect("file_info found no written file");
+    assert_eq!(written.size, 4096, "file_info reported the wrong size");
+    let age = SystemTime::now().duration_since(written.modified).unwrap_or_default();
+    assert!(age < Duration::from_secs(60), "file_info reported a stale modification time");
+
+    let listed = storage.list_files().await.expect("list_files failed");
+    assert!(listed.contains(&uuid), "written file missing from list_files");
+
+    // Overwriting replaces the previous contents entirely
+    let replacement = pattern(10, 2);
+    write(storage, &uuid, chunked_stream(&replacement, 3)).await;
+    assert_eq!(read(storage, &uuid).await, replacement, "overwrite kept stale bytes");
+    assert_eq!(info(storage, &uuid).await.map(|info| info.size), Some(10), "file_info missed the overwrite");
+
+    match storage.delete_file(&uuid).await {
+        DeleteFileResult::Success => (),
+        DeleteFileResult::NotFound => panic!("delete_file reported NotFound for an existing file"),
+        DeleteFileResult::Failure(e) => panic!("delete_file failed: {}", e),
+    }
+    assert!(is_missing(storage, &uuid).await, "file still readable after delete");
+    assert!(info(storage, &uuid).await.is_none(), "file_info still describes a deleted file");
+
+    let listed = storage.list_files().await.expect("list_files failed");
+    assert!(!listed.contains(&uuid), "deleted file still in list_files");
+}
+
+async fn storage_empty_file(storage: &dyn Storage) {
+    let uuid = Uuid::new_v4().to_string();
+
+    write(storage, &uuid, Box::pin(tokio_stream::empty())).await;
+    assert!(read(storage, &uuid).await.is_empty(), "empty upload produced bytes");
+    assert_eq!(info(storage, &uuid).await.map(|info| info.size), Some(0), "empty file has a size");
+    assert!(
+        storage.list_files().await.expect("list_files failed").contains(&uuid),
+        "empty file missing from list_files"
+    );
+
+    assert!(matches!(storage.delete_file(&uuid).await, DeleteFileResult::Success));
+}
+
+async fn storage_large_stream(storage: &dyn Storage) {
+    let uuid = Uuid::new_v4().to_string();
+    // 8 MiB in odd-sized chunks so chunk boundaries never line up with buffer sizes
+    let data = pattern(8 * 1024 * 1024, 3);
+
+    write(storage, &uuid, chunked_stream(&data, 64 * 1024 + 7)).await;
+    let read_back = read(storage, &uuid).await;
+    assert_eq!(read_back.len(), data.len(), "large file has wrong length");
+    assert!(read_back == data, "large file contents differ");
+
+    assert!(matches!(storage.delete_file(&uuid).await, DeleteFileResult::Success));
+}
+
+async fn storage_missing_file(storage: &dyn Storage) {
+    let uuid = Uuid::new_v4().to_string();
+
+    assert!(is_missing(storage, &uuid).await, "reading an unwritten file must fail with NotFound");
+    assert!(info(storage, &uuid).await.is_none(), "file_info describes an unwritten file");
+    assert!(
+        matches!(storage.delete_file(&uuid).await, DeleteFileResult::NotFound),
+        "deleting a missing file must report NotFound"
+    );
+    assert!(
+        !storage.list_files().await.expect("list_files failed").contains(&uuid),
+        "unwritten file appears in list_files"
+    );
+}
+
+async fn storage_stream_error(storage: &dyn Storage) {
+    let uuid = Uuid::new_v4().to_string();
+    let chunks: Vec<Result<Bytes, io::Error>> = vec![
+        Ok(Bytes::from_static(b"partial")),
+        Err(io::Error::other("client went away")),
+    ];
+
+    match storage.write_file(&uuid, Box::pin(tokio_stream::iter(chunks))).await {
+        WriteFileResult::Failure(_) => (),
+        WriteFileResult::Success => panic!("write_file succeeded despite a stream error"),
+    }
+
+    // Callers clean up after a failed write; that must work whether or not a partial file exists
+    assert!(matches!(
+        storage.delete_file(&uuid).await,
+        DeleteFileResult::Success | DeleteFileResult::NotFound
+    ));
+}
+
+async fn storage_concurrent_writes(storage: &dyn Storage) {
+    let files: Vec<(String, Vec<u8>)> = (0..16u8)
+        .map(|i| (Uuid::new_v4().to_string(), pattern(32 * 1024 + i as usize, i)))
+        .collect();
+
+    join_all(
+        files
+            .iter()
+            .map(|(uuid, data)| write(storage, uuid, chunked_stream(data, 4096))),
+    )
+    .await;
+
+    let listed = storage.list_files().await.expect("list_files failed");
+    for (uuid, data) in &files {
+        assert!(listed.contains(uuid), "concurrently written file missing from list_files");
+        assert_eq!(&read(storage, uuid).await, data, "concurrent writes interleaved");
+    }
+
+    for (uuid, _) in &files {
+        assert!(matches!(storage.delete_file(uuid).await, DeleteFileResult::Success));
+    }
+}
+
+async fn storage_available_space(storage: &dyn Storage) {
+    let free = storage.available_space().await.expect("available_space failed");
+    assert!(free > 0, "backend reports no free space at all");
+}
+
+pub(crate) fn sample_entry(file_name: &str) -> Entry {
+    Entry {
+        uuid: Uuid::new_v4(),
+        file_name: file_name.to_string(),
+        file_size: 1234,
+        source_ip: "192.0.2.1".to_string(),
+        // Whole seconds so backends that store second precision still compare equal
+        timestamp: Utc.with_ymd_and_hms(2024, 11, 5, 12, 30, 0).unwrap(),
+        deleted_at: None,
+        version: 1,
+        sha256: Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string()),
+        mime_type: "application/pdf".to_string(),
+    }
+}
+
+fn assert_same_entry(actual: &Entry, expected: &Entry) {
+    assert_eq!(actual.uuid, expected.uuid);
+    assert_eq!(actual.file_name, expected.file_name);
+    assert_eq!(actual.file_size, expected.file_size);
+    assert_eq!(actual.source_ip, expected.source_ip);
+    assert_eq!(actual.timestamp, expected.timestamp);
+    assert_eq!(actual.deleted_at, expected.deleted_at);
+    assert_eq!(actual.version, expected.version);
+    assert_eq!(actual.sha256, expected.sha256);
+    assert_eq!(actual.mime_type, expected.mime_type);
+}
+
+/// Runs every database check against `db`.
+///
+/// The database should start empty; all rows created here are removed again on success.
+pub async fn database_suite(db: &dyn Database) {
+    database_round_trip(db).await;
+    database_duplicate_uuid(db).await;
+    database_missing_rows(db).await;
+    database_unusual_values(db).await;
+    database_trash(db).await;
+    database_versions(db).await;
+    database_webhooks(db).await;
+    database_usage(db).await;
+    database_imports(db).await;
+    database_scrub(db).await;
+    database_downloads(db).await;
+    database_audit(db).await;
+    database_anonymize(db).await;
+    database_snapshot(db).await;
+}
+
+async fn database_round_trip(db: &dyn Database) {
+    db.ping().await.expect("ping failed");
+
+    let first = sample_entry("report.pdf");
+    let second = sample_entry("photo.jpg");
+
+    assert_eq!(db.insert_entry(first.clone()).await.unwrap(), first.uuid);
+    assert_eq!(db.insert_entry(second.clone()).await.unwrap(), second.uuid);
+
+    let fetched = db.get_entry(first.uuid).await.unwrap().expect("inserted entry missing");
+    assert_same_entry(&fetched, &first);
+
+    let uuids = db.list_uuids().await.unwrap();
+    assert!(uuids.contains(&first.uuid) && uuids.contains(&second.uuid));
+
+    assert!(db.delete_entry(first.uuid).await.unwrap(), "delete_entry reported nothing removed");
+    assert!(db.get_entry(first.uuid).await.unwrap().is_none(), "entry still present after delete");
+
+    let uuids = db.list_uuids().await.unwrap();
+    assert!(!uuids.contains(&first.uuid) && uuids.contains(&second.uuid));
+
+    assert!(db.delete_entry(second.uuid).await.unwrap());
+}
+
+async fn database_duplicate_uuid(db: &dyn Database) {
+    let original = sample_entry("original.txt");
+    let mut duplicate = original.clone();
+    duplicate.file_name = "impostor.txt".to_string();
+
+    db.insert_entry(original.clone()).await.unwrap();
+    assert!(
+        db.insert_entry(duplicate).await.is_err(),
+        "inserting a duplicate UUID must fail"
+    );
+
+    // The original row must survive the rejected insert untouched
+    let fetched = db.get_entry(original.uuid).await.unwrap().expect("original entry missing");
+    assert_same_entry(&fetched, &original);
+
+    assert!(db.delete_entry(original.uuid).await.unwrap());
+}
+
+async fn database_missing_rows(db: &dyn Database) {
+    let uuid = Uuid::new_v4();
+
+    assert!(db.get_entry(uuid).await.unwrap().is_none(), "missing row must be Ok(None)");
+    assert!(!db.delete_entry(uuid).await.unwrap(), "deleting a missing row must be Ok(false)");
+    assert!(!db.list_uuids().await.unwrap().contains(&uuid));
+}
+
+async fn database_unusual_values(db: &dyn Database) {
+    let mut entry = sample_entry("названия файлов/with 'quotes' \"and\" ; DROP TABLE entries; --.bin");
+    entry.file_size = i64::MAX as u64;
+    entry.source_ip = "2001:db8::1".to_string();
+
+    db.insert_entry(entry.clone()).await.unwrap();
+    let fetched = db.get_entry(entry.uuid).await.unwrap().expect("entry missing");
+    assert_same_entry(&fetched, &entry);
+
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+}
+
+async fn database_trash(db: &dyn Database) {
+    let entry = sample_entry("draft.docx");
+    let deleted_at = Utc.with_ymd_and_hms(2024, 12, 1, 8, 0, 0).unwrap();
+    db.insert_entry(entry.clone()).await.unwrap();
+
+    assert!(db.trash_entry(entry.uuid, deleted_at).await.unwrap());
+    assert!(!db.trash_entry(entry.uuid, deleted_at).await.unwrap(), "trashing twice must be a no-op");
+    assert!(!db.list_uuids().await.unwrap().contains(&entry.uuid), "trashed entry still listed");
+
+    // Trashed entries stay readable so they can be shown in the trash and restored
+    let fetched = db.get_entry(entry.uuid).await.unwrap().expect("trashed entry missing");
+    assert_eq!(fetched.deleted_at, Some(deleted_at));
+    let trash = db.list_trash().await.unwrap();
+    assert!(trash.iter().any(|e| e.uuid == entry.uuid && e.deleted_at == Some(deleted_at)));
+
+    assert!(db.restore_entry(entry.uuid).await.unwrap());
+    assert!(!db.restore_entry(entry.uuid).await.unwrap(), "restoring a live entry must be a no-op");
+    assert!(db.list_uuids().await.unwrap().contains(&entry.uuid));
+    assert!(!db.list_trash().await.unwrap().iter().any(|e| e.uuid == entry.uuid));
+    assert_same_entry(&db.get_entry(entry.uuid).await.unwrap().unwrap(), &entry);
+
+    // Permanent deletion works on trashed entries too
+    assert!(db.trash_entry(entry.uuid, deleted_at).await.unwrap());
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    assert!(db.get_entry(entry.uuid).await.unwrap().is_none());
+
+    assert!(!db.trash_entry(Uuid::new_v4(), deleted_at).await.unwrap());
+    assert!(!db.restore_entry(Uuid::new_v4()).await.unwrap());
+}
+
+async fn database_versions(db: &dyn Database) {
+    let entry = sample_entry("notes.md");
+    db.insert_entry(entry.clone()).await.unwrap();
+
+    // Inserting an entry records its current version
+    let versions = db.list_versions(entry.uuid).await.unwrap();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].version, 1);
+    assert_eq!(versions[0].file_size, entry.file_size);
+    assert_eq!(versions[0].sha256, entry.sha256);
+    assert_eq!(versions[0].mime_type, entry.mime_type);
+
+    let second = FileVersion {
+        version: 2,
+        file_size: 99,
+        sha256: Some("ab".repeat(32)),
+        mime_type: "text/markdown".to_string(),
+        timestamp: Utc.with_ymd_and_hms(2024, 12, 24, 18, 0, 0).unwrap(),
+    };
+    assert!(db.add_version(entry.uuid, second.clone()).await.unwrap());
+    assert!(
+        db.add_version(entry.uuid, second.clone()).await.is_err(),
+        "recording the same version twice must fail"
+    );
+
+    // The entry now reflects the new version while keeping its original name and upload time
+    let current = db.get_entry(entry.uuid).await.unwrap().unwrap();
+    assert_eq!(current.version, 2);
+    assert_eq!(current.file_size, 99);
+    assert_eq!(current.sha256, second.sha256);
+    assert_eq!(current.mime_type, second.mime_type);
+    assert_eq!(current.file_name, entry.file_name);
+    assert_eq!(current.timestamp, entry.timestamp);
+
+    let versions = db.list_versions(entry.uuid).await.unwrap();
+    assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(versions[1].timestamp, second.timestamp);
+    assert_eq!(versions[1].mime_type, second.mime_type);
+
+    assert!(!db.delete_version(entry.uuid, 2).await.unwrap(), "current version must not be deletable");
+    assert!(db.delete_version(entry.uuid, 1).await.unwrap());
+    assert!(!db.delete_version(entry.uuid, 1).await.unwrap());
+    assert_eq!(db.list_versions(entry.uuid).await.unwrap().len(), 1);
+
+    // Trashed entries cannot receive new versions
+    db.trash_entry(entry.uuid, Utc::now()).await.unwrap();
+    let third = FileVersion { version: 3, ..second.clone() };
+    assert!(!db.add_version(entry.uuid, third).await.unwrap());
+
+    // Deleting the entry removes its history as well
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    assert!(db.list_versions(entry.uuid).await.unwrap().is_empty());
+    assert!(!db.add_version(Uuid::new_v4(), second).await.unwrap());
+}
+
+async fn database_webhooks(db: &dyn Database) {
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: "http://192.0.2.7:9000/hooks/lfs?token=a,b".to_string(),
+        secret: "s3cret".to_string(),
+        events: vec![WebhookEvent::Uploaded, WebhookEvent::Expired],
+        created_at: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap(),
+    };
+    db.insert_webhook(webhook.clone()).await.unwrap();
+    assert!(db.insert_webhook(webhook.clone()).await.is_err(), "duplicate webhook id must be rejected");
+
+    let stored = db.get_webhook(webhook.id).await.unwrap().expect("webhook not found");
+    assert_eq!(stored.url, webhook.url);
+    assert_eq!(stored.secret, webhook.secret);
+    assert_eq!(stored.events, webhook.events);
+    assert_eq!(stored.created_at, webhook.created_at);
+    assert!(db.list_webhooks().await.unwrap().iter().any(|w| w.id == webhook.id));
+    assert!(db.get_webhook(Uuid::new_v4()).await.unwrap().is_none());
+
+    // The log keeps only the newest attempts and lists them newest first
+    let delivery_id = Uuid::new_v4();
+    for attempt in 1..=4 {
+        let delivery = WebhookDelivery {
+            webhook_id: webhook.id,
+            delivery_id,
+            event: WebhookEvent::Uploaded,
+            entry_uuid: Some(Uuid::new_v4()),
+            attempt,
+            status_code: (attempt > 1).then_some(500),
+            error: Some(format!("attempt {} failed", attempt)),
+            timestamp: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, attempt).unwrap(),
+        };
+        db.record_delivery(delivery, 3).await.unwrap();
+    }
+    let deliveries = db.list_deliveries(webhook.id, 10).await.unwrap();
+    assert_eq!(deliveries.iter().map(|d| d.attempt).collect::<Vec<_>>(), vec![4, 3, 2]);
+    assert_eq!(deliveries[0].delivery_id, delivery_id);
+    assert_eq!(deliveries[0].status_code, Some(500));
+    assert_eq!(deliveries[0].timestamp, Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 4).unwrap());
+    assert_eq!(db.list_deliveries(webhook.id, 1).await.unwrap().len(), 1);
+
+    assert!(db.delete_webhook(webhook.id).await.unwrap());
+    assert!(!db.delete_webhook(webhook.id).await.unwrap());
+    assert!(db.list_deliveries(webhook.id, 10).await.unwrap().is_empty());
+}
+
+async fn database_usage(db: &dyn Database) {
+    let before = db.usage().await.unwrap();
+
+    let entry = sample_entry("usage.bin");
+    db.insert_entry(entry.clone()).await.unwrap();
+    let second = FileVersion {
+        version: 2,
+        file_size: 766,
+        sha256: None,
+        mime_type: entry.mime_type.clone(),
+        timestamp: entry.timestamp,
+    };
+    assert!(db.add_version(entry.uuid, second).await.unwrap());
+
+    // Every version counts towards stored bytes, not just the current one
+    let after = db.usage().await.unwrap();
+    assert_eq!(after.entries, before.entries + 1);
+    assert_eq!(after.stored_bytes, before.stored_bytes + entry.file_size + 766);
+
+    db.trash_entry(entry.uuid, Utc::now()).await.unwrap();
+    let trashed = db.usage().await.unwrap();
+    assert_eq!(trashed.entries, before.entries);
+    assert_eq!(trashed.trashed_entries, before.trashed_entries + 1);
+    assert_eq!(trashed.stored_bytes, after.stored_bytes);
+
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    assert_eq!(db.usage().await.unwrap().stored_bytes, before.stored_bytes);
+}
+
+async fn database_imports(db: &dyn Database) {
+    let entry = sample_entry("scan-0001.tiff");
+    let record = ImportRecord {
+        source_root: "/srv/archive".to_string(),
+        relative_path: "scans/2003/scan-0001.tiff".to_string(),
+        uuid: entry.uuid,
+        file_size: entry.file_size,
+        modified: entry.timestamp,
+        imported_at: Utc.with_ymd_and_hms(2024, 11, 6, 8, 0, 0).unwrap(),
+    };
+    assert!(db.get_import(&record.source_root, &record.relative_path).await.unwrap().is_none());
+
+    db.insert_imported_entry(entry.clone(), record.clone()).await.unwrap();
+    assert_same_entry(&db.get_entry(entry.uuid).await.unwrap().expect("imported entry missing"), &entry);
+    assert_eq!(db.get_import(&record.source_root, &record.relative_path).await.unwrap(), Some(record.clone()));
+    assert!(db.get_import("/srv/other", &record.relative_path).await.unwrap().is_none());
+
+    // Importing the same source twice fails without leaving a second entry behind
+    let again = sample_entry("scan-0001.tiff");
+    let duplicate = ImportRecord { uuid: again.uuid, ..record.clone() };
+    assert!(db.insert_imported_entry(again.clone(), duplicate).await.is_err());
+    assert!(db.get_entry(again.uuid).await.unwrap().is_none(), "entry kept after its import was rejected");
+
+    // Once the entry is gone the source can be imported again
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    assert!(
+        db.get_import(&record.source_root, &record.relative_path).await.unwrap().is_none(),
+        "import record outlived its entry"
+    );
+    assert!(db.snapshot().await.unwrap().imports.is_empty(), "import record of a deleted entry snapshotted");
+}
+
+async fn database_scrub(db: &dyn Database) {
+    let entry = sample_entry("scrubbed.bin");
+    let second = FileVersion {
+        version: 2,
+        file_size: 10,
+        sha256: None,
+        mime_type: "application/octet-stream".to_string(),
+        timestamp: Utc.with_ymd_and_hms(2024, 11, 8, 0, 0, 0).unwrap(),
+    };
+    db.insert_entry(entry.clone()).await.unwrap();
+    db.add_version(entry.uuid, second.clone()).await.unwrap();
+    let verified_at = Utc.with_ymd_and_hms(2025, 2, 1, 0, 0, 0).unwrap();
+    let due = |cutoff| async move {
+        let due = db.list_scrub_due(cutoff).await.unwrap();
+        due.into_iter().filter(|(uuid, _)| *uuid == entry.uuid).map(|(_, v)| v.version).collect::<Vec<_>>()
+    };
+
+    // Never-verified versions are always due
+    assert_eq!(due(verified_at).await, vec![1, 2]);
+    let failed = ScrubResult {
+        uuid: entry.uuid,
+        version: 1,
+        status: ScrubStatus::ChecksumMismatch,
+        detail: Some("found SHA-256 00".to_string()),
+        verified_at,
+    };
+    db.record_scrub(failed.clone()).await.unwrap();
+    assert_eq!(due(verified_at).await, vec![2], "verified version still due");
+    assert_eq!(due(verified_at + chrono::Duration::seconds(1)).await, vec![2, 1], "never-verified versions not first");
+    let versions = db.list_scrub_due(verified_at).await.unwrap();
+    let (_, due_version) = versions.iter().find(|(uuid, _)| *uuid == entry.uuid).expect("due version missing");
+    assert_eq!(
+        (due_version.file_size, &due_version.sha256, due_version.timestamp),
+        (second.file_size, &second.sha256, second.timestamp)
+    );
+    assert_eq!(db.list_scrub_failures().await.unwrap(), vec![failed.clone()]);
+
+    // A later result replaces the earlier one
+    db.record_scrub(ScrubResult { status: ScrubStatus::Ok, detail: None, ..failed.clone() }).await.unwrap();
+    assert!(db.list_scrub_failures().await.unwrap().is_empty());
+
+    // Failures of removed versions are not reported
+    let missing = ScrubResult { version: 2, status: ScrubStatus::Missing, detail: None, ..failed.clone() };
+    db.record_scrub(missing.clone()).await.unwrap();
+    assert_eq!(db.list_scrub_failures().await.unwrap(), vec![missing]);
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    assert!(db.list_scrub_failures().await.unwrap().is_empty());
+    assert!(due(verified_at).await.is_empty());
+}
+
+async fn database_downloads(db: &dyn Database) {
+    let popular = sample_entry("popular.txt");
+    let quiet = sample_entry("quiet.txt");
+    let unused = sample_entry("unused.txt");
+    for entry in [&popular, &quiet, &unused] {
+        db.insert_entry(entry.clone()).await.unwrap();
+    }
+    let day = |d| Utc.with_ymd_and_hms(2025, 3, d, 23, 59, 0).unwrap();
+    let download = |uuid, bytes, timestamp| Download { uuid, version: 1, bytes, client_ip: None, timestamp, first_byte: 0 };
+    let before = db.usage().await.unwrap();
+
+    db.record_download(download(popular.uuid, 1234, day(1))).await.unwrap();
+    db.record_download(download(popular.uuid, 100, day(2))).await.unwrap();
+    db.record_download(Download { client_ip: Some("2001:db8::1".to_string()), ..download(quiet.uuid, 1234, day(2)) })
+        .await
+        .unwrap();
+    // Later parts of a ranged download add bytes, not downloads
+    db.record_download(Download { first_byte: 1000, ..download(popular.uuid, 234, day(2)) }).await.unwrap();
+    db.record_download(Download { first_byte: 1, ..download(unused.uuid, 10, day(2)) }).await.unwrap();
+
+    let stats = db.download_stats(popular.uuid).await.unwrap();
+    assert_eq!((stats.downloads, stats.served_bytes, stats.last_accessed), (2, 1568, Some(day(2))));
+    let partial = db.download_stats(unused.uuid).await.unwrap();
+    assert_eq!((partial.downloads, partial.served_bytes, partial.last_accessed), (0, 10, Some(day(2))));
+    let usage = db.usage().await.unwrap();
+    assert_eq!((usage.downloads, usage.served_bytes), (before.downloads + 3, before.served_bytes + 2812));
+
+    let top = db.top_downloads(10).await.unwrap();
+    let ranked: Vec<_> = top.iter().map(|(entry, stats)| (entry.uuid, stats.downloads)).collect();
+    assert_eq!(ranked, vec![(popular.uuid, 2), (quiet.uuid, 1)], "entries never fully downloaded or out of order");
+    assert_eq!(db.top_downloads(1).await.unwrap().len(), 1);
+    db.trash_entry(popular.uuid, day(3)).await.unwrap();
+    assert_eq!(db.top_downloads(10).await.unwrap()[0].0.uuid, quiet.uuid, "trashed entries are not top downloads");
+
+    // The sample entries were all uploaded on 2024-11-05
+    let volume = db.daily_volume(Utc.with_ymd_and_hms(2024, 11, 1, 0, 0, 0).unwrap()).await.unwrap();
+    let days: Vec<_> = volume.iter().map(|v| (v.day.to_string(), v.uploads, v.downloads, v.downloaded_bytes)).collect();
+    assert!(days.contains(&("2025-03-01".to_string(), 0, 1, 1234)));
+    assert!(days.contains(&("2025-03-02".to_string(), 0, 2, 1578)));
+    let uploads = volume.iter().find(|v| v.day.to_string() == "2024-11-05").expect("upload day missing");
+    assert!(uploads.uploads >= 3 && uploads.uploaded_bytes >= 3 * 1234);
+    assert!(volume.windows(2).all(|pair| pair[0].day < pair[1].day), "days not oldest first");
+    assert!(db.daily_volume(day(2) + chrono::Duration::seconds(1)).await.unwrap().is_empty(), "rows before `since` reported");
+
+    for entry in [&popular, &quiet, &unused] {
+        assert!(db.delete_entry(entry.uuid).await.unwrap());
+    }
+    assert_eq!(db.download_stats(quiet.uuid).await.unwrap().downloads, 0, "download log outlived its entry");
+}
+
+async fn database_audit(db: &dyn Database) {
+    let entry = sample_entry("audited.txt");
+    db.insert_entry(entry.clone()).await.unwrap();
+    let at = |d| Utc.with_ymd_and_hms(2025, 4, d, 12, 0, 0).unwrap();
+    let record = |actor: &str, action: &str, timestamp| AuditRecord {
+        timestamp,
+        client_ip: Some("198.51.100.4".to_string()),
+        entry_uuid: Some(entry.uuid),
+        file_name: Some(entry.file_name.clone()),
+        ..AuditRecord::new(actor, action)
+    };
+
+    let uploaded = db.append_audit(record("alice", "entry.upload", at(1))).await.unwrap();
+    let failed = AuditRecord { result: "entry_not_found".to_string(), ..record("bob", "entry.trash", at(2)) };
+    let trashed = db.append_audit(failed).await.unwrap();
+    let created = db
+        .append_audit(AuditRecord { detail: Some("https://example.com/hook".to_string()), ..AuditRecord::new("alice", "webhook.create") })
+        .await
+        .unwrap();
+    assert!(uploaded < trashed && trashed < created, "ids must increase in append order");
+
+    let ids = |records: Vec<AuditRecord>| records.iter().map(|record| record.id).collect::<Vec<_>>();
+    let list = |filter| db.list_audit(filter, 100);
+    let mine = list(AuditFilter { entry: Some(entry.uuid), ..Default::default() }).await.unwrap();
+    assert_eq!(ids(mine.clone()), vec![uploaded, trashed], "records not oldest first");
+    assert_eq!(mine[1].result, "entry_not_found");
+    assert_eq!(mine[0].client_ip.as_deref(), Some("198.51.100.4"));
+    assert_eq!(mine[0].timestamp, at(1));
+    let alice = list(AuditFilter { actor: Some("alice".to_string()), after: Some(trashed - 1), ..Default::default() }).await.unwrap();
+    assert_eq!(ids(alice), vec![created]);
+    let action = list(AuditFilter { action: Some("entry.upload".to_string()), ..Default::default() }).await.unwrap();
+    assert!(ids(action).contains(&uploaded));
+    let window = AuditFilter { entry: Some(entry.uuid), since: Some(at(2)), until: Some(at(3)), ..Default::default() };
+    assert_eq!(ids(list(window).await.unwrap()), vec![trashed], "`since` is inclusive and `until` exclusive");
+    let paged = db.list_audit(AuditFilter { after: Some(uploaded - 1), ..Default::default() }, 2).await.unwrap();
+    assert_eq!(ids(paged), vec![uploaded, trashed]);
+
+    assert!(db.delete_entry(entry.uuid).await.unwrap());
+    let kept = list(AuditFilter { entry: Some(entry.uuid), ..Default::default() }).await.unwrap();
+    assert_eq!(kept.len(), 2, "audit log must outlive the entries it describes");
+}
+
+async fn database_anonymize(db: &dyn Database) {
+    let cutoff = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+    let old = sample_entry("old.txt");
+    let imported = Entry { source_ip: "cli".to_string(), ..sample_entry("imported.txt") };
+    let recent = Entry { timestamp: cutoff + chrono::Duration::days(1), ..sample_entry("recent.txt") };
+    for entry in [&old, &imported, &recent] {
+        db.insert_entry(entry.clone()).await.unwrap();
+    }
+    for timestamp in [old.timestamp, recent.timestamp] {
+        let download =
+            Download { uuid: old.uuid, version: 1, bytes: 1, client_ip: Some("192.0.2.1".to_string()), timestamp, first_byte: 0 };
+        db.record_download(download).await.unwrap();
+        let record = AuditRecord {
+            timestamp,
+            client_ip: Some("192.0.2.1".to_string()),
+            entry_uuid: Some(old.uuid),
+            ..AuditRecord::new("alice", "entry.upload")
+        };
+        db.append_audit(record).await.unwrap();
+    }
+
+    // One entry, one download and one audit record are older than the cutoff
+    assert!(db.anonymize_client_ips(cutoff).await.unwrap() >= 3);
+    assert_eq!(db.anonymize_client_ips(cutoff).await.unwrap(), 0, "addresses removed twice");
+    let source = |uuid| async move { db.get_entry(uuid).await.unwrap().unwrap().source_ip };
+    assert_eq!(source(old.uuid).await, "");
+    assert_eq!(source(imported.uuid).await, "cli", "command line source removed");
+    assert_eq!(source(recent.uuid).await, "192.0.2.1", "address removed before its time");
+    let audit = db.list_audit(AuditFilter { entry: Some(old.uuid), ..Default::default() }, 10).await.unwrap();
+    let addresses: Vec<_> = audit.iter().map(|record| record.client_ip.as_deref()).collect();
+    assert_eq!(addresses, vec![None, Some("192.0.2.1")]);
+    let snapshot = db.snapshot().await.unwrap();
+    let mut downloads: Vec<_> = snapshot.downloads.iter().filter(|d| d.uuid == old.uuid).collect();
+    downloads.sort_by_key(|d| d.timestamp);
+    assert_eq!(downloads.iter().map(|d| d.client_ip.as_deref()).collect::<Vec<_>>(), vec![None, Some("192.0.2.1")]);
+
+    for entry in [&old, &imported, &recent] {
+        assert!(db.delete_entry(entry.uuid).await.unwrap());
+    }
+}
+
+async fn database_snapshot(db: &dyn Database) {
+    let live = sample_entry("live.txt");
+    let mut trashed = sample_entry("trashed.txt");
+    trashed.deleted_at = Some(Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap());
+    let second = FileVersion {
+        version: 2,
+        file_size: 4321,
+        sha256: None,
+        mime_type: "text/plain".to_string(),
+        timestamp: Utc.with_ymd_and_hms(2024, 11, 7, 9, 0, 0).unwrap(),
+    };
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: "https://example.com/hook".to_string(),
+        secret: "s3cret".to_string(),
+        events: vec![WebhookEvent::Deleted],
+        created_at: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap(),
+    };
+    let delivery = |attempt| WebhookDelivery {
+        webhook_id: webhook.id,
+        delivery_id: Uuid::nil(),
+        event: WebhookEvent::Deleted,
+        entry_uuid: Some(trashed.uuid),
+        attempt,
+        status_code: Some(502),
+        error: None,
+        timestamp: Utc.with_ymd_and_hms(2025, 1, 2, 3, 5, attempt).unwrap(),
+    };
+    let imported = sample_entry("imported.txt");
+    let import = ImportRecord {
+        source_root: "/srv/archive".to_string(),
+        relative_path: "imported.txt".to_string(),
+        uuid: imported.uuid,
+        file_size: imported.file_size,
+        modified: imported.timestamp,
+        imported_at: imported.timestamp,
+    };
+    db.insert_entry(live.clone()).await.unwrap();
+    db.add_version(live.uuid, second.clone()).await.unwrap();
+    db.insert_entry(trashed.clone()).await.unwrap();
+    db.insert_webhook(webhook.clone()).await.unwrap();
+    db.record_delivery(delivery(1), 10).await.unwrap();
+    db.record_delivery(delivery(2), 10).await.unwrap();
+    db.insert_imported_entry(imported.clone(), import.clone()).await.unwrap();
+    let download = Download {
+        uuid: live.uuid,
+        version: 1,
+        bytes: 99,
+        client_ip: Some("192.0.2.7".to_string()),
+        timestamp: Utc.with_ymd_and_hms(2025, 1, 3, 0, 0, 0).unwrap(),
+        first_byte: 4096,
+    };
+    db.record_download(download.clone()).await.unwrap();
+
+    // Other checks clean up their entries, but import records outlive them
+    let snapshot = db.snapshot().await.unwrap();
+    assert_eq!(snapshot.entries.len(), 3);
+    let (current, versions) = snapshot.entries.iter().find(|(entry, _)| entry.uuid == live.uuid).expect("live entry missing");
+    assert_eq!((current.version, current.file_size), (2, second.file_size));
+    assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), vec![1, 2]);
+    let (deleted, _) = snapshot.entries.iter().find(|(entry, _)| entry.uuid == trashed.uuid).expect("trashed entry missing");
+    assert_same_entry(deleted, &trashed);
+    assert_eq!(snapshot.webhooks.len(), 1);
+    assert_eq!(snapshot.webhooks[0].secret, webhook.secret);
+    assert_eq!(snapshot.deliveries.iter().map(|d| d.attempt).collect::<Vec<_>>(), vec![1, 2], "deliveries not oldest first");
+    assert!(snapshot.imports.contains(&import));
+    assert_eq!(snapshot.downloads, vec![download]);
+    assert!(!snapshot.audit.is_empty());
+    assert!(snapshot.audit.windows(2).all(|pair| pair[0].id < pair[1].id), "audit log not oldest first");
+    let current = current.clone();
+
+    // Loading replaces whatever is there
+    assert!(db.delete_entry(live.uuid).await.unwrap());
+    let stray = sample_entry("stray.txt");
+    db.insert_entry(stray.clone()).await.unwrap();
+    db.load_snapshot(snapshot.clone()).await.unwrap();
+    assert!(db.get_entry(stray.uuid).await.unwrap().is_none(), "row outside the snapshot survived");
+    assert_same_entry(&db.get_entry(live.uuid).await.unwrap().expect("entry not loaded"), &current);
+    assert_eq!(db.list_versions(live.uuid).await.unwrap().len(), 2);
+    assert_eq!(db.download_stats(live.uuid).await.unwrap().served_bytes, 99);
+    assert!(db.list_trash().await.unwrap().iter().any(|entry| entry.uuid == trashed.uuid));
+    assert_eq!(db.get_webhook(webhook.id).await.unwrap().unwrap().events, webhook.events);
+    assert_eq!(db.list_deliveries(webhook.id, 10).await.unwrap()[0].attempt, 2);
+    assert_eq!(db.get_import(&import.source_root, &import.relative_path).await.unwrap(), Some(import));
+    assert_eq!(db.list_audit(AuditFilter::default(), 1000).await.unwrap(), snapshot.audit, "audit ids not kept");
+    let last = snapshot.audit.last().unwrap().id;
+    assert!(db.append_audit(AuditRecord::new("system", "entry.expire")).await.unwrap() > last, "audit id reused");
+
+    // A load that fails part-way keeps the previous contents
+    let mut broken = snapshot.clone();
+    broken.entries.push(broken.entries[0].clone());
+    assert!(db.load_snapshot(broken).await.is_err(), "duplicate rows must be rejected");
+    assert_eq!(db.list_uuids().await.unwrap().len(), 2);
+
+    let empty = Snapshot { entries: vec![], webhooks: vec![], deliveries: vec![], imports: vec![], downloads: vec![], audit: vec![] };
+    db.load_snapshot(empty).await.unwrap();
+    assert!(db.list_uuids().await.unwrap().is_empty() && db.list_trash().await.unwrap().is_empty());
+    assert!(db.list_webhooks().await.unwrap().is_empty());
+    assert!(db.list_audit(AuditFilter::default(), 10).await.unwrap().is_empty());
+}
+
+/// Scratch instances and entries shared by the tests of modules that work on a whole instance
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::sample_entry;
+    use crate::database::Database;
+    use crate::entry::{self, Entry};
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+    use crate::storage::{ByteStream, DeleteFileResult, FileInfo, Storage, StorageError, WriteFileResult};
+    use crate::upload;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures_util::TryStreamExt;
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    /// Local storage and a migrated SQLite database, both under `dir`
+    pub(crate) async fn scratch_instance(dir: &Path) -> (SqliteDatabase, Arc<Box<dyn Storage>>) {
+        let storage: Arc<Box<dyn Storage>> = Arc::new(Box::new(LocalStorage::new(dir.join("storage"))));
+        let db = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+        (db, storage)
+    }
+
+    /// Stores `contents` as the first version of a new entry and records it with its real size and hash
+    pub(crate) async fn add_entry(db: &dyn Database, storage: &Arc<Box<dyn Storage>>, contents: &[u8]) -> Entry {
+        let template = sample_entry("a.txt");
+        let mut data = futures_util::stream::iter([Ok::<_, io::Error>(Bytes::copy_from_slice(contents))]);
+        let key = entry::blob_key(template.uuid, 1);
+        let stored = upload::store_stream(&mut data, storage, &key, &template.file_name, 0).await.unwrap();
+        let entry = Entry {
+            file_size: stored.size,
+            sha256: Some(stored.sha256),
+            mime_type: stored.mime_type,
+            ..template
+        };
+        db.insert_entry(entry.clone()).await.unwrap();
+        entry
+    }
+    /// Replaces what is stored under `key`, as damage outside the server would
+    pub(crate) async fn overwrite(storage: &dyn Storage, key: &str, contents: impl AsRef<[u8]>) {
+        let data = Box::pin(tokio_stream::iter([Ok(Bytes::copy_from_slice(contents.as_ref()))]));
+        assert!(matches!(storage.write_file(key, data).await, WriteFileResult::Success));
+    }
+
+    /// `Storage` that keeps its files in memory, with nothing at the paths `get_file_path` returns.
+    ///
+    /// Code that moves files between backends is tested against it to show that it only goes
+    /// through the trait.
+    #[derive(Default)]
+    pub(crate) struct MemoryStorage {
+        files: Mutex<HashMap<String, (Bytes, SystemTime)>>,
+    }
+
+    #[async_trait]
+    impl Storage for MemoryStorage {
+        async fn write_file(&self, uuid: &str, data: ByteStream) -> WriteFileResult {
+            match data.try_collect::<Vec<Bytes>>().await {
+                Ok(chunks) => {
+                    let contents = Bytes::from(chunks.concat());
+                    self.files.lock().unwrap().insert(uuid.to_string(), (contents, SystemTime::now()));
+                    WriteFileResult::Success
+                }
+                Err(e) => WriteFileResult::Failure(StorageError::Stream(e)),
+            }
+        }
+
+        fn get_file_path(&self, uuid: &str) -> PathBuf {
+            Path::new("/nonexistent/lfs-memory-storage").join(uuid)
+        }
+
+        async fn read_file(&self, uuid: &str) -> Result<ByteStream, StorageError> {
+            match self.files.lock().unwrap().get(uuid) {
+                Some((contents, _)) => Ok(Box::pin(tokio_stream::iter([Ok(contents.clone())]))),
+                None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+            }
+        }
+
+        async fn file_info(&self, uuid: &str) -> Result<Option<FileInfo>, StorageError> {
+            let files = self.files.lock().unwrap();
+            Ok(files.get(uuid).map(|(contents, modified)| FileInfo { size: contents.len() as u64, modified: *modified }))
+        }
+
+        async fn delete_file(&self, uuid: &str) -> DeleteFileResult {
+            match self.files.lock().unwrap().remove(uuid) {
+                Some(_) => DeleteFileResult::Success,
+                None => DeleteFileResult::NotFound,
+            }
+        }
+
+        async fn list_files(&self) -> Result<Vec<String>, StorageError> {
+            Ok(self.files.lock().unwrap().keys().cloned().collect())
+        }
+
+        async fn available_space(&self) -> Result<u64, StorageError> {
+            Ok(u64::MAX)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage_conformance() {
+        super::storage_suite(&MemoryStorage::default()).await;
+    }
+}
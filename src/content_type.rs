@@ -0,0 +1,110 @@
+use std::path::Path;
+
+/// Fallback type for content that could not be identified
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// How many leading bytes of an upload are kept for type sniffing
+pub const SNIFF_LEN: usize = 8192;
+
+/// Types that can run script or load resources when rendered by a browser
+const ACTIVE_TYPES: &[&str] = &[
+    "text/html",
+    "image/svg+xml",
+    "application/xhtml+xml",
+    "application/xml",
+    "text/xml",
+];
+
+/// Types a browser renders passively and that are safe to show inline
+const PASSIVE_TYPES: &[&str] = &["application/pdf", "text/plain", "text/csv", "text/markdown"];
+
+/// How a file may be presented when a client asks to view it in the browser
+#[derive(Debug, PartialEq)]
+pub enum Presentation {
+    /// Rendered as-is
+    Inline,
+    /// Rendered, but under a sandboxing Content-Security-Policy
+    Sandboxed,
+    /// Never rendered; always downloaded
+    Attachment,
+}
+
+/// Works out the MIME type of an upload.
+///
+/// Magic bytes win over the file name, since extensions are trivially wrong or missing;
+/// the extension is consulted for formats without a signature (text, SVG, HTML), and
+/// anything still unknown that decodes as UTF-8 is treated as plain text.
+pub fn detect(head: &[u8], file_name: &str) -> String {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+
+    let extension = Path::new(file_name).extension().and_then(|e| e.to_str());
+    if let Some(guess) = extension.and_then(|e| mime_guess::from_ext(e).first()) {
+        return guess.essence_str().to_string();
+    }
+
+    if !head.is_empty() && !head.contains(&0) && utf8_prefix(head) {
+        return "text/plain".to_string();
+    }
+
+    OCTET_STREAM.to_string()
+}
+
+/// Whether `head` is valid UTF-8, allowing a multi-byte character cut off at the end
+fn utf8_prefix(head: &[u8]) -> bool {
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() == SNIFF_LEN,
+    }
+}
+
+/// Decides how a stored file of type `mime_type` may be shown inline
+pub fn presentation(mime_type: &str) -> Presentation {
+    let essence = mime_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+    if ACTIVE_TYPES.contains(&essence.as_str()) {
+        Presentation::Sandboxed
+    } else if PASSIVE_TYPES.contains(&essence.as_str())
+        || essence.starts_with("image/")
+        || essence.starts_with("audio/")
+        || essence.starts_with("video/")
+    {
+        Presentation::Inline
+    } else {
+        Presentation::Attachment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D, b'I', b'H', b'D', b'R'];
+
+    #[test]
+    fn test_detect_prefers_magic_bytes() {
+        assert_eq!(detect(PNG_HEADER, "holiday.txt"), "image/png");
+        assert_eq!(detect(b"%PDF-1.7\n", "report"), "application/pdf");
+    }
+
+    #[test]
+    fn test_detect_falls_back_to_extension_and_text() {
+        assert_eq!(detect(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>", "logo.svg"), "image/svg+xml");
+        assert_eq!(detect(b"<html></html>", "page.html"), "text/html");
+        assert_eq!(detect("héllo wörld".as_bytes(), "README"), "text/plain");
+        assert_eq!(detect(&[0, 1, 2, 3, 0xff], "blob"), OCTET_STREAM);
+        assert_eq!(detect(b"", "empty"), OCTET_STREAM);
+    }
+
+    #[test]
+    fn test_presentation() {
+        assert_eq!(presentation("image/png"), Presentation::Inline);
+        assert_eq!(presentation("application/pdf"), Presentation::Inline);
+        assert_eq!(presentation("text/plain; charset=utf-8"), Presentation::Inline);
+        assert_eq!(presentation("image/svg+xml"), Presentation::Sandboxed);
+        assert_eq!(presentation("TEXT/HTML"), Presentation::Sandboxed);
+        assert_eq!(presentation("application/zip"), Presentation::Attachment);
+        assert_eq!(presentation("application/javascript"), Presentation::Attachment);
+    }
+}
@@ -1,8 +1,93 @@
-use crate::entry::Entry;
+use crate::audit::{AuditFilter, AuditRecord};
+use crate::entry::{Entry, FileVersion};
+use crate::import::ImportRecord;
+use crate::scrub::ScrubResult;
+use crate::stats::{DailyVolume, Download, DownloadStats};
+use crate::webhook::{Webhook, WebhookDelivery};
+use crate::config::DatabaseConfig;
+use crate::sqlite_database::SqliteDatabase;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
 use uuid::Uuid;
 
+/// Database-level failures shared by all backends.
+///
+/// Backend-specific errors are flattened into messages so the type stays `Send + Sync`
+/// and callers never need to know which backend produced them.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// The backend could not be reached, e.g. no pooled connection was available
+    Unavailable(String),
+    /// An entry with this UUID already exists
+    DuplicateEntry(Uuid),
+    /// A stored row could not be decoded into an `Entry`
+    CorruptEntry { uuid: String, reason: String },
+    /// Any other failure reported by the backend
+    Backend(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Unavailable(msg) => write!(f, "database unavailable: {}", msg),
+            DatabaseError::DuplicateEntry(uuid) => write!(f, "entry {} already exists", uuid),
+            DatabaseError::CorruptEntry { uuid, reason } => {
+                write!(f, "stored entry {} is corrupt: {}", uuid, reason)
+            }
+            DatabaseError::Backend(msg) => write!(f, "database error: {}", msg),
+        }
+    }
+}
+
+impl Error for DatabaseError {}
+
+/// An ordered, forward-only schema change.
+///
+/// Each backend keeps its own list sorted by `version`; versions are never reused or edited
+/// once released, so a database can always be brought up to date from any earlier state.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Whether a known migration has been applied to the database
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Totals reported by `Database::usage`
+pub struct Usage {
+    /// Live entries; trashed entries are counted separately
+    pub entries: u64,
+    pub trashed_entries: u64,
+    /// Combined size of every recorded version, trashed entries included
+    pub stored_bytes: u64,
+    /// Downloads recorded for entries that still exist, and the bytes they served
+    pub downloads: u64,
+    pub served_bytes: u64,
+}
+
+/// Every row in the database, read at a single point in time
+#[derive(Clone)]
+pub struct Snapshot {
+    /// Live and trashed entries, each with all of its stored versions
+    pub entries: Vec<(Entry, Vec<FileVersion>)>,
+    pub webhooks: Vec<Webhook>,
+    /// Delivery log of every webhook, oldest attempt first
+    pub deliveries: Vec<WebhookDelivery>,
+    pub imports: Vec<ImportRecord>,
+    /// Download log of every entry, oldest first
+    pub downloads: Vec<Download>,
+    /// The whole audit log, oldest first
+    pub audit: Vec<AuditRecord>,
+}
+
 /// Traits used to define platform-independent database operations.
 ///
 /// Return types are set up to handle data-level and database-level errors separately.
@@ -10,8 +95,103 @@ use uuid::Uuid;
 ///     whereas if there were a database error, the error variant would be returned.
 #[async_trait]
 pub trait Database: Send + Sync {
-    async fn list_uuids(&self) -> Result<Vec<Uuid>, Box<dyn Error>>;
-    async fn get_entry(&self, uuid: Uuid) -> Result<Option<Entry>, Box<dyn Error>>;
-    async fn insert_entry(&self, input: Entry) -> Result<Uuid, Box<dyn Error>>;
-    async fn delete_entry(&self, uuid: Uuid) -> Result<bool, Box<dyn Error>>;
+    /// Runs a trivial query to confirm the database is reachable
+    async fn ping(&self) -> Result<(), DatabaseError>;
+
+    /// Lists live entries; trashed entries are excluded
+    async fn list_uuids(&self) -> Result<Vec<Uuid>, DatabaseError>;
+    /// Looks up an entry whether or not it is in the trash
+    async fn get_entry(&self, uuid: Uuid) -> Result<Option<Entry>, DatabaseError>;
+    /// Inserts an entry together with the version record for its current version
+    async fn insert_entry(&self, input: Entry) -> Result<Uuid, DatabaseError>;
+    /// Permanently removes an entry with its version history, download log and import record,
+    /// trashed or not
+    async fn delete_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError>;
+
+    /// Records a new version and makes it current; returns false if the entry is missing or trashed
+    async fn add_version(&self, uuid: Uuid, version: FileVersion) -> Result<bool, DatabaseError>;
+    /// Lists every recorded version of an entry, oldest first
+    async fn list_versions(&self, uuid: Uuid) -> Result<Vec<FileVersion>, DatabaseError>;
+    /// Removes the record of an old version; the current version can never be removed
+    async fn delete_version(&self, uuid: Uuid, version: u32) -> Result<bool, DatabaseError>;
+
+    /// Marks a live entry as trashed; returns false if it is missing or already trashed
+    async fn trash_entry(&self, uuid: Uuid, deleted_at: DateTime<Utc>) -> Result<bool, DatabaseError>;
+    /// Moves a trashed entry back to the live set; returns false if it is not in the trash
+    async fn restore_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError>;
+    /// Lists trashed entries, oldest deletion first
+    async fn list_trash(&self) -> Result<Vec<Entry>, DatabaseError>;
+
+    /// Counts entries and the bytes stored for them
+    async fn usage(&self) -> Result<Usage, DatabaseError>;
+
+    /// Stores a new webhook subscription
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<(), DatabaseError>;
+    /// Lists every webhook, oldest first
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, DatabaseError>;
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, DatabaseError>;
+    /// Removes a webhook and its delivery log; returns false if it does not exist
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, DatabaseError>;
+    /// Appends to a webhook's delivery log, keeping only its `keep` most recent attempts
+    async fn record_delivery(&self, delivery: WebhookDelivery, keep: usize) -> Result<(), DatabaseError>;
+    /// Lists up to `limit` of a webhook's most recent delivery attempts, newest first
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<WebhookDelivery>, DatabaseError>;
+
+    /// Inserts an entry together with the record of the file it was imported from, atomically
+    async fn insert_imported_entry(&self, input: Entry, source: ImportRecord) -> Result<Uuid, DatabaseError>;
+    /// Looks up the import of `relative_path` below `source_root`, if it has been imported before
+    async fn get_import(&self, source_root: &str, relative_path: &str) -> Result<Option<ImportRecord>, DatabaseError>;
+
+    /// Appends to the download log
+    async fn record_download(&self, download: Download) -> Result<(), DatabaseError>;
+    /// Totals the download log of one entry; an entry never downloaded has zero downloads
+    async fn download_stats(&self, uuid: Uuid) -> Result<DownloadStats, DatabaseError>;
+    /// Lists up to `limit` live entries with at least one download, most downloaded first
+    async fn top_downloads(&self, limit: usize) -> Result<Vec<(Entry, DownloadStats)>, DatabaseError>;
+    /// Totals uploads and downloads per UTC day since `since`, oldest first; idle days are left out
+    async fn daily_volume(&self, since: DateTime<Utc>) -> Result<Vec<DailyVolume>, DatabaseError>;
+
+    /// Removes the client addresses of entries, downloads and audit records created before `before`,
+    /// returning how many rows changed. Entries created from the command line keep their source.
+    async fn anonymize_client_ips(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError>;
+
+    /// Appends to the audit log, returning the `id` given to the record; apart from
+    /// `anonymize_client_ips`, records are never changed
+    async fn append_audit(&self, record: AuditRecord) -> Result<u64, DatabaseError>;
+    /// Lists up to `limit` audit records matching `filter`, oldest first
+    async fn list_audit(&self, filter: AuditFilter, limit: usize) -> Result<Vec<AuditRecord>, DatabaseError>;
+
+    /// Lists every stored version last verified before `cutoff`, never-verified versions first
+    async fn list_scrub_due(&self, cutoff: DateTime<Utc>) -> Result<Vec<(Uuid, FileVersion)>, DatabaseError>;
+    /// Records the latest verification of a version, replacing the previous one
+    async fn record_scrub(&self, result: ScrubResult) -> Result<(), DatabaseError>;
+    /// Lists versions that still exist but failed their latest verification, oldest verification first
+    async fn list_scrub_failures(&self) -> Result<Vec<ScrubResult>, DatabaseError>;
+
+    /// Reads every row consistently, as of one moment
+    async fn snapshot(&self) -> Result<Snapshot, DatabaseError>;
+    /// Replaces everything in the database with `snapshot` in one transaction, so a failed load
+    /// leaves the previous contents in place
+    async fn load_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError>;
+
+    /// Applies all pending migrations in order, returning the versions that were applied
+    async fn migrate(&self) -> Result<Vec<u32>, DatabaseError>;
+
+    /// Lists every migration known to this backend and when it was applied, if at all
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError>;
+
+    /// Flushes pending writes before the process exits; the database must not be used afterwards
+    async fn close(&self) -> Result<(), DatabaseError>;
+}
+
+/// Opens the configured database backend
+pub async fn init_database(config: &DatabaseConfig) -> Result<Arc<Box<dyn Database>>, DatabaseError> {
+    let path = config
+        .path
+        .as_deref()
+        .ok_or_else(|| DatabaseError::Backend("no database path configured".to_string()))?;
+    match config.kind.as_str() {
+        "sqlite" => Ok(Arc::new(Box::new(SqliteDatabase::new(&path.to_string_lossy()).await?))),
+        other => Err(DatabaseError::Backend(format!("unsupported database type {}", other))),
+    }
 }
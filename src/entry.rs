@@ -1,13 +1,55 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Recorded as the `source_ip` of entries created from the command line
+pub const CLI_SOURCE: &str = "cli";
+
 /// A single entry in the database
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Entry {
     pub uuid: Uuid,
     pub file_name: String,
+    /// Size of the current version
     pub file_size: u64,
     pub source_ip: String,
+    /// When the entry was first uploaded
+    pub timestamp: DateTime<Utc>,
+    /// When the entry was moved to the trash; `None` for live entries
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Number of the version currently served for this entry, starting at 1
+    pub version: u32,
+    /// Hex-encoded SHA-256 of the current version; `None` for files stored before hashing existed
+    pub sha256: Option<String>,
+    /// MIME type of the current version, sniffed from its contents at upload
+    pub mime_type: String,
+}
+
+impl Entry {
+    /// Storage key of the version currently served for this entry
+    pub fn blob_key(&self) -> String {
+        blob_key(self.uuid, self.version)
+    }
+}
+
+/// A single stored revision of an entry's contents
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub version: u32,
+    pub file_size: u64,
+    pub sha256: Option<String>,
+    pub mime_type: String,
+    /// When this version was uploaded
     pub timestamp: DateTime<Utc>,
-}
\ No newline at end of file
+}
+
+/// Storage key for one version of an entry.
+///
+/// The first version keeps the bare UUID so files stored before versioning existed need no renaming.
+pub fn blob_key(uuid: Uuid, version: u32) -> String {
+    if version <= 1 {
+        uuid.to_string()
+    } else {
+        format!("{}.v{}", uuid, version)
+    }
+}
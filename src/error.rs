@@ -0,0 +1,214 @@
+use crate::database::DatabaseError;
+use crate::storage::StorageError;
+use crate::thumbnail::ThumbnailError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Errors returned by HTTP handlers.
+///
+/// Every variant maps to an HTTP status and a stable, machine-readable `code`
+/// that clients can match on; the `message` is for humans and may change.
+/// `Display` gives the full details for the server log, while responses to
+/// server-side failures only carry a generic message.
+#[derive(Debug)]
+pub enum ApiError {
+    EntryNotFound,
+    FileNotFound,
+    VersionNotFound,
+    WebhookNotFound,
+    /// No thumbnail can be produced for the entry
+    ThumbnailUnavailable(String),
+    /// The upload exceeds the configured size limit, given in bytes
+    UploadTooLarge(u64),
+    /// The server is shutting down and no longer accepts uploads
+    ShuttingDown,
+    /// The client is not in `admin.allowed_networks`
+    Forbidden,
+    BadRequest { code: &'static str, message: String },
+    /// The request clashes with the current state of the resource
+    Conflict { code: &'static str, message: String },
+    Database(DatabaseError),
+    Storage(StorageError),
+    Internal(String),
+}
+
+/// JSON body of every error response
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::BadRequest { code, message: message.into() }
+    }
+
+    /// Human-readable `message` of the response body; backend errors may name files, queries or
+    /// hosts, so they are left to the log
+    fn public_message(&self) -> String {
+        match self {
+            ApiError::Database(DatabaseError::Unavailable(_)) => {
+                "The database is temporarily unavailable; retry shortly".to_string()
+            }
+            ApiError::Database(DatabaseError::Backend(_) | DatabaseError::CorruptEntry { .. })
+            | ApiError::Storage(StorageError::Io(_))
+            | ApiError::Internal(_) => "Internal server error".to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Stable identifier for the error, returned as `code` in the response body
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::EntryNotFound => "entry_not_found",
+            ApiError::FileNotFound => "file_not_found",
+            ApiError::VersionNotFound => "version_not_found",
+            ApiError::WebhookNotFound => "webhook_not_found",
+            ApiError::ThumbnailUnavailable(_) => "thumbnail_unavailable",
+            ApiError::UploadTooLarge(_) => "upload_too_large",
+            ApiError::ShuttingDown => "shutting_down",
+            ApiError::Forbidden => "forbidden",
+            ApiError::BadRequest { code, .. } | ApiError::Conflict { code, .. } => code,
+            ApiError::Database(DatabaseError::Unavailable(_)) => "database_unavailable",
+            ApiError::Database(DatabaseError::DuplicateEntry(_)) => "duplicate_entry",
+            ApiError::Database(DatabaseError::CorruptEntry { .. }) => "corrupt_entry",
+            ApiError::Database(DatabaseError::Backend(_)) => "database_error",
+            ApiError::Storage(StorageError::Stream(_)) => "upload_interrupted",
+            ApiError::Storage(StorageError::Io(_)) => "storage_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::EntryNotFound => write!(f, "Entry not found"),
+            ApiError::FileNotFound => write!(f, "File not found"),
+            ApiError::VersionNotFound => write!(f, "Version not found"),
+            ApiError::WebhookNotFound => write!(f, "Webhook not found"),
+            ApiError::ThumbnailUnavailable(reason) => write!(f, "Thumbnail unavailable: {}", reason),
+            ApiError::UploadTooLarge(limit) => write!(f, "Uploads are limited to {} bytes", limit),
+            ApiError::ShuttingDown => write!(f, "The server is shutting down; retry shortly"),
+            ApiError::Forbidden => write!(f, "This endpoint is not available from your address"),
+            ApiError::BadRequest { message, .. } | ApiError::Conflict { message, .. } => write!(f, "{}", message),
+            ApiError::Database(e) => write!(f, "{}", e),
+            ApiError::Storage(e) => write!(f, "{}", e),
+            ApiError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::EntryNotFound
+            | ApiError::FileNotFound
+            | ApiError::VersionNotFound
+            | ApiError::WebhookNotFound
+            | ApiError::ThumbnailUnavailable(_) => StatusCode::NOT_FOUND,
+            ApiError::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Database(DatabaseError::Unavailable(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Database(DatabaseError::DuplicateEntry(_)) => StatusCode::CONFLICT,
+            ApiError::Storage(StorageError::Stream(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Database(_)
+            | ApiError::Storage(_)
+            | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            code: self.code(),
+            message: self.public_message(),
+        })
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(e: DatabaseError) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(e: StorageError) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+impl From<ThumbnailError> for ApiError {
+    fn from(e: ThumbnailError) -> Self {
+        match e {
+            ThumbnailError::Storage(e) => ApiError::Storage(e),
+            e => ApiError::ThumbnailUnavailable(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use std::io;
+    use uuid::Uuid;
+
+    async fn body_json(err: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = err.error_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_error_status_and_code_mapping() {
+        let cases = vec![
+            (ApiError::EntryNotFound, StatusCode::NOT_FOUND, "entry_not_found"),
+            (ApiError::bad_request("missing_filename", "No filename provided"), StatusCode::BAD_REQUEST, "missing_filename"),
+            (DatabaseError::DuplicateEntry(Uuid::nil()).into(), StatusCode::CONFLICT, "duplicate_entry"),
+            (DatabaseError::Unavailable("pool timeout".into()).into(), StatusCode::SERVICE_UNAVAILABLE, "database_unavailable"),
+            (
+                DatabaseError::CorruptEntry { uuid: "x".into(), reason: "bad".into() }.into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "corrupt_entry",
+            ),
+            (StorageError::Io(io::Error::other("disk")).into(), StatusCode::INTERNAL_SERVER_ERROR, "storage_error"),
+        ];
+
+        for (err, status, code) in cases {
+            let message = err.to_string();
+            let (actual_status, body) = body_json(err).await;
+            assert_eq!(actual_status, status);
+            assert_eq!(body["code"], code);
+            if status.is_client_error() {
+                assert_eq!(body["message"], message);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_server_errors_hide_backend_details() {
+        let cases: Vec<ApiError> = vec![
+            DatabaseError::Backend("no such table: entries".into()).into(),
+            DatabaseError::Unavailable("timed out waiting for /var/lib/lfs/db.sqlite".into()).into(),
+            StorageError::Io(io::Error::other("/srv/lfs/storage: permission denied")).into(),
+            ApiError::Internal("Task error: panicked".into()),
+        ];
+
+        for err in cases {
+            let (status, body) = body_json(err).await;
+            assert!(status.is_server_error());
+            let message = body["message"].as_str().unwrap();
+            for detail in ["entries", "/var/lib", "/srv", "panicked"] {
+                assert!(!message.contains(detail), "'{}' leaks '{}'", message, detail);
+            }
+        }
+    }
+}
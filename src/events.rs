@@ -0,0 +1,123 @@
+use crate::shutdown::Shutdown;
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Events a subscriber may fall behind by before it is told to resync
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Idle connections get a comment this often so proxies keep them open and dead clients are noticed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A change to the entry list, sent to clients as JSON with a `type` tag
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Uploaded { uuid: Uuid },
+    VersionAdded { uuid: Uuid, version: u32 },
+    Trashed { uuid: Uuid },
+    Restored { uuid: Uuid },
+    Purged { uuid: Uuid },
+    /// The subscriber missed events and should reload the whole list
+    Resync,
+}
+
+impl Event {
+    /// Encodes the event as one Server-Sent Events message
+    fn to_frame(&self) -> Bytes {
+        let json = serde_json::to_string(self).expect("events always serialize");
+        Bytes::from(format!("data: {}\n\n", json))
+    }
+}
+
+/// Fans out entry changes from the handlers to every connected client
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus { sender }
+    }
+
+    /// Sends an event to current subscribers; nobody listening is not an error
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns a subscription into a `text/event-stream` body.
+///
+/// The stream ends when the server starts shutting down so it does not hold up the drain;
+/// browsers reconnect on their own once the server is back.
+pub fn sse_stream(
+    receiver: broadcast::Receiver<Event>,
+    shutdown: Shutdown,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let keep_alive = tokio::time::interval_at(Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+
+    let state = (receiver, keep_alive, shutdown);
+    futures_util::stream::unfold(state, |(mut receiver, mut keep_alive, shutdown)| async move {
+        let frame = tokio::select! {
+            biased;
+            _ = shutdown.triggered() => return None,
+            received = receiver.recv() => match received {
+                Ok(event) => event.to_frame(),
+                Err(RecvError::Lagged(_)) => Event::Resync.to_frame(),
+                Err(RecvError::Closed) => return None,
+            },
+            _ = keep_alive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+        };
+        Some((Ok(frame), (receiver, keep_alive, shutdown)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_events_reach_subscribers_and_lag_resyncs() {
+        let bus = EventBus::new();
+        let uuid = Uuid::new_v4();
+
+        let shutdown = Shutdown::default();
+
+        let mut stream = Box::pin(sse_stream(bus.subscribe(), shutdown.clone()));
+        bus.publish(Event::VersionAdded { uuid, version: 2 });
+        let frame = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            frame,
+            format!("data: {{\"type\":\"version_added\",\"uuid\":\"{}\",\"version\":2}}\n\n", uuid)
+        );
+
+        // A subscriber that falls too far behind is told to reload instead of getting a partial history
+        let mut slow = Box::pin(sse_stream(bus.subscribe(), shutdown.clone()));
+        for _ in 0..=CHANNEL_CAPACITY {
+            bus.publish(Event::Trashed { uuid });
+        }
+        let frame = slow.next().await.unwrap().unwrap();
+        assert_eq!(frame, Bytes::from_static(b"data: {\"type\":\"resync\"}\n\n"));
+
+        shutdown.trigger();
+        assert!(stream.next().await.is_none(), "stream must end on shutdown");
+    }
+}
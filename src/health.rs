@@ -0,0 +1,163 @@
+use crate::database::Database;
+use crate::storage::{DeleteFileResult, Storage, StorageError, WriteFileResult};
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// A backend that does not answer within this long is reported as failing
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Written, read back and deleted by every storage probe
+const PROBE_CONTENTS: &[u8] = b"lfs readiness probe";
+
+/// Outcome of probing one backend
+#[derive(Debug, Serialize)]
+pub struct ComponentCheck {
+    /// `ok` or `error`
+    pub status: &'static str,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ComponentCheck {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Checks {
+    pub database: ComponentCheck,
+    pub storage: ComponentCheck,
+}
+
+/// Body of `/readyz`
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    /// `ready`, `not_ready` or `shutting_down`
+    pub status: &'static str,
+    pub checks: Checks,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == "ready"
+    }
+}
+
+/// Runs `probe` under the check timeout and times it
+async fn run_check<F>(probe: F) -> ComponentCheck
+where
+    F: Future<Output = Result<Option<u64>, String>>,
+{
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, probe)
+        .await
+        .unwrap_or_else(|_| Err(format!("no response within {}s", CHECK_TIMEOUT.as_secs())));
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    match result {
+        Ok(free_bytes) => ComponentCheck { status: "ok", latency_ms, free_bytes, error: None },
+        Err(error) => ComponentCheck { status: "error", latency_ms, free_bytes: None, error: Some(error) },
+    }
+}
+
+pub async fn check_database(db: &dyn Database) -> ComponentCheck {
+    run_check(async { db.ping().await.map(|_| None).map_err(|e| e.to_string()) }).await
+}
+
+/// Writes, reads back and deletes a probe object, then checks free space against `min_free_bytes`
+pub async fn check_storage(storage: &dyn Storage, min_free_bytes: u64) -> ComponentCheck {
+    run_check(async {
+        // Unique per probe so concurrent checks never trip over each other's file
+        let key = format!(".readyz-{}", Uuid::new_v4());
+        let data = Box::pin(tokio_stream::iter(vec![Ok(Bytes::from_static(PROBE_CONTENTS))]));
+        if let WriteFileResult::Failure(e) = storage.write_file(&key, data).await {
+            return Err(format!("write failed: {}", e));
+        }
+
+        let read = match storage.read_file(&key).await {
+            Ok(data) => data.try_collect::<Vec<Bytes>>().await.map_err(StorageError::from),
+            Err(e) => Err(e),
+        };
+        let deleted = storage.delete_file(&key).await;
+        match read {
+            Ok(chunks) if chunks.concat() == PROBE_CONTENTS => {}
+            Ok(_) => return Err("read back different contents than were written".to_string()),
+            Err(e) => return Err(format!("read failed: {}", e)),
+        }
+        if let DeleteFileResult::Failure(e) = deleted {
+            return Err(format!("delete failed: {}", e));
+        }
+
+        let free = storage.available_space().await.map_err(|e| e.to_string())?;
+        if free < min_free_bytes {
+            return Err(format!("{} bytes free, below the minimum of {}", free, min_free_bytes));
+        }
+        Ok(Some(free))
+    })
+    .await
+}
+
+/// Probes every backend concurrently; a server that is shutting down is never ready
+pub async fn readiness(
+    db: &dyn Database,
+    storage: &dyn Storage,
+    min_free_bytes: u64,
+    shutting_down: bool,
+) -> Readiness {
+    let (database, storage) = tokio::join!(check_database(db), check_storage(storage, min_free_bytes));
+    let status = if shutting_down {
+        "shutting_down"
+    } else if database.is_ok() && storage.is_ok() {
+        "ready"
+    } else {
+        "not_ready"
+    };
+    Readiness { status, checks: Checks { database, storage } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::fixtures::MemoryStorage;
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+
+    #[tokio::test]
+    async fn test_readiness_reports_each_backend() {
+        let dir = std::env::temp_dir().join(format!("lfs-health-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.join("storage"));
+        let db = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+
+        let ready = readiness(&db, &storage, 0, false).await;
+        assert!(ready.is_ready(), "{:?}", ready);
+        assert!(ready.checks.storage.free_bytes.unwrap() > 0);
+        assert!(storage.list_files().await.unwrap().is_empty(), "probe file left behind");
+
+        // Nothing has that much space free
+        let full = readiness(&db, &storage, u64::MAX, false).await;
+        assert_eq!(full.status, "not_ready");
+        assert!(full.checks.database.is_ok());
+        assert!(full.checks.storage.error.as_deref().unwrap().contains("below the minimum"));
+
+        assert_eq!(readiness(&db, &storage, 0, true).await.status, "shutting_down");
+
+        // The probe goes through the trait, so backends without local files pass it too
+        assert!(check_storage(&MemoryStorage::default(), 0).await.is_ok());
+
+        // A storage directory that has disappeared fails the write
+        std::fs::remove_dir_all(dir.join("storage")).unwrap();
+        let broken = readiness(&db, &storage, 0, false).await;
+        assert!(broken.checks.storage.error.as_deref().unwrap().starts_with("write failed"));
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use crate::admin::{self, CommandError};
+use crate::audit::{self, AuditRecord};
+use crate::database::Database;
+use crate::entry::{self, Entry, CLI_SOURCE};
+use crate::storage::{Storage, StorageError};
+use crate::upload;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+/// Minimum time between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where an imported entry came from, recorded so re-running an import skips finished files
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportRecord {
+    /// Absolute path of the directory the import was started from
+    pub source_root: String,
+    /// Path of the file below `source_root`, with `/` separators
+    pub relative_path: String,
+    pub uuid: Uuid,
+    pub file_size: u64,
+    /// Modification time of the source file when it was imported
+    pub modified: DateTime<Utc>,
+    pub imported_at: DateTime<Utc>,
+}
+
+/// How imported files get into storage
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ImportMode {
+    /// Copy the file, leaving the original untouched
+    Copy,
+    /// Hard-link the file into storage; needs both on the same filesystem
+    Link,
+    /// Move the file into storage, copying and deleting it across filesystems
+    Move,
+}
+
+/// Counters reported while an import runs and returned when it finishes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportProgress {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    pub imported: u64,
+    /// Files already imported by an earlier run
+    pub skipped: u64,
+    pub failed: u64,
+    /// Bytes of imported and skipped files
+    pub done_bytes: u64,
+}
+
+impl ImportProgress {
+    pub fn done_files(&self) -> u64 {
+        self.imported + self.skipped + self.failed
+    }
+}
+
+/// A regular file found below the import root
+struct SourceFile {
+    relative: PathBuf,
+    size: u64,
+}
+
+/// Lists every regular file below `root`, sorted so repeated runs visit them in the same order.
+///
+/// Symlinks are not followed. Directories that cannot be read are reported through `on_error`
+/// and skipped.
+fn walk(root: &Path, on_error: &mut impl FnMut(&Path, io::Error)) -> io::Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    // The root itself must be readable; everything below it is best effort
+    std::fs::read_dir(root)?;
+
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(root.join(&dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                on_error(&root.join(&dir), e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    on_error(&root.join(&dir), e);
+                    continue;
+                }
+            };
+            let relative = dir.join(entry.file_name());
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => pending.push(relative),
+                Ok(meta) if meta.is_file() => files.push(SourceFile { relative, size: meta.len() }),
+                Ok(_) => {}
+                Err(e) => on_error(&root.join(&relative), e),
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.relative.cmp(&b.relative));
+    Ok(files)
+}
+
+/// `/`-separated form of a relative path, the same on every platform
+fn normalize(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Puts `path` into storage under `key` without copying its data, returning false if it has to
+/// be copied instead because storage is on another filesystem
+async fn link_into_storage(storage: &dyn Storage, path: &Path, key: &str) -> io::Result<bool> {
+    match tokio::fs::hard_link(path, storage.get_file_path(key)).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Imports one file, or skips it if an earlier run already did
+async fn import_file(
+    db: &dyn Database,
+    storage: &Arc<Box<dyn Storage>>,
+    root: &Path,
+    source_root: &str,
+    relative: &Path,
+    mode: ImportMode,
+) -> Result<bool, CommandError> {
+    let path = root.join(relative);
+    let io_error = |e| CommandError::Io(path.clone(), e);
+    let relative_path = normalize(relative);
+    let meta = tokio::fs::metadata(&path).await.map_err(io_error)?;
+    let modified: DateTime<Utc> = meta.modified().map_err(io_error)?.into();
+
+    if let Some(record) = db.get_import(source_root, &relative_path).await? {
+        // A move that stopped before deleting the source finishes now, unless the file has changed since
+        if mode == ImportMode::Move && record.file_size == meta.len() && record.modified == modified {
+            tokio::fs::remove_file(&path).await.map_err(io_error)?;
+        }
+        return Ok(false);
+    }
+
+    let file_name = relative
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| relative_path.clone());
+    let uuid = Uuid::new_v4();
+    let key = entry::blob_key(uuid, 1);
+
+    let linked = match mode {
+        ImportMode::Copy => false,
+        ImportMode::Link | ImportMode::Move => {
+            let linked = link_into_storage(&***storage, &path, &key).await.map_err(io_error)?;
+            if !linked && mode == ImportMode::Link {
+                return Err(io_error(io::Error::new(
+                    io::ErrorKind::CrossesDevices,
+                    "cannot hard-link across filesystems; use --mode copy or move",
+                )));
+            }
+            linked
+        }
+    };
+    let stored = if linked {
+        let described = match storage.read_file(&key).await {
+            Ok(mut data) => upload::describe_stream(&mut data, &file_name).await.map_err(StorageError::from),
+            Err(e) => Err(e),
+        };
+        match described {
+            Ok(stored) => stored,
+            Err(e) => {
+                let _ = storage.delete_file(&key).await;
+                return Err(e.into());
+            }
+        }
+    } else {
+        let file = tokio::fs::File::open(&path).await.map_err(io_error)?;
+        upload::store_stream(&mut ReaderStream::new(file), storage, &key, &file_name, 0).await?
+    };
+
+    let entry = Entry {
+        uuid,
+        file_name,
+        file_size: stored.size,
+        source_ip: CLI_SOURCE.to_string(),
+        timestamp: modified,
+        deleted_at: None,
+        version: 1,
+        sha256: Some(stored.sha256),
+        mime_type: stored.mime_type,
+    };
+    let record = ImportRecord {
+        source_root: source_root.to_string(),
+        relative_path,
+        uuid,
+        file_size: stored.size,
+        modified,
+        imported_at: Utc::now(),
+    };
+    let detail = Some(path.display().to_string());
+    let audit_record = AuditRecord { detail, ..admin::audit_record("entry.import", &entry) };
+    if let Err(e) = db.insert_imported_entry(entry, record).await {
+        let _ = storage.delete_file(&key).await;
+        return Err(e.into());
+    }
+    audit::append(db, audit_record).await;
+
+    if mode == ImportMode::Move {
+        tokio::fs::remove_file(&path).await.map_err(io_error)?;
+    }
+    Ok(true)
+}
+
+/// Imports every regular file below `root` as an entry named after the file and dated by its
+/// modification time.
+///
+/// Each file is recorded together with its entry, so an interrupted import can simply be run
+/// again and picks up where it stopped. Individual failures are passed to `on_error` and do not
+/// stop the import; `on_progress` is called every couple of seconds and once at the end.
+pub async fn import_tree(
+    db: &dyn Database,
+    storage: &Arc<Box<dyn Storage>>,
+    root: &Path,
+    mode: ImportMode,
+    mut on_progress: impl FnMut(&ImportProgress),
+    mut on_error: impl FnMut(&Path, &CommandError),
+) -> Result<ImportProgress, CommandError> {
+    let root = std::fs::canonicalize(root).map_err(|e| CommandError::Io(root.to_path_buf(), e))?;
+    let source_root = root.to_string_lossy().into_owned();
+
+    let mut progress = ImportProgress::default();
+    let files = walk(&root, &mut |path, e| {
+        progress.failed += 1;
+        on_error(path, &CommandError::Io(path.to_path_buf(), e));
+    })
+    .map_err(|e| CommandError::Io(root.clone(), e))?;
+    progress.total_files = files.len() as u64 + progress.failed;
+    progress.total_bytes = files.iter().map(|file| file.size).sum();
+
+    let mut reported = Instant::now();
+    for file in &files {
+        match import_file(db, storage, &root, &source_root, &file.relative, mode).await {
+            Ok(true) => progress.imported += 1,
+            Ok(false) => progress.skipped += 1,
+            Err(e) => {
+                progress.failed += 1;
+                on_error(&root.join(&file.relative), &e);
+            }
+        }
+        progress.done_bytes += file.size;
+
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            on_progress(&progress);
+            reported = Instant::now();
+        }
+    }
+    on_progress(&progress);
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+
+    #[tokio::test]
+    async fn test_import_tree_resumes_and_moves() {
+        let dir = std::env::temp_dir().join(format!("lfs-import-{}", Uuid::new_v4()));
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("photos/2019")).unwrap();
+        std::fs::write(tree.join("readme.txt"), b"top level").unwrap();
+        std::fs::write(tree.join("photos/2019/beach.txt"), b"sand").unwrap();
+
+        let storage: Arc<Box<dyn Storage>> = Arc::new(Box::new(LocalStorage::new(dir.join("storage"))));
+        let db = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+        let import = |mode| import_tree(&db, &storage, &tree, mode, |_| {}, |path, e| panic!("{}: {}", path.display(), e));
+
+        let first = import(ImportMode::Copy).await.unwrap();
+        assert_eq!((first.total_files, first.imported, first.skipped, first.total_bytes), (2, 2, 0, 13));
+
+        let root = std::fs::canonicalize(&tree).unwrap().to_string_lossy().into_owned();
+        let record = db.get_import(&root, "photos/2019/beach.txt").await.unwrap().expect("import not recorded");
+        let entry = db.get_entry(record.uuid).await.unwrap().unwrap();
+        assert_eq!((entry.file_name.as_str(), entry.file_size), ("beach.txt", 4));
+        assert_eq!(entry.timestamp, record.modified, "entry not dated by the file's mtime");
+        assert_eq!(std::fs::read(storage.get_file_path(&entry.blob_key())).unwrap(), b"sand");
+
+        // A second run only adds what is new
+        std::fs::write(tree.join("photos/late.txt"), b"new").unwrap();
+        let second = import(ImportMode::Move).await.unwrap();
+        assert_eq!((second.imported, second.skipped), (1, 2));
+        assert_eq!(db.list_uuids().await.unwrap().len(), 3);
+
+        // Moving deletes the new file's source, and finishes earlier imports that match their record
+        assert!(!tree.join("photos/late.txt").exists());
+        assert!(!tree.join("readme.txt").exists());
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! Library side of the LFS file server, shared by the `lfs` binary and by out-of-tree backends.
+//!
+//! A new `Storage` or `Database` backend can check itself against the same conformance suites as
+//! the built-in ones by enabling the `conformance` feature.
+
+pub mod access;
+pub mod admin;
+pub mod audit;
+pub mod backup;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod config;
+pub mod content_type;
+pub mod database;
+pub mod entry;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod import;
+pub mod scrub;
+pub mod shutdown;
+pub mod stats;
+pub mod sqlite_database;
+pub mod storage;
+pub mod local_storage;
+pub mod logging;
+pub mod metrics;
+pub mod privacy;
+pub mod proxy;
+pub mod thumbnail;
+pub mod tls;
+pub mod transfer;
+pub mod trash;
+pub mod upload;
+pub mod webhook;
@@ -1,14 +1,15 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use tokio::fs::File;
-use std::{error::Error, fs};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::io;
 use tokio::io::AsyncWriteExt;
 use tokio_stream::{Stream, StreamExt};
+use tokio_util::io::ReaderStream;
 
-use crate::storage::{DeleteFileResult, Storage, WriteFileResult};
+use crate::storage::{ByteStream, DeleteFileResult, FileInfo, Storage, StorageError, WriteFileResult};
 
 pub struct LocalStorage {
     storage_path: PathBuf,
@@ -31,7 +32,7 @@ impl Storage for LocalStorage {
         let file_path = self.get_file_path(uuid);
         let file = match File::create(&file_path).await {
             Ok(f) => f,
-            Err(e) => return WriteFileResult::Failure(e),
+            Err(e) => return WriteFileResult::Failure(StorageError::Io(e)),
         };
         
         let mut file = file;
@@ -39,10 +40,10 @@ impl Storage for LocalStorage {
             match chunk {
                 Ok(chunk) => {
                     if let Err(e) = file.write_all(&chunk).await {
-                        return WriteFileResult::Failure(e);
+                        return WriteFileResult::Failure(StorageError::Io(e));
                     }
                 }
-                Err(e) => return WriteFileResult::Failure(e),
+                Err(e) => return WriteFileResult::Failure(StorageError::Stream(e)),
             }
         }
         
@@ -53,6 +54,19 @@ impl Storage for LocalStorage {
         self.storage_path.join(uuid)
     }
 
+    async fn read_file(&self, uuid: &str) -> Result<ByteStream, StorageError> {
+        let file = File::open(self.get_file_path(uuid)).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    async fn file_info(&self, uuid: &str) -> Result<Option<FileInfo>, StorageError> {
+        match tokio::fs::metadata(self.get_file_path(uuid)).await {
+            Ok(meta) => Ok(Some(FileInfo { size: meta.len(), modified: meta.modified()? })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StorageError::Io(e)),
+        }
+    }
+
     async fn delete_file(&self, uuid: &str) -> DeleteFileResult {
         let file_path = self.get_file_path(uuid);
         
@@ -62,21 +76,19 @@ impl Storage for LocalStorage {
 
         match tokio::fs::remove_file(file_path).await {
             Ok(_) => DeleteFileResult::Success,
-            Err(e) => DeleteFileResult::Failure(e),
+            Err(e) => DeleteFileResult::Failure(StorageError::Io(e)),
         }
     }
 
-    async fn list_files(&self) -> Result<Vec<String>, Box<dyn Error>> {
+    async fn list_files(&self) -> Result<Vec<String>, StorageError> {
         let mut files = Vec::new();
         let entries = fs::read_dir(&self.storage_path)?;
 
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Ok(file_type) = entry.file_type() {
-                    if file_type.is_file() {
-                        if let Some(file_name) = entry.file_name().to_str() {
-                            files.push(file_name.to_string());
-                        }
+        for entry in entries.flatten() {
+            if let Ok(file_type) = entry.file_type() {
+                if file_type.is_file() {
+                    if let Some(file_name) = entry.file_name().to_str() {
+                        files.push(file_name.to_string());
                     }
                 }
             }
@@ -84,13 +96,46 @@ impl Storage for LocalStorage {
 
         Ok(files)
     }
+
+    async fn available_space(&self) -> Result<u64, StorageError> {
+        let path = self.storage_path.clone();
+        tokio::task::spawn_blocking(move || available_space(&path))
+            .await
+            .map_err(io::Error::other)?
+            .map_err(StorageError::Io)
+    }
+}
+
+/// Space available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read after statvfs reports success
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    // The field types are narrower than u64 on some platforms
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only reported on Unix"))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
-    use futures_util::StreamExt;
 
     const TEST_UUID: &str = "550e8400-e29b-41d4-a716-446655440000";
     const TEST_DIR: &str = "./test-storage";
@@ -122,7 +167,18 @@ mod tests {
         // Clean up test directory
         fs::remove_dir_all(TEST_DIR).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_local_storage_conformance() {
+        let dir = std::env::temp_dir().join(format!("lfs-storage-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.clone());
+
+        crate::conformance::storage_suite(&storage).await;
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
 
 
 
+
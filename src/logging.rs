@@ -0,0 +1,115 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpanBuilder};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Response header echoing the ID that tags every log line of the request
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// How log lines are written to stdout
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines with the enclosing spans' fields
+    Pretty,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Installs the global subscriber; `filter` uses `EnvFilter` directives such as `info,lfs=debug`
+pub fn init(filter: EnvFilter, format: LogFormat) {
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Pretty => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json().with_current_span(false).with_span_list(true))
+            .init(),
+    }
+}
+
+/// Root span for each request, carrying the request ID every nested span and event inherits
+pub struct RequestSpan;
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let request_id = request.extensions().get::<RequestId>().copied();
+        let client_ip = crate::proxy::client_ip(request.request());
+        tracing::info_span!(
+            "request",
+            request_id = %request_id.map(|id| id.to_string()).unwrap_or_default(),
+            method = %request.method(),
+            path = %request.path(),
+            client_ip = client_ip.as_deref().unwrap_or("unknown"),
+            http.status_code = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            exception.message = tracing::field::Empty,
+            exception.details = tracing::field::Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// Logs the outcome of every request and returns its ID in `X-Request-Id`.
+///
+/// Must run inside `TracingLogger` so the request span and ID already exist.
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req.extensions().get::<RequestId>().copied();
+    let started = Instant::now();
+    let mut response = next.call(req).await?;
+
+    let status = response.status().as_u16();
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    if response.status().is_server_error() {
+        // Responses only carry a generic message, so the details have to be kept here
+        let error = response.response().error().map(ToString::to_string).unwrap_or_default();
+        tracing::error!(status, elapsed_ms, error, "request failed");
+    } else {
+        tracing::info!(status, elapsed_ms, "request completed");
+    }
+
+    if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(&id.to_string()).ok()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware, test, web, App, HttpResponse};
+    use tracing_actix_web::TracingLogger;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn test_responses_carry_a_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(middleware::from_fn(log_requests))
+                .wrap(TracingLogger::<RequestSpan>::new())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let response = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+            let id = response.headers().get(REQUEST_ID_HEADER).expect("missing request id");
+            ids.push(Uuid::parse_str(id.to_str().unwrap()).expect("request id is not a UUID"));
+        }
+        assert_ne!(ids[0], ids[1], "request ids must be unique");
+    }
+}
@@ -1,38 +1,43 @@
-use crate::database::Database;
-use crate::sqlite_database::SqliteDatabase;
-use crate::storage::Storage;
-use crate::local_storage::LocalStorage;
-use actix_web::{web, App, HttpServer, Responder, HttpResponse, get, delete, post, HttpRequest};
-use entry::Entry;
-use storage::{DeleteFileResult, WriteFileResult};
-use std::env;
+use lfs::{admin, audit, backup, content_type, entry, events, health, logging, metrics, privacy, shutdown, stats, thumbnail, tls, trash, upload, webhook};
+use lfs::audit::AuditFilter;
+use lfs::privacy::IpPrivacy;
+use lfs::access::{AdminAccess, AdminNetworks};
+use lfs::proxy::{client_ip, TrustedProxies};
+use lfs::config::{Cli, Command, Config, CorsConfig, LimitsConfig, StatsConfig, StorageConfig};
+use lfs::database::{init_database, Database, DatabaseError};
+use lfs::error::ApiError;
+use lfs::storage::{init_storage, Storage};
+use actix_web::{web, middleware, App, HttpServer, Responder, HttpResponse, get, delete, post, put, HttpRequest};
+use actix_web::body::{BodySize, MessageBody};
+use lfs::entry::{Entry, FileVersion};
+use lfs::events::{Event, EventBus};
+use lfs::logging::RequestSpan;
+use lfs::metrics::{InstrumentedDatabase, InstrumentedStorage, METRICS};
+use lfs::scrub::Scrubber;
+use lfs::stats::Download;
+use serde::Deserialize;
+use lfs::storage::{DeleteFileResult, StorageError};
+use lfs::thumbnail::ThumbnailConfig;
+use clap::Parser;
+use lfs::shutdown::Shutdown;
+use lfs::upload::{ActiveUploads, RevisionLocks};
+use lfs::webhook::{Webhook, WebhookDispatcher, WebhookEvent};
 use std::sync::Arc;
 use uuid::Uuid;
-use std::path::PathBuf;
+use tracing_actix_web::TracingLogger;
+use tracing_subscriber::EnvFilter;
 use chrono::Utc;
 use futures_util::TryStreamExt;
-use bytes::Bytes;
-use futures_util::StreamExt;
-use actix_multipart::Multipart;
-use std::io;
-use tokio::sync::mpsc;
+use actix_multipart::{Field, Multipart};
 use actix_cors::Cors;
 use actix_files::NamedFile;
-use actix_web::http::header::{ContentDisposition, DispositionType, DispositionParam};
-
-mod database;
-mod entry;
-mod sqlite_database;
-mod storage;
-mod local_storage;
+use actix_web::http::header::{self, ContentDisposition, DispositionType, DispositionParam, EntityTag, HeaderValue};
+use lfs::content_type::Presentation;
 
 // List all entry UUIDs
 #[get("/entries")]
-async fn list_entries(db: web::Data<Arc<Box<dyn Database>>>) -> impl Responder {
-    match db.list_uuids().await {
-        Ok(uuids) => HttpResponse::Ok().json(uuids),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+async fn list_entries(db: web::Data<Arc<Box<dyn Database>>>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(db.list_uuids().await?))
 }
 
 // Get a specific entry by UUID
@@ -40,199 +45,799 @@ async fn list_entries(db: web::Data<Arc<Box<dyn Database>>>) -> impl Responder {
 async fn get_entry(
     db: web::Data<Arc<Box<dyn Database>>>,
     path: web::Path<Uuid>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let uuid = path.into_inner();
-    match db.get_entry(uuid).await {
-        Ok(Some(entry)) => HttpResponse::Ok().json(entry),
-        Ok(None) => HttpResponse::NotFound().body("Entry not found"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => Ok(HttpResponse::Ok().json(entry)),
+        _ => Err(ApiError::EntryNotFound),
     }
 }
 
-// Delete an entry by UUID and its associated file from storage
+// Move an entry to the trash; its file is kept until the entry is purged
 #[delete("/entry/{uuid}")]
 async fn delete_entry(
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    events: web::Data<EventBus>,
+    webhooks: web::Data<WebhookDispatcher>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+
+    if !db.trash_entry(uuid, Utc::now()).await? {
+        return Err(ApiError::EntryNotFound);
+    }
+    events.publish(Event::Trashed { uuid });
+    METRICS.deletes.with_label_values(&["trash"]).inc();
+    if let Some(entry) = db.get_entry(uuid).await? {
+        audit::note_entry(&req, uuid, &entry.file_name);
+        tracing::info!(%uuid, size = entry.file_size, client_ip = client_ip(&req), "entry moved to trash");
+        webhooks.notify(WebhookEvent::Deleted, &entry);
+    }
+    Ok(HttpResponse::Ok().body("Entry moved to trash"))
+}
+
+// List trashed entries
+#[get("/trash")]
+async fn list_trash(db: web::Data<Arc<Box<dyn Database>>>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(db.list_trash().await?))
+}
+
+// Restore a trashed entry
+#[post("/trash/{uuid}/restore")]
+async fn restore_entry(
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    events: web::Data<EventBus>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+
+    if !db.restore_entry(uuid).await? {
+        return Err(ApiError::EntryNotFound);
+    }
+    events.publish(Event::Restored { uuid });
+    if let Some(entry) = db.get_entry(uuid).await? {
+        audit::note_entry(&req, uuid, &entry.file_name);
+    }
+    tracing::info!(%uuid, client_ip = client_ip(&req), "entry restored");
+    Ok(HttpResponse::Ok().body("Entry restored"))
+}
+
+// Permanently delete a trashed entry and its associated file from storage
+#[delete("/trash/{uuid}")]
+async fn purge_entry(
+    req: HttpRequest,
     db: web::Data<Arc<Box<dyn Database>>>,
     storage: web::Data<Arc<Box<dyn Storage>>>,
+    events: web::Data<EventBus>,
     path: web::Path<Uuid>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let uuid = path.into_inner();
     
-    // First check if entry exists in database
-    let entry = match db.get_entry(uuid).await {
-        Ok(Some(entry)) => entry,
-        Ok(None) => return HttpResponse::NotFound().body("Entry not found"),
-        Err(e) => return HttpResponse::InternalServerError()
-            .body(format!("Database error: {}", e)),
+    // Only entries already in the trash can be purged
+    let entry = match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_some() => entry,
+        _ => return Err(ApiError::EntryNotFound),
     };
+    let size = entry.file_size;
+    audit::note_entry(&req, uuid, &entry.file_name);
 
-    // Delete from database
-    match db.delete_entry(uuid).await {
-        Ok(true) => {
-            // Try to delete from storage
-            match storage.delete_file(&uuid.to_string()).await {
-                DeleteFileResult::Success => {
-                    HttpResponse::Ok().body("Entry deleted")
-                },
-                DeleteFileResult::NotFound => {
-                    // File not found in storage but database entry was deleted - this is ok
-                    HttpResponse::Ok().body("Entry deleted")
-                },
-                DeleteFileResult::Failure(e) => {
-                    // Storage deletion failed - attempt to restore database entry
-                    if let Err(db_err) = db.insert_entry(entry).await {
-                        return HttpResponse::InternalServerError()
-                            .body(format!("Critical error: Storage deletion failed AND database restoration failed. Storage error: {}, Database error: {}", e, db_err));
-                    }
-                    HttpResponse::InternalServerError()
-                        .body(format!("Storage error: {}", e))
-                }
-            }
-        },
-        Ok(false) => HttpResponse::InternalServerError()
-            .body("Database entry not removed"),
-        Err(e) => HttpResponse::InternalServerError()
-            .body(format!("Database error: {}", e)),
-    }
+    trash::purge_entry(
+        db.get_ref().as_ref().as_ref(),
+        storage.get_ref().as_ref().as_ref(),
+        entry,
+    )
+    .await?;
+    events.publish(Event::Purged { uuid });
+    tracing::info!(%uuid, size, client_ip = client_ip(&req), "entry purged");
+    Ok(HttpResponse::Ok().body("Entry purged"))
 }
 
 
 
+#[derive(Deserialize)]
+struct ContentsQuery {
+    /// Serve this version instead of the current one
+    version: Option<u32>,
+    /// Ask for the file to be shown in the browser rather than downloaded (`1` or `true`)
+    inline: Option<String>,
+}
+
+/// Content-Security-Policy for rendering types that could otherwise run script
+const SANDBOX_CSP: &str = "sandbox; default-src 'none'; img-src data:; style-src 'unsafe-inline'";
+
 /// Allows content to be downloaded from the server
 #[get("/contents/{uuid}")]
 async fn download_file(
+    req: HttpRequest,
     db: web::Data<Arc<Box<dyn Database>>>,
     storage: web::Data<Arc<Box<dyn Storage>>>,
+    stats_config: web::Data<StatsConfig>,
     path: web::Path<Uuid>,
-) -> Result<NamedFile, actix_web::Error> {
-    let uuid = path.into_inner();
-    
-    match db.get_entry(uuid).await {
-        Ok(Some(entry)) => {
-            let file_path = storage.get_file_path(&uuid.to_string());
-            
-            if !file_path.exists() {
-                return Err(actix_web::error::ErrorNotFound("File not found"));
+    query: web::Query<ContentsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let inline = matches!(query.inline.as_deref(), Some("1") | Some("true"));
+    serve_contents(&req, &db, &storage, &stats_config, path.into_inner(), query.version, inline).await
+}
+
+/// Shows content in the browser where its type allows, falling back to a download
+#[get("/view/{uuid}")]
+async fn view_file(
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+    stats_config: web::Data<StatsConfig>,
+    path: web::Path<Uuid>,
+    query: web::Query<ContentsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    serve_contents(&req, &db, &storage, &stats_config, path.into_inner(), query.version, true).await
+}
+
+async fn serve_contents(
+    req: &HttpRequest,
+    db: &Arc<Box<dyn Database>>,
+    storage: &Arc<Box<dyn Storage>>,
+    stats_config: &StatsConfig,
+    uuid: Uuid,
+    version: Option<u32>,
+    inline: bool,
+) -> Result<HttpResponse, ApiError> {
+    let entry = match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => entry,
+        _ => return Err(ApiError::EntryNotFound),
+    };
+
+    let (version, key, mime_type) = match version {
+        None => (entry.version, entry.blob_key(), entry.mime_type.clone()),
+        Some(version) if version == entry.version => (version, entry.blob_key(), entry.mime_type.clone()),
+        Some(version) => {
+            let found = db.list_versions(uuid).await?.into_iter().find(|v| v.version == version);
+            match found {
+                Some(found) => (version, entry::blob_key(uuid, version), found.mime_type),
+                None => return Err(ApiError::VersionNotFound),
             }
+        }
+    };
+    let file_path = storage.get_file_path(&key);
+    
+    if !file_path.exists() {
+        return Err(ApiError::FileNotFound);
+    }
+
+    let presentation = if inline {
+        content_type::presentation(&mime_type)
+    } else {
+        Presentation::Attachment
+    };
+    let disposition = match presentation {
+        Presentation::Attachment => DispositionType::Attachment,
+        Presentation::Inline | Presentation::Sandboxed => DispositionType::Inline,
+    };
+
+    let mut response = NamedFile::open(file_path)
+        .map_err(StorageError::Io)?
+        .set_content_type(mime_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM))
+        .set_content_disposition(ContentDisposition {
+            disposition,
+            parameters: vec![DispositionParam::Filename(entry.file_name)],
+        })
+        .use_last_modified(true)
+        .into_response(req);
+
+    // Range and conditional requests only send part of the file, or none of it
+    let sent = match response.body().size() {
+        BodySize::Sized(len) => len,
+        _ => 0,
+    };
+    METRICS.sent_bytes.inc_by(sent);
+    if response.status().is_success() {
+        let first_byte = stats::first_byte(&response);
+        if first_byte == 0 {
+            METRICS.downloads.inc();
+        }
+        stats::record_download(db, Download {
+            uuid,
+            version,
+            bytes: sent,
+            client_ip: stats_config.record_client_ip.then(|| client_ip(req)).flatten(),
+            timestamp: Utc::now(),
+            first_byte,
+        });
+    }
+
+    // Browsers must trust the stored type rather than guess one that might execute
+    let headers = response.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if presentation == Presentation::Sandboxed {
+        headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(SANDBOX_CSP));
+    }
+
+    Ok(response)
+}
+
+// Download count, bytes served and last access of an entry
+#[get("/entry/{uuid}/stats")]
+async fn entry_stats(
+    db: web::Data<Arc<Box<dyn Database>>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+    match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => Ok(HttpResponse::Ok().json(db.download_stats(uuid).await?)),
+        _ => Err(ApiError::EntryNotFound),
+    }
+}
 
-            Ok(NamedFile::open(file_path)?
-                .set_content_disposition(ContentDisposition {
-                    disposition: DispositionType::Attachment,
-                    parameters: vec![DispositionParam::Filename(entry.file_name)],
-                })
-                .use_last_modified(true))
-        },
-        Ok(None) => Err(actix_web::error::ErrorNotFound("Entry not found")),
-        Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// Days of upload and download volume to report, today included
+    #[serde(default = "default_stats_days")]
+    days: u32,
+    /// Number of most downloaded entries to list
+    #[serde(default = "default_stats_top")]
+    top: usize,
+}
+
+fn default_stats_days() -> u32 {
+    30
+}
+
+fn default_stats_top() -> usize {
+    10
+}
+
+// Totals, the most downloaded entries and daily upload and download volume
+#[get("/stats")]
+async fn server_stats(
+    db: web::Data<Arc<Box<dyn Database>>>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if !(1..=366).contains(&query.days) {
+        return Err(ApiError::bad_request("invalid_days", "days must be between 1 and 366"));
+    }
+    if query.top > 100 {
+        return Err(ApiError::bad_request("invalid_top", "top must be at most 100"));
+    }
+
+    let usage = db.usage().await?;
+    let top: Vec<_> = db
+        .top_downloads(query.top)
+        .await?
+        .into_iter()
+        .map(|(entry, stats)| {
+            serde_json::json!({
+                "uuid": entry.uuid,
+                "file_name": entry.file_name,
+                "file_size": entry.file_size,
+                "downloads": stats.downloads,
+                "served_bytes": stats.served_bytes,
+                "last_accessed": stats.last_accessed,
+            })
+        })
+        .collect();
+    let today = Utc::now().date_naive();
+    let since = (today - chrono::Duration::days(i64::from(query.days) - 1)).and_time(chrono::NaiveTime::MIN).and_utc();
+    let days = db.daily_volume(since).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "entries": usage.entries,
+        "trashed_entries": usage.trashed_entries,
+        "stored_bytes": usage.stored_bytes,
+        "downloads": usage.downloads,
+        "served_bytes": usage.served_bytes,
+        "top": top,
+        "days": days,
+    })))
+}
+
+// List every stored version of an entry
+#[get("/entry/{uuid}/versions")]
+async fn list_versions(
+    db: web::Data<Arc<Box<dyn Database>>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+    match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => Ok(HttpResponse::Ok().json(db.list_versions(uuid).await?)),
+        _ => Err(ApiError::EntryNotFound),
+    }
+}
+
+#[derive(Deserialize)]
+struct PruneQuery {
+    /// Number of most recent versions to keep, including the current one
+    keep: u32,
+}
+
+// Delete old versions of an entry, keeping the newest `keep`
+#[delete("/entry/{uuid}/versions")]
+async fn prune_versions(
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+    path: web::Path<Uuid>,
+    query: web::Query<PruneQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+    if query.keep == 0 {
+        return Err(ApiError::bad_request("invalid_keep", "At least one version must be kept"));
+    }
+
+    let entry = match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => entry,
+        _ => return Err(ApiError::EntryNotFound),
+    };
+    audit::note_entry(&req, uuid, &entry.file_name);
+
+    let mut versions = db.list_versions(uuid).await?;
+    versions.sort_by_key(|v| std::cmp::Reverse(v.version));
+
+    let mut pruned = Vec::new();
+    for version in versions.iter().skip(query.keep as usize) {
+        if version.version == entry.version {
+            continue;
+        }
+        // Remove the blob first so a storage failure never leaves a version record without its file
+        if let DeleteFileResult::Failure(e) = storage.delete_file(&entry::blob_key(uuid, version.version)).await {
+            return Err(e.into());
+        }
+        if db.delete_version(uuid, version.version).await? {
+            pruned.push(version.version);
+        }
+        thumbnail::delete_thumbnails(storage.get_ref().as_ref().as_ref(), uuid, version.version).await;
     }
+
+    let list: Vec<String> = pruned.iter().map(u32::to_string).collect();
+    audit::note_detail(&req, format!("pruned versions [{}]", list.join(", ")));
+    Ok(HttpResponse::Ok().json(pruned))
 }
 
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    /// Edge length in pixels; must be one of the configured sizes
+    size: Option<u32>,
+}
 
+// Serve a PNG thumbnail of an image entry, generating it on first request
+#[get("/thumb/{uuid}")]
+async fn get_thumbnail(
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+    thumbnails: web::Data<ThumbnailConfig>,
+    path: web::Path<Uuid>,
+    query: web::Query<ThumbnailQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+    let size = query.size.unwrap_or_else(|| thumbnails.default_size());
+    if !thumbnails.sizes.contains(&size) {
+        let sizes: Vec<String> = thumbnails.sizes.iter().map(u32::to_string).collect();
+        return Err(ApiError::bad_request(
+            "invalid_thumbnail_size",
+            format!("Thumbnail size must be one of: {}", sizes.join(", ")),
+        ));
+    }
 
+    let entry = match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => entry,
+        _ => return Err(ApiError::EntryNotFound),
+    };
+
+    // The URL stays the same across versions, so browsers revalidate against the version-specific tag
+    let etag = EntityTag::new_strong(format!("{}-v{}-{}", uuid, entry.version, size));
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag.to_string()));
+    if not_modified {
+        return Ok(HttpResponse::NotModified().insert_header(header::ETag(etag)).finish());
+    }
+
+    let png = thumbnail::get_or_create(storage.get_ref().as_ref().as_ref(), &entry, size).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(png))
+}
 
+/// Reads the first multipart field of an upload along with its filename
+async fn next_file_field(payload: &mut Multipart) -> Result<(Field, String), ApiError> {
+    let field = match payload.try_next().await {
+        Ok(Some(field)) => field,
+        _ => return Err(ApiError::bad_request("missing_file", "No file in request")),
+    };
 
+    let filename = match field
+        .content_disposition()
+        .and_then(|cd| cd.get_filename())
+    {
+        Some(name) => name.to_string(),
+        None => return Err(ApiError::bad_request("missing_filename", "No filename provided")),
+    };
+
+    Ok((field, filename))
+}
 
 #[post("/upload")]
+#[allow(clippy::too_many_arguments)]
 async fn upload_file(
     mut payload: Multipart,
     req: HttpRequest,
     storage: web::Data<Arc<Box<dyn Storage>>>,
     db: web::Data<Arc<Box<dyn Database>>>,
-) -> impl Responder {
+    thumbnails: web::Data<ThumbnailConfig>,
+    events: web::Data<EventBus>,
+    webhooks: web::Data<WebhookDispatcher>,
+    limits: web::Data<LimitsConfig>,
+    uploads: web::Data<ActiveUploads>,
+) -> Result<HttpResponse, ApiError> {
     let uuid = Uuid::new_v4();
-    let uuid_str = uuid.to_string();
-    
-    if let Ok(Some(mut field)) = payload.try_next().await {
-        let filename = match field
-            .content_disposition()
-            .and_then(|cd| cd.get_filename())
-        {
-            Some(name) => name.to_string(),
-            None => return HttpResponse::BadRequest().body("No filename provided"),
-        };
-            
-        let content_length = match req.headers().get("Content-Length") {
-            Some(len) => match len.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
-                Some(len) => len,
-                None => return HttpResponse::BadRequest().body("Invalid Content-Length header"),
-            },
-            None => return HttpResponse::BadRequest().body("Content-Length header required"),
-        };
-
-        let (tx, rx) = mpsc::channel::<Result<Bytes, io::Error>>(1024);
-        let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-        let pinned_stream = Box::pin(stream);
-
-        let storage_clone = Arc::clone(&storage);
-        let uuid_for_storage = uuid_str.clone();
-        
-        let storage_handle = tokio::spawn(async move {
-            storage_clone.write_file(&uuid_for_storage, pinned_stream).await
-        });
+    let key = entry::blob_key(uuid, 1);
+    let upload = uploads.begin(&key, &storage)?;
+    let (mut field, filename) = next_file_field(&mut payload).await?;
+    audit::note_entry(&req, uuid, &filename);
 
-        // Process the field in the current task
-        while let Some(chunk) = field.next().await {
-            match chunk {
-                Ok(data) => {
-                    if tx.send(Ok(data)).await.is_err() {
-                        let _ = storage.delete_file(&uuid_str).await;
-                        return HttpResponse::InternalServerError()
-                            .body("Failed to process upload stream");
-                    }
-                }
-                Err(e) => {
-                    let io_error = io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Multipart error: {}", e)
-                    );
-                    let _ = tx.send(Err(io_error)).await;
-                    let _ = storage.delete_file(&uuid_str).await;
-                    return HttpResponse::InternalServerError()
-                        .body(format!("Upload error: {}", e));
-                }
-            }
+    let stored = upload::store_field(&mut field, &storage, &key, &filename, limits.max_upload_size).await?;
+
+    let entry = Entry {
+        uuid,
+        file_name: filename,
+        file_size: stored.size,
+        source_ip: client_ip(&req).unwrap_or_default(),
+        timestamp: Utc::now(),
+        deleted_at: None,
+        version: 1,
+        sha256: Some(stored.sha256),
+        mime_type: stored.mime_type,
+    };
+
+    match db.insert_entry(entry.clone()).await {
+        Ok(_) => {
+            upload.finish();
+            tracing::info!(
+                %uuid,
+                file_name = %entry.file_name,
+                size = entry.file_size,
+                mime_type = %entry.mime_type,
+                client_ip = %entry.source_ip,
+                "file uploaded"
+            );
+            METRICS.uploads.with_label_values(&["entry"]).inc();
+            METRICS.received_bytes.inc_by(entry.file_size);
+            METRICS.upload_size.observe(entry.file_size as f64);
+            webhooks.notify(WebhookEvent::Uploaded, &entry);
+            thumbnail::spawn_generate(Arc::clone(&storage), entry, thumbnails.get_ref().clone());
+            events.publish(Event::Uploaded { uuid });
+            Ok(HttpResponse::Ok().json(uuid))
         }
-        
-        drop(tx);
-
-        match storage_handle.await {
-            Ok(WriteFileResult::Success) => {
-                let entry = Entry {
-                    uuid,
-                    file_name: filename,
-                    file_size: content_length,
-                    source_ip: req.peer_addr()
-                        .map(|addr| addr.ip().to_string())
-                        .unwrap_or_else(|| "unknown".to_string()),
-                    timestamp: Utc::now(),
-                };
-
-                match db.insert_entry(entry).await {
-                    Ok(_) => HttpResponse::Ok().json(uuid),
-                    Err(e) => {
-                        // Clean up the stored file if database entry fails
-                        let _ = storage.delete_file(&uuid_str).await;
-                        HttpResponse::InternalServerError()
-                            .body(format!("Database error: {}", e))
-                    }
-                }
-            }
-            Ok(WriteFileResult::Failure(e)) => {
-                // Clean up any partial file if storage write fails
-                let _ = storage.delete_file(&uuid_str).await;
-                HttpResponse::InternalServerError()
-                    .body(format!("Storage error: {}", e))
-            }
-            Err(e) => {
-                // Clean up any partial file if task fails
-                let _ = storage.delete_file(&uuid_str).await;
-                HttpResponse::InternalServerError()
-                    .body(format!("Task error: {}", e))
-            }
+        Err(e) => {
+            // Clean up the stored file if database entry fails
+            let _ = storage.delete_file(&key).await;
+            Err(e.into())
+        }
+    }
+}
+
+// Upload a new version of an existing entry, which becomes the version served by default
+#[put("/entry/{uuid}/contents")]
+#[allow(clippy::too_many_arguments)]
+async fn upload_version(
+    mut payload: Multipart,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    locks: web::Data<RevisionLocks>,
+    thumbnails: web::Data<ThumbnailConfig>,
+    events: web::Data<EventBus>,
+    webhooks: web::Data<WebhookDispatcher>,
+    limits: web::Data<LimitsConfig>,
+    uploads: web::Data<ActiveUploads>,
+) -> Result<HttpResponse, ApiError> {
+    let uuid = path.into_inner();
+    let _guard = locks.try_lock(uuid).ok_or_else(|| ApiError::Conflict {
+        code: "revision_in_progress",
+        message: "Another version of this entry is being uploaded".to_string(),
+    })?;
+
+    let entry = match db.get_entry(uuid).await? {
+        Some(entry) if entry.deleted_at.is_none() => entry,
+        _ => return Err(ApiError::EntryNotFound),
+    };
+    let next = entry.version + 1;
+    audit::note_entry(&req, uuid, &entry.file_name);
+    audit::note_detail(&req, format!("version {}", next));
+    let key = entry::blob_key(uuid, next);
+    let upload = uploads.begin(&key, &storage)?;
+    let (mut field, filename) = next_file_field(&mut payload).await?;
+
+    let stored = upload::store_field(&mut field, &storage, &key, &filename, limits.max_upload_size).await?;
+
+    let version = FileVersion {
+        version: next,
+        file_size: stored.size,
+        sha256: Some(stored.sha256),
+        mime_type: stored.mime_type,
+        timestamp: Utc::now(),
+    };
+
+    match db.add_version(uuid, version.clone()).await {
+        Ok(true) => {
+            upload.finish();
+            let current = Entry {
+                file_size: version.file_size,
+                version: version.version,
+                sha256: version.sha256.clone(),
+                mime_type: version.mime_type.clone(),
+                ..entry
+            };
+            tracing::info!(
+                %uuid,
+                version = current.version,
+                size = current.file_size,
+                mime_type = %current.mime_type,
+                client_ip = client_ip(&req),
+                "new version uploaded"
+            );
+            METRICS.uploads.with_label_values(&["version"]).inc();
+            METRICS.received_bytes.inc_by(current.file_size);
+            METRICS.upload_size.observe(current.file_size as f64);
+            webhooks.notify(WebhookEvent::Uploaded, &current);
+            thumbnail::spawn_generate(Arc::clone(&storage), current, thumbnails.get_ref().clone());
+            events.publish(Event::VersionAdded { uuid, version: version.version });
+            Ok(HttpResponse::Ok().json(version))
         }
+        result => {
+            // Entry vanished or the database failed; don't leave an unreferenced blob behind
+            let _ = storage.delete_file(&key).await;
+            Err(match result {
+                Err(e) => e.into(),
+                _ => ApiError::EntryNotFound,
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NewWebhook {
+    url: String,
+    events: Vec<WebhookEvent>,
+    /// Signing key; a random one is generated when omitted
+    secret: Option<String>,
+}
+
+/// A freshly created webhook, the only response that includes its secret
+#[derive(serde::Serialize)]
+struct CreatedWebhook<'a> {
+    #[serde(flatten)]
+    webhook: &'a Webhook,
+    secret: &'a str,
+}
+
+/// Number of delivery attempts returned by the delivery log endpoint
+const DELIVERY_LOG_PAGE: usize = 50;
+
+// Subscribe a URL to entry events
+#[post("/webhooks")]
+async fn create_webhook(
+    _admin: AdminAccess,
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    body: web::Json<NewWebhook>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    audit::note_detail(&req, body.url.clone());
+
+    match reqwest::Url::parse(&body.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return Err(ApiError::bad_request("invalid_webhook_url", "Webhook URL must be an http or https URL")),
+    }
+    if body.events.is_empty() || body.events.contains(&WebhookEvent::Ping) {
+        return Err(ApiError::bad_request(
+            "invalid_webhook_events",
+            "Subscribe to one or more of entry.uploaded, entry.deleted and entry.expired",
+        ));
+    }
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        url: body.url,
+        secret: body.secret.filter(|s| !s.is_empty()).unwrap_or_else(webhook::generate_secret),
+        events: body.events,
+        created_at: Utc::now(),
+    };
+    db.insert_webhook(webhook.clone()).await?;
+    audit::note_detail(&req, format!("webhook {} {}", webhook.id, webhook.url));
+
+    Ok(HttpResponse::Created().json(CreatedWebhook { webhook: &webhook, secret: &webhook.secret }))
+}
+
+// List webhook subscriptions; secrets are not included
+#[get("/webhooks")]
+async fn list_webhooks(_admin: AdminAccess, db: web::Data<Arc<Box<dyn Database>>>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(db.list_webhooks().await?))
+}
+
+// Unsubscribe a webhook
+#[delete("/webhooks/{id}")]
+async fn delete_webhook(
+    _admin: AdminAccess,
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    audit::note_detail(&req, format!("webhook {}", id));
+    if let Some(webhook) = db.get_webhook(id).await? {
+        audit::note_detail(&req, format!("webhook {} {}", id, webhook.url));
+    }
+    if !db.delete_webhook(id).await? {
+        return Err(ApiError::WebhookNotFound);
+    }
+    Ok(HttpResponse::Ok().body("Webhook deleted"))
+}
+
+// Most recent delivery attempts for a webhook, newest first
+#[get("/webhooks/{id}/deliveries")]
+async fn list_deliveries(
+    _admin: AdminAccess,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    if db.get_webhook(id).await?.is_none() {
+        return Err(ApiError::WebhookNotFound);
+    }
+    Ok(HttpResponse::Ok().json(db.list_deliveries(id, DELIVERY_LOG_PAGE).await?))
+}
+
+// Send a signed `ping` to a webhook right away and report how the receiver answered
+#[post("/webhooks/{id}/test")]
+async fn test_webhook(
+    _admin: AdminAccess,
+    req: HttpRequest,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    webhooks: web::Data<WebhookDispatcher>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    audit::note_detail(&req, format!("webhook {}", id));
+    let webhook = db.get_webhook(id).await?.ok_or(ApiError::WebhookNotFound)?;
+    audit::note_detail(&req, format!("webhook {} {}", id, webhook.url));
+    Ok(HttpResponse::Ok().json(webhooks.test_fire(&webhook).await))
+}
+
+// Prometheus metrics in the text exposition format
+#[get("/metrics")]
+async fn metrics_endpoint(db: web::Data<Arc<Box<dyn Database>>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(METRICS.render(db.get_ref().as_ref().as_ref()).await)
+}
+
+// Liveness: the process is up and serving requests
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Readiness: the database and storage both work, with per-backend detail
+#[get("/readyz")]
+async fn readyz(
+    db: web::Data<Arc<Box<dyn Database>>>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+    storage_config: web::Data<StorageConfig>,
+    shutdown: web::Data<Shutdown>,
+) -> HttpResponse {
+    let readiness = health::readiness(
+        db.get_ref().as_ref().as_ref(),
+        storage.get_ref().as_ref().as_ref(),
+        storage_config.min_free_bytes,
+        shutdown.is_triggered(),
+    )
+    .await;
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(readiness)
     } else {
-        HttpResponse::BadRequest().body("No file in request")
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+// Stream a backup of every entry, version, webhook and stored file as a tar archive
+#[get("/admin/backup")]
+async fn download_backup(
+    _admin: AdminAccess,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    storage: web::Data<Arc<Box<dyn Storage>>>,
+) -> Result<HttpResponse, ApiError> {
+    let snapshot = db.snapshot().await?;
+    let file_name = format!("lfs-backup-{}.tar", Utc::now().format("%Y%m%dT%H%M%SZ"));
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .streaming(backup::stream_backup(snapshot, Arc::clone(storage.get_ref()))))
+}
+
+// Report stored files that failed their latest integrity check, and how the latest scrub went
+#[get("/admin/scrub")]
+async fn scrub_status(
+    _admin: AdminAccess,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    scrubber: web::Data<Scrubber>,
+) -> Result<HttpResponse, ApiError> {
+    let failures = db.list_scrub_failures().await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "running": scrubber.is_running(),
+        "latest": scrubber.latest(),
+        "failures": failures,
+    })))
+}
+
+// Re-read and check every stored file now rather than waiting for the schedule
+#[post("/admin/scrub")]
+async fn start_scrub(_admin: AdminAccess, scrubber: web::Data<Scrubber>) -> Result<HttpResponse, ApiError> {
+    if !scrubber.start(Utc::now()) {
+        return Err(ApiError::Conflict { code: "scrub_running", message: "A scrub is already running".to_string() });
+    }
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "running": true })))
+}
+
+#[derive(Deserialize)]
+struct AuditPage {
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+// Audit log records matching the query, oldest first; page with `after` set to the last `id` seen
+#[get("/audit")]
+async fn list_audit(
+    _admin: AdminAccess,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    filter: web::Query<AuditFilter>,
+    page: web::Query<AuditPage>,
+) -> Result<HttpResponse, ApiError> {
+    if !(1..=1000).contains(&page.limit) {
+        return Err(ApiError::bad_request("invalid_limit", "limit must be between 1 and 1000"));
     }
+    Ok(HttpResponse::Ok().json(db.list_audit(filter.into_inner(), page.limit).await?))
+}
+
+// Stream every audit log record matching the query as newline-delimited JSON
+#[get("/audit/export")]
+async fn export_audit(
+    _admin: AdminAccess,
+    db: web::Data<Arc<Box<dyn Database>>>,
+    filter: web::Query<AuditFilter>,
+) -> HttpResponse {
+    let file_name = format!("lfs-audit-{}.ndjson", Utc::now().format("%Y%m%dT%H%M%SZ"));
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .streaming(audit::export_stream(Arc::clone(db.get_ref()), filter.into_inner()))
+}
+
+// Stream entry list changes to the client as Server-Sent Events
+#[get("/events")]
+async fn event_stream(events: web::Data<EventBus>, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(events::sse_stream(events.subscribe(), shutdown.get_ref().clone()))
 }
 
 #[get("/")]
@@ -243,92 +848,267 @@ async fn index() -> impl Responder {
 }
 
 
+/// CORS policy allowing either any origin or only the configured ones
+fn cors(config: &CorsConfig) -> Cors {
+    let cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .expose_headers(["x-request-id"])
+        .max_age(config.max_age);
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        config.allowed_origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin))
+    }
+}
+
+/// `lfs migrate [--status]`: applies pending migrations, or only reports them with `--status`
+async fn migrate_command(db: &dyn Database, status_only: bool) -> Result<(), DatabaseError> {
+    if !status_only {
+        let applied = db.migrate().await?;
+        if applied.is_empty() {
+            println!("Database schema is up to date");
+        }
+        for version in applied {
+            println!("Applied migration {}", version);
+        }
+        return Ok(());
+    }
+
+    println!("{:<8} {:<24} APPLIED", "VERSION", "NAME");
+    for migration in db.migration_status().await? {
+        let applied = migration
+            .applied_at
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_else(|| "pending".to_string());
+        println!("{:<8} {:<24} {}", migration.version, migration.name, applied);
+    }
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Load environment variables
     dotenv::dotenv().ok();
 
-    // Initialize database based on DATABASE_TYPE
-    let database_type = env::var("DATABASE_TYPE").unwrap_or_else(|_| {
-        eprintln!("Error: DATABASE_TYPE must be set in .env file");
-        eprintln!("Supported values:");
-        eprintln!("  - sqlite (requires DATABASE_PATH)");
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    });
+    if cli.check_config {
+        println!("Configuration OK\n");
+        print!("{}", toml::to_string(&config).expect("configuration is serializable"));
+        return Ok(());
+    }
+    logging::init(EnvFilter::new(&config.logging.level), config.logging.format);
+
+    let db = init_database(&config.database).await.unwrap_or_else(|e| {
+        eprintln!("Failed to initialize database: {}", e);
         std::process::exit(1);
     });
+    let db: Arc<Box<dyn Database>> = Arc::new(Box::new(InstrumentedDatabase::new(db)));
 
-    let db: Arc<Box<dyn Database>> = match database_type.as_str() {
-        "sqlite" => {
-            let database_path = env::var("DATABASE_PATH").unwrap_or_else(|_| {
-                eprintln!("Error: DATABASE_PATH must be set in .env file when using sqlite database");
-                eprintln!("Example .env configuration:");
-                eprintln!("DATABASE_TYPE=sqlite");
-                eprintln!("DATABASE_PATH=/path/to/database.db");
-                std::process::exit(1);
-            });
-
-            Arc::new(Box::new(
-                SqliteDatabase::new(&database_path).await.unwrap_or_else(|e| {
-                    eprintln!("Failed to initialize sqlite database: {}", e);
-                    std::process::exit(1);
-                }),
-            ))
-        }
-        _ => {
-            eprintln!("Error: Unsupported DATABASE_TYPE '{}'", database_type);
-            eprintln!("Supported values:");
-            eprintln!("  - sqlite (requires DATABASE_PATH)");
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::Migrate { status } = command {
+        if let Err(e) = migrate_command(&**db, status).await {
+            eprintln!("Migration failed: {}", e);
             std::process::exit(1);
         }
-    };
-    let db_data = web::Data::new(db);
+        return Ok(());
+    }
 
-    // Initialize storage based on STORAGE_TYPE
-    let storage_type = env::var("STORAGE_TYPE").unwrap_or_else(|_| {
-        eprintln!("Error: STORAGE_TYPE must be set in .env file");
-        eprintln!("Supported values:");
-        eprintln!("  - local (requires STORAGE_PATH)");
+    // Bring the schema up to date before touching any data
+    if let Err(e) = db.migrate().await {
+        eprintln!("Failed to migrate database: {}", e);
+        std::process::exit(1);
+    }
+
+    let storage = init_storage(&config.storage).unwrap_or_else(|e| {
+        eprintln!("Failed to initialize storage: {}", e);
         std::process::exit(1);
     });
+    let storage: Arc<Box<dyn Storage>> = Arc::new(Box::new(InstrumentedStorage::new(storage)));
 
-    let storage: Arc<Box<dyn Storage>> = match storage_type.as_str() {
-        "local" => {
-            let storage_path = env::var("STORAGE_PATH").unwrap_or_else(|_| {
-                eprintln!("Error: STORAGE_PATH must be set in .env file when using local storage");
-                eprintln!("Example .env configuration:");
-                eprintln!("STORAGE_TYPE=local");
-                eprintln!("STORAGE_PATH=/path/to/storage");
-                std::process::exit(1);
-            });
-
-            Arc::new(Box::new(LocalStorage::new(PathBuf::from(storage_path))))
-        }
-        _ => {
-            eprintln!("Error: Unsupported STORAGE_TYPE '{}'", storage_type);
-            eprintln!("Supported values:");
-            eprintln!("  - local (requires STORAGE_PATH)");
+    if let Command::Serve = command {
+        return serve(config, db, storage).await;
+    }
+    let code = admin::run(command, &**db, &storage, &config).await.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        1
+    });
+    let _ = db.close().await;
+    std::process::exit(code);
+}
+
+/// Runs the HTTP server until it is stopped by a signal
+async fn serve(config: Config, db: Arc<Box<dyn Database>>, storage: Arc<Box<dyn Storage>>) -> std::io::Result<()> {
+    let rustls_config = config.tls.enabled().then(|| {
+        let (rustls_config, resolver) = tls::setup(&config.tls).unwrap_or_else(|e| {
+            eprintln!("Error: failed to set up TLS: {}", e);
             std::process::exit(1);
+        });
+        if let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) {
+            tls::spawn_reload_task(resolver, cert.clone(), key.clone());
         }
-    };
-    let storage_data = web::Data::new(storage);
+        rustls_config
+    });
+
+    let thumbnail_config = config.thumbnails.clone();
+
+    let event_bus = EventBus::new();
+    let webhook_dispatcher = WebhookDispatcher::new(db.clone(), Default::default());
+
+    if config.trash.retention_days > 0 {
+        trash::spawn_purge_task(
+            db.clone(),
+            storage.clone(),
+            chrono::Duration::days(config.trash.retention_days.into()),
+            event_bus.clone(),
+            webhook_dispatcher.clone(),
+        );
+    }
+
+    if config.privacy.ip_retention_days > 0 {
+        privacy::spawn_anonymize_task(db.clone(), chrono::Duration::days(config.privacy.ip_retention_days.into()));
+    }
+
+    let scrubber = Scrubber::new(db.clone(), storage.clone(), config.scrub.max_bytes_per_second);
+    if config.scrub.interval_hours > 0 {
+        scrubber.spawn_schedule(chrono::Duration::hours(config.scrub.interval_hours.into()));
+    }
+
+    let shutdown = Shutdown::default();
+    let uploads = web::Data::new(ActiveUploads::new(shutdown.clone()));
+
+    let db_data = web::Data::new(db.clone());
+    let storage_data = web::Data::new(storage.clone());
+    let revision_locks = web::Data::new(RevisionLocks::default());
+    let thumbnail_data = web::Data::new(thumbnail_config);
+    let event_data = web::Data::new(event_bus);
+    let webhook_data = web::Data::new(webhook_dispatcher);
+    let scrub_data = web::Data::new(scrubber);
+    let limits_data = web::Data::new(config.limits.clone());
+    let shutdown_data = web::Data::new(shutdown.clone());
+    let storage_config_data = web::Data::new(config.storage.clone());
+    let stats_config_data = web::Data::new(config.stats.clone());
+    let audit_config_data = web::Data::new(config.audit.clone());
+    let privacy_data = web::Data::new(IpPrivacy::new(config.privacy.ip_mode, config.privacy.ip_hash_key.as_deref()));
+    let trusted_proxies_data = web::Data::new(
+        TrustedProxies::parse(&config.server.trusted_proxies).expect("trusted proxies are checked by Config::validate"),
+    );
+    let admin_networks_data = web::Data::new(
+        AdminNetworks::parse(&config.admin.allowed_networks).expect("admin networks are checked by Config::validate"),
+    );
+    let uploads_data = uploads.clone();
+    let cors_config = config.cors.clone();
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let address = (config.server.bind.as_str(), config.server.port);
+    let scheme = if rustls_config.is_some() { "https" } else { "http" };
+    tracing::info!(address = %format!("{}://{}:{}", scheme, address.0, address.1), "starting server");
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(Cors::default()
-                .allow_any_origin()
-                .allow_any_method()
-                .allow_any_header()
-                .max_age(3600))
+            .wrap(middleware::from_fn(audit::record_requests))
+            .wrap(middleware::from_fn(metrics::track_requests))
+            .wrap(middleware::from_fn(logging::log_requests))
+            .wrap(cors(&cors_config))
+            .wrap(TracingLogger::<RequestSpan>::new())
             .app_data(db_data.clone())
             .app_data(storage_data.clone())
+            .app_data(revision_locks.clone())
+            .app_data(thumbnail_data.clone())
+            .app_data(event_data.clone())
+            .app_data(webhook_data.clone())
+            .app_data(scrub_data.clone())
+            .app_data(limits_data.clone())
+            .app_data(shutdown_data.clone())
+            .app_data(storage_config_data.clone())
+            .app_data(stats_config_data.clone())
+            .app_data(audit_config_data.clone())
+            .app_data(trusted_proxies_data.clone())
+            .app_data(privacy_data.clone())
+            .app_data(admin_networks_data.clone())
+            .app_data(uploads_data.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::bad_request("invalid_request_body", err.to_string()).into()
+            }))
             .service(index)
             .service(list_entries)
             .service(get_entry)
             .service(delete_entry)
+            .service(list_trash)
+            .service(restore_entry)
+            .service(purge_entry)
             .service(download_file)
+            .service(view_file)
             .service(upload_file)
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+            .service(upload_version)
+            .service(list_versions)
+            .service(prune_versions)
+            .service(entry_stats)
+            .service(server_stats)
+            .service(get_thumbnail)
+            .service(event_stream)
+            .service(healthz)
+            .service(readyz)
+            .service(metrics_endpoint)
+            .service(download_backup)
+            .service(scrub_status)
+            .service(start_scrub)
+            .service(list_audit)
+            .service(export_audit)
+            .service(create_webhook)
+            .service(list_webhooks)
+            .service(delete_webhook)
+            .service(list_deliveries)
+            .service(test_webhook)
+    });
+    if config.server.workers > 0 {
+        server = server.workers(config.server.workers);
+    }
+    // Stop signals are handled by `shutdown` so uploads are refused before the listeners close
+    let shutdown_timeout = config.server.shutdown_timeout;
+    server = server.disable_signals().shutdown_timeout(shutdown_timeout);
+    let server = match rustls_config {
+        Some(rustls_config) => server.bind_rustls_0_23(address, rustls_config)?,
+        None => server.bind(address)?,
+    }
+    .run();
+    let mut handles = vec![server.handle()];
+
+    let redirect = match config.tls.redirect_http_port {
+        Some(redirect_port) => {
+            let https_port = config.server.port;
+            tracing::info!(port = redirect_port, "redirecting plain HTTP to HTTPS");
+            let redirect = HttpServer::new(move || {
+                App::new().default_service(web::to(move |req: HttpRequest| tls::redirect_to_https(req, https_port)))
+            })
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(shutdown_timeout)
+            .bind((address.0, redirect_port))?
+            .run();
+            handles.push(redirect.handle());
+            Some(redirect)
+        }
+        None => None,
+    };
+    shutdown::spawn_signal_handler(shutdown, handles);
+
+    match redirect {
+        Some(redirect) => futures_util::future::try_join(server, redirect).await.map(|_| ())?,
+        None => server.await?,
+    }
+
+    // Anything still registered was cut off when the shutdown timeout expired
+    let removed = uploads.remove_unfinished(&**storage).await;
+    if removed > 0 {
+        tracing::warn!(count = removed, "removed partial files of aborted uploads");
+    }
+    if let Err(e) = db.close().await {
+        tracing::error!(error = %e, "failed to close database cleanly");
+    }
+    tracing::info!("shutdown complete");
+    Ok(())
 }
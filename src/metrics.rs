@@ -0,0 +1,492 @@
+use crate::audit::{AuditFilter, AuditRecord};
+use crate::database::{Database, DatabaseError, MigrationStatus, Snapshot, Usage};
+use crate::entry::{Entry, FileVersion};
+use crate::import::ImportRecord;
+use crate::scrub::ScrubResult;
+use crate::stats::{DailyVolume, Download, DownloadStats};
+use crate::storage::{ByteStream, DeleteFileResult, FileInfo, Storage, StorageError, WriteFileResult};
+use crate::webhook::{Webhook, WebhookDelivery};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Process-wide metrics, exposed at `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+    /// New entries and new versions, labelled by `kind`
+    pub uploads: IntCounterVec,
+    pub downloads: IntCounter,
+    /// Entries moved to the trash or purged, labelled by `kind`
+    pub deletes: IntCounterVec,
+    pub received_bytes: IntCounter,
+    pub sent_bytes: IntCounter,
+    pub upload_size: Histogram,
+    storage_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+    database_duration: HistogramVec,
+    database_errors: IntCounterVec,
+    stored_bytes: IntGauge,
+    entries: IntGaugeVec,
+    /// Stored versions re-read by the scrubber, labelled by `status`
+    pub scrub_verified: IntCounterVec,
+    pub scrub_read_bytes: IntCounter,
+    pub scrub_last_completed: IntGauge,
+    scrub_failed_versions: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("lfs".to_string()), None).expect("valid registry prefix");
+        let operation_buckets = prometheus::exponential_buckets(0.0005, 4.0, 9).expect("valid buckets");
+
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled, by route and status"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time until the response head was ready")
+                    .buckets(operation_buckets.clone()),
+                &["method", "route"],
+            )
+            .unwrap(),
+            uploads: IntCounterVec::new(Opts::new("uploads_total", "Files stored successfully"), &["kind"]).unwrap(),
+            downloads: IntCounter::new("downloads_total", "File contents served").unwrap(),
+            deletes: IntCounterVec::new(Opts::new("deletes_total", "Entries trashed or purged"), &["kind"]).unwrap(),
+            received_bytes: IntCounter::new("received_bytes_total", "Bytes of file contents uploaded").unwrap(),
+            sent_bytes: IntCounter::new("sent_bytes_total", "Bytes of file contents served").unwrap(),
+            upload_size: Histogram::with_opts(
+                HistogramOpts::new("upload_size_bytes", "Size of uploaded files")
+                    .buckets(prometheus::exponential_buckets(1024.0, 8.0, 8).expect("valid buckets")),
+            )
+            .unwrap(),
+            storage_duration: HistogramVec::new(
+                HistogramOpts::new("storage_operation_duration_seconds", "Time spent in storage operations")
+                    .buckets(operation_buckets.clone()),
+                &["operation"],
+            )
+            .unwrap(),
+            storage_errors: IntCounterVec::new(
+                Opts::new("storage_errors_total", "Failed storage operations"),
+                &["operation"],
+            )
+            .unwrap(),
+            database_duration: HistogramVec::new(
+                HistogramOpts::new("database_operation_duration_seconds", "Time spent in database operations")
+                    .buckets(operation_buckets),
+                &["operation"],
+            )
+            .unwrap(),
+            database_errors: IntCounterVec::new(
+                Opts::new("database_errors_total", "Failed database operations"),
+                &["operation"],
+            )
+            .unwrap(),
+            stored_bytes: IntGauge::new("stored_bytes", "Combined size of every stored version").unwrap(),
+            entries: IntGaugeVec::new(Opts::new("entries", "Number of entries, by state"), &["state"]).unwrap(),
+            scrub_verified: IntCounterVec::new(
+                Opts::new("scrub_verified_total", "Stored versions re-read by the scrubber, by outcome"),
+                &["status"],
+            )
+            .unwrap(),
+            scrub_read_bytes: IntCounter::new("scrub_read_bytes_total", "Bytes re-read by the scrubber").unwrap(),
+            scrub_last_completed: IntGauge::new(
+                "scrub_last_completed_timestamp_seconds",
+                "Unix time the last scrub pass finished",
+            )
+            .unwrap(),
+            scrub_failed_versions: IntGauge::new(
+                "scrub_failed_versions",
+                "Stored versions whose latest verification failed",
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_duration.clone()),
+            Box::new(metrics.uploads.clone()),
+            Box::new(metrics.downloads.clone()),
+            Box::new(metrics.deletes.clone()),
+            Box::new(metrics.received_bytes.clone()),
+            Box::new(metrics.sent_bytes.clone()),
+            Box::new(metrics.upload_size.clone()),
+            Box::new(metrics.storage_duration.clone()),
+            Box::new(metrics.storage_errors.clone()),
+            Box::new(metrics.database_duration.clone()),
+            Box::new(metrics.database_errors.clone()),
+            Box::new(metrics.stored_bytes.clone()),
+            Box::new(metrics.entries.clone()),
+            Box::new(metrics.scrub_verified.clone()),
+            Box::new(metrics.scrub_read_bytes.clone()),
+            Box::new(metrics.scrub_last_completed.clone()),
+            Box::new(metrics.scrub_failed_versions.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// Refreshes the gauges derived from the database and renders everything in the text exposition format
+    pub async fn render(&self, db: &dyn Database) -> String {
+        match db.usage().await {
+            Ok(usage) => {
+                self.stored_bytes.set(usage.stored_bytes as i64);
+                self.entries.with_label_values(&["live"]).set(usage.entries as i64);
+                self.entries.with_label_values(&["trashed"]).set(usage.trashed_entries as i64);
+            }
+            // Serve the stale values; the error itself shows up in database_errors_total
+            Err(e) => tracing::warn!(error = %e, "failed to refresh usage metrics"),
+        }
+        match db.list_scrub_failures().await {
+            Ok(failures) => self.scrub_failed_versions.set(failures.len() as i64),
+            Err(e) => tracing::warn!(error = %e, "failed to refresh scrub metrics"),
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding does not fail");
+        String::from_utf8(buffer).expect("text encoding is UTF-8")
+    }
+}
+
+/// Middleware recording the count and latency of every request by its route pattern
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let method = req.method().to_string();
+    let timer = std::time::Instant::now();
+    let response = next.call(req).await?;
+
+    // Use the pattern rather than the path so UUIDs don't explode label cardinality
+    let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    METRICS
+        .http_duration
+        .with_label_values(&[method.as_str(), route.as_str()])
+        .observe(timer.elapsed().as_secs_f64());
+    METRICS
+        .http_requests
+        .with_label_values(&[method.as_str(), route.as_str(), response.status().as_str()])
+        .inc();
+
+    Ok(response)
+}
+
+/// Times a database call in its own span, and counts and logs it as an error if it fails
+async fn observe_database<T>(
+    operation: &str,
+    call: impl Future<Output = Result<T, DatabaseError>>,
+) -> Result<T, DatabaseError> {
+    async {
+        let timer = METRICS.database_duration.with_label_values(&[operation]).start_timer();
+        let result = call.await;
+        timer.observe_duration();
+        if let Err(e) = &result {
+            METRICS.database_errors.with_label_values(&[operation]).inc();
+            tracing::warn!(error = %e, "database operation failed");
+        }
+        result
+    }
+    .instrument(tracing::debug_span!("database", operation))
+    .await
+}
+
+/// Times a storage call in its own span, and counts and logs it as an error when `error` finds one
+async fn observe_storage<T>(
+    operation: &str,
+    call: impl Future<Output = T>,
+    error: impl Fn(&T) -> Option<String>,
+) -> T {
+    async {
+        let timer = METRICS.storage_duration.with_label_values(&[operation]).start_timer();
+        let result = call.await;
+        timer.observe_duration();
+        if let Some(e) = error(&result) {
+            METRICS.storage_errors.with_label_values(&[operation]).inc();
+            tracing::warn!(error = %e, "storage operation failed");
+        }
+        result
+    }
+    .instrument(tracing::debug_span!("storage", operation))
+    .await
+}
+
+/// `Database` wrapper that records latency and errors for every call of the backend it wraps
+pub struct InstrumentedDatabase {
+    inner: Arc<Box<dyn Database>>,
+}
+
+impl InstrumentedDatabase {
+    pub fn new(inner: Arc<Box<dyn Database>>) -> Self {
+        InstrumentedDatabase { inner }
+    }
+}
+
+#[async_trait]
+impl Database for InstrumentedDatabase {
+    async fn ping(&self) -> Result<(), DatabaseError> {
+        observe_database("ping", self.inner.ping()).await
+    }
+
+    async fn list_uuids(&self) -> Result<Vec<Uuid>, DatabaseError> {
+        observe_database("list_uuids", self.inner.list_uuids()).await
+    }
+
+    async fn get_entry(&self, uuid: Uuid) -> Result<Option<Entry>, DatabaseError> {
+        observe_database("get_entry", self.inner.get_entry(uuid)).await
+    }
+
+    async fn insert_entry(&self, input: Entry) -> Result<Uuid, DatabaseError> {
+        observe_database("insert_entry", self.inner.insert_entry(input)).await
+    }
+
+    async fn delete_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError> {
+        observe_database("delete_entry", self.inner.delete_entry(uuid)).await
+    }
+
+    async fn add_version(&self, uuid: Uuid, version: FileVersion) -> Result<bool, DatabaseError> {
+        observe_database("add_version", self.inner.add_version(uuid, version)).await
+    }
+
+    async fn list_versions(&self, uuid: Uuid) -> Result<Vec<FileVersion>, DatabaseError> {
+        observe_database("list_versions", self.inner.list_versions(uuid)).await
+    }
+
+    async fn delete_version(&self, uuid: Uuid, version: u32) -> Result<bool, DatabaseError> {
+        observe_database("delete_version", self.inner.delete_version(uuid, version)).await
+    }
+
+    async fn trash_entry(&self, uuid: Uuid, deleted_at: DateTime<Utc>) -> Result<bool, DatabaseError> {
+        observe_database("trash_entry", self.inner.trash_entry(uuid, deleted_at)).await
+    }
+
+    async fn restore_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError> {
+        observe_database("restore_entry", self.inner.restore_entry(uuid)).await
+    }
+
+    async fn list_trash(&self) -> Result<Vec<Entry>, DatabaseError> {
+        observe_database("list_trash", self.inner.list_trash()).await
+    }
+
+    async fn usage(&self) -> Result<Usage, DatabaseError> {
+        observe_database("usage", self.inner.usage()).await
+    }
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<(), DatabaseError> {
+        observe_database("insert_webhook", self.inner.insert_webhook(webhook)).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, DatabaseError> {
+        observe_database("list_webhooks", self.inner.list_webhooks()).await
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, DatabaseError> {
+        observe_database("get_webhook", self.inner.get_webhook(id)).await
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, DatabaseError> {
+        observe_database("delete_webhook", self.inner.delete_webhook(id)).await
+    }
+
+    async fn record_delivery(&self, delivery: WebhookDelivery, keep: usize) -> Result<(), DatabaseError> {
+        observe_database("record_delivery", self.inner.record_delivery(delivery, keep)).await
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<WebhookDelivery>, DatabaseError> {
+        observe_database("list_deliveries", self.inner.list_deliveries(webhook_id, limit)).await
+    }
+
+    async fn insert_imported_entry(&self, input: Entry, source: ImportRecord) -> Result<Uuid, DatabaseError> {
+        observe_database("insert_imported_entry", self.inner.insert_imported_entry(input, source)).await
+    }
+
+    async fn get_import(&self, source_root: &str, relative_path: &str) -> Result<Option<ImportRecord>, DatabaseError> {
+        observe_database("get_import", self.inner.get_import(source_root, relative_path)).await
+    }
+
+    async fn record_download(&self, download: Download) -> Result<(), DatabaseError> {
+        observe_database("record_download", self.inner.record_download(download)).await
+    }
+
+    async fn download_stats(&self, uuid: Uuid) -> Result<DownloadStats, DatabaseError> {
+        observe_database("download_stats", self.inner.download_stats(uuid)).await
+    }
+
+    async fn top_downloads(&self, limit: usize) -> Result<Vec<(Entry, DownloadStats)>, DatabaseError> {
+        observe_database("top_downloads", self.inner.top_downloads(limit)).await
+    }
+
+    async fn daily_volume(&self, since: DateTime<Utc>) -> Result<Vec<DailyVolume>, DatabaseError> {
+        observe_database("daily_volume", self.inner.daily_volume(since)).await
+    }
+
+    async fn anonymize_client_ips(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        observe_database("anonymize_client_ips", self.inner.anonymize_client_ips(before)).await
+    }
+
+    async fn append_audit(&self, record: AuditRecord) -> Result<u64, DatabaseError> {
+        observe_database("append_audit", self.inner.append_audit(record)).await
+    }
+
+    async fn list_audit(&self, filter: AuditFilter, limit: usize) -> Result<Vec<AuditRecord>, DatabaseError> {
+        observe_database("list_audit", self.inner.list_audit(filter, limit)).await
+    }
+
+    async fn list_scrub_due(&self, cutoff: DateTime<Utc>) -> Result<Vec<(Uuid, FileVersion)>, DatabaseError> {
+        observe_database("list_scrub_due", self.inner.list_scrub_due(cutoff)).await
+    }
+
+    async fn record_scrub(&self, result: ScrubResult) -> Result<(), DatabaseError> {
+        observe_database("record_scrub", self.inner.record_scrub(result)).await
+    }
+
+    async fn list_scrub_failures(&self) -> Result<Vec<ScrubResult>, DatabaseError> {
+        observe_database("list_scrub_failures", self.inner.list_scrub_failures()).await
+    }
+
+    async fn snapshot(&self) -> Result<Snapshot, DatabaseError> {
+        observe_database("snapshot", self.inner.snapshot()).await
+    }
+
+    async fn load_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        observe_database("load_snapshot", self.inner.load_snapshot(snapshot)).await
+    }
+
+    async fn migrate(&self) -> Result<Vec<u32>, DatabaseError> {
+        observe_database("migrate", self.inner.migrate()).await
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+        observe_database("migration_status", self.inner.migration_status()).await
+    }
+
+    async fn close(&self) -> Result<(), DatabaseError> {
+        observe_database("close", self.inner.close()).await
+    }
+}
+
+/// `Storage` wrapper that records latency and failures for every call of the backend it wraps
+pub struct InstrumentedStorage {
+    inner: Arc<Box<dyn Storage>>,
+}
+
+impl InstrumentedStorage {
+    pub fn new(inner: Arc<Box<dyn Storage>>) -> Self {
+        InstrumentedStorage { inner }
+    }
+}
+
+#[async_trait]
+impl Storage for InstrumentedStorage {
+    async fn write_file(
+        &self,
+        uuid: &str,
+        data: Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>,
+    ) -> WriteFileResult {
+        observe_storage("write_file", self.inner.write_file(uuid, data), |result| match result {
+            WriteFileResult::Failure(e) => Some(e.to_string()),
+            WriteFileResult::Success => None,
+        })
+        .await
+    }
+
+    fn get_file_path(&self, uuid: &str) -> PathBuf {
+        self.inner.get_file_path(uuid)
+    }
+
+    async fn read_file(&self, uuid: &str) -> Result<ByteStream, StorageError> {
+        observe_storage("read_file", self.inner.read_file(uuid), |result| {
+            result.as_ref().err().map(ToString::to_string)
+        })
+        .await
+    }
+
+    async fn file_info(&self, uuid: &str) -> Result<Option<FileInfo>, StorageError> {
+        observe_storage("file_info", self.inner.file_info(uuid), |result| {
+            result.as_ref().err().map(ToString::to_string)
+        })
+        .await
+    }
+
+    async fn delete_file(&self, uuid: &str) -> DeleteFileResult {
+        observe_storage("delete_file", self.inner.delete_file(uuid), |result| match result {
+            DeleteFileResult::Failure(e) => Some(e.to_string()),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, StorageError> {
+        observe_storage("list_files", self.inner.list_files(), |result| {
+            result.as_ref().err().map(ToString::to_string)
+        })
+        .await
+    }
+
+    async fn available_space(&self) -> Result<u64, StorageError> {
+        observe_storage("available_space", self.inner.available_space(), |result| {
+            result.as_ref().err().map(ToString::to_string)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+
+    #[tokio::test]
+    async fn test_instrumented_backends_report_metrics() {
+        let dir = std::env::temp_dir().join(format!("lfs-metrics-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sqlite = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+        let db = InstrumentedDatabase::new(Arc::new(Box::new(sqlite)));
+        let storage = InstrumentedStorage::new(Arc::new(Box::new(LocalStorage::new(dir.join("storage")))));
+
+        let failures_before = METRICS.storage_errors.with_label_values(&["delete_file"]).get();
+        db.migrate().await.unwrap();
+        assert!(matches!(storage.delete_file("missing").await, DeleteFileResult::NotFound));
+        assert_eq!(
+            METRICS.storage_errors.with_label_values(&["delete_file"]).get(),
+            failures_before,
+            "a missing file is not a storage error"
+        );
+
+        let text = METRICS.render(&db).await;
+        assert!(text.contains("lfs_database_operation_duration_seconds_count{operation=\"migrate\"}"));
+        assert!(text.contains("lfs_database_operation_duration_seconds_count{operation=\"usage\"}"));
+        assert!(text.contains("lfs_storage_operation_duration_seconds_count{operation=\"delete_file\"}"));
+        assert!(text.contains("lfs_entries{state=\"live\"} 0"));
+        assert!(text.contains("lfs_stored_bytes 0"));
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use crate::database::Database;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the background task looks for addresses past their retention
+const ANONYMIZE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Network prefixes kept when truncating addresses
+const IPV4_PREFIX: u8 = 24;
+const IPV6_PREFIX: u8 = 48;
+
+/// What is stored of a client's address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpMode {
+    /// The address as it is
+    #[default]
+    Full,
+    /// Only the network: a /24 for IPv4 and a /48 for IPv6
+    Truncate,
+    /// A keyed hash, which still tells repeat visitors apart but cannot be reversed without the key
+    Hash,
+    /// Nothing
+    None,
+}
+
+/// Turns client addresses into what `IpMode` allows to be stored
+#[derive(Clone)]
+pub struct IpPrivacy {
+    mode: IpMode,
+    key: Vec<u8>,
+}
+
+impl IpPrivacy {
+    /// `key` is only used, and required, for `IpMode::Hash`
+    pub fn new(mode: IpMode, key: Option<&str>) -> Self {
+        IpPrivacy { mode, key: key.unwrap_or_default().as_bytes().to_vec() }
+    }
+
+    pub fn apply(&self, ip: IpAddr) -> Option<String> {
+        match self.mode {
+            IpMode::Full => Some(ip.to_string()),
+            IpMode::Truncate => {
+                let prefix = if ip.is_ipv4() { IPV4_PREFIX } else { IPV6_PREFIX };
+                Some(IpNet::new(ip, prefix).expect("prefix fits the address family").trunc().to_string())
+            }
+            IpMode::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+                mac.update(ip.to_string().as_bytes());
+                Some(hex::encode(mac.finalize().into_bytes()))
+            }
+            IpMode::None => None,
+        }
+    }
+}
+
+impl Default for IpPrivacy {
+    fn default() -> Self {
+        IpPrivacy::new(IpMode::Full, None)
+    }
+}
+
+/// Removes client addresses stored more than `retention` ago, returning how many rows changed
+pub async fn anonymize_expired(db: &dyn Database, retention: chrono::Duration) -> Result<u64, crate::database::DatabaseError> {
+    let changed = db.anonymize_client_ips(Utc::now() - retention).await?;
+    if changed > 0 {
+        tracing::info!(rows = changed, "client addresses past their retention removed");
+    }
+    Ok(changed)
+}
+
+/// Periodically removes expired client addresses for the lifetime of the server
+pub fn spawn_anonymize_task(db: Arc<Box<dyn Database>>, retention: chrono::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ANONYMIZE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = anonymize_expired(&**db, retention).await {
+                tracing::error!(error = %e, "failed to remove expired client addresses");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_modes() {
+        let v4: IpAddr = "198.51.100.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1:2::7".parse().unwrap();
+
+        assert_eq!(IpPrivacy::new(IpMode::Full, None).apply(v4).as_deref(), Some("198.51.100.77"));
+        let truncate = IpPrivacy::new(IpMode::Truncate, None);
+        assert_eq!(truncate.apply(v4).as_deref(), Some("198.51.100.0/24"));
+        assert_eq!(truncate.apply(v6).as_deref(), Some("2001:db8:1::/48"));
+        assert_eq!(IpPrivacy::new(IpMode::None, None).apply(v4), None);
+
+        let hash = |key| IpPrivacy::new(IpMode::Hash, Some(key)).apply(v4).unwrap();
+        assert_eq!(hash("first key, long enough"), hash("first key, long enough"), "hashes must be stable");
+        assert_ne!(hash("first key, long enough"), hash("other key, long enough"));
+        assert_eq!(hash("first key, long enough").len(), 64);
+        assert!(!hash("first key, long enough").contains("198.51"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::privacy::IpPrivacy;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, HttpRequest};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Reverse proxies whose forwarding headers are believed
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parses addresses such as `10.0.0.7` and networks such as `10.0.0.0/8` or `fd00::/8`
+    pub fn parse(items: &[String]) -> Result<Self, String> {
+        parse_networks(items).map(TrustedProxies)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        networks_contain(&self.0, ip)
+    }
+}
+
+/// Parses a configured list of addresses and networks, naming the first entry that is neither
+pub fn parse_networks(items: &[String]) -> Result<Vec<IpNet>, String> {
+    items
+        .iter()
+        .map(|item| {
+            item.parse::<IpNet>()
+                .or_else(|_| item.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("'{}' is not an IP address or network", item))
+        })
+        .collect()
+}
+
+/// Whether `ip` falls in any of `networks`, treating IPv4-mapped IPv6 addresses as IPv4
+pub fn networks_contain(networks: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+/// Address of the client that sent a request, seen through any trusted proxies in front of it.
+///
+/// Forwarding headers are only read when `peer` is a trusted proxy; anyone else could write
+/// whatever they like into them. `Forwarded` takes precedence over `X-Forwarded-For`. The chain
+/// is walked from the nearest hop outwards and the first address not belonging to a trusted proxy
+/// is the client, so addresses a client prepends itself are never reached. A hop that cannot be
+/// parsed ends the walk at the last proxy that could.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !trusted.contains(peer) {
+        if headers.contains_key(header::FORWARDED) || headers.contains_key(header::X_FORWARDED_FOR) {
+            tracing::debug!(%peer, "ignoring forwarding headers from an untrusted peer");
+        }
+        return peer;
+    }
+
+    let hops = if headers.contains_key(header::FORWARDED) {
+        forwarded_hops(headers)
+    } else {
+        header_list(headers, &header::X_FORWARDED_FOR)
+    };
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) if trusted.contains(ip) => client = ip,
+            Some(ip) => return ip,
+            None => break,
+        }
+    }
+    client
+}
+
+/// Address of the client that sent `req`; behind a trusted proxy this is the address it forwarded
+/// rather than the proxy's own
+pub fn client_addr(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    Some(match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(trusted) => resolve(peer, req.headers(), trusted),
+        None => peer,
+    })
+}
+
+/// Address of the client as it may be recorded on entries, downloads and audit records and in logs.
+///
+/// This is `client_addr`, truncated, hashed or left out as `privacy.ip_mode` says.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let ip = client_addr(req)?;
+    match req.app_data::<web::Data<IpPrivacy>>() {
+        Some(privacy) => privacy.apply(ip),
+        None => Some(ip.to_string()),
+    }
+}
+
+/// Comma-separated values of every instance of `name`, in order
+fn header_list(headers: &HeaderMap, name: &header::HeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .map(|item| item.trim().to_string())
+        .collect()
+}
+
+/// The `for` parameter of every element of the `Forwarded` headers, in order (RFC 7239)
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    header_list(headers, &header::FORWARDED)
+        .iter()
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Parses `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `[2001:db8::1]:4711`; obfuscated
+/// identifiers and `unknown` are not addresses
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    let host = match hop.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        None => hop.rsplit_once(':')?.0,
+    };
+    host.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_address_behind_proxies() {
+        let trusted = TrustedProxies::parse(&["10.0.0.0/8".to_string(), "2001:db8::1".to_string()]).unwrap();
+        let xff = |value| headers(&[(header::X_FORWARDED_FOR, value)]);
+
+        // Headers from anyone else are ignored
+        assert_eq!(resolve(ip("203.0.113.9"), &xff("198.51.100.1"), &trusted), ip("203.0.113.9"));
+        assert_eq!(resolve(ip("10.0.0.2"), &HeaderMap::new(), &trusted), ip("10.0.0.2"));
+
+        // The nearest untrusted hop is the client, whatever the client claimed before it
+        assert_eq!(resolve(ip("10.0.0.2"), &xff("1.1.1.1, 198.51.100.1, 10.0.0.5"), &trusted), ip("198.51.100.1"));
+        let split = headers(&[(header::X_FORWARDED_FOR, "1.1.1.1"), (header::X_FORWARDED_FOR, "198.51.100.1:4711")]);
+        assert_eq!(resolve(ip("::ffff:10.0.0.2"), &split, &trusted), ip("198.51.100.1"));
+        assert_eq!(resolve(ip("10.0.0.2"), &xff("10.0.0.9, 10.0.0.5"), &trusted), ip("10.0.0.9"));
+        assert_eq!(resolve(ip("10.0.0.2"), &xff("198.51.100.1, garbage"), &trusted), ip("10.0.0.2"));
+
+        // Forwarded wins over X-Forwarded-For
+        let forwarded = headers(&[
+            (header::FORWARDED, r#"for=198.51.100.1;proto=https, for="[2001:db8::1]:4711""#),
+            (header::X_FORWARDED_FOR, "192.0.2.99"),
+        ]);
+        assert_eq!(resolve(ip("10.0.0.2"), &forwarded, &trusted), ip("198.51.100.1"));
+        let hidden = headers(&[(header::FORWARDED, "for=_hidden, for=10.0.0.5")]);
+        assert_eq!(resolve(ip("10.0.0.2"), &hidden, &trusted), ip("10.0.0.5"));
+
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+    }
+}
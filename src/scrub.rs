@@ -0,0 +1,314 @@
+use crate::database::{Database, DatabaseError};
+use crate::entry::{self, FileVersion};
+use crate::metrics::METRICS;
+use crate::storage::{Storage, StorageError};
+use crate::upload;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How often the background task looks for versions that are due for verification
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Minimum time between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Outcome of re-reading one stored version
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrubStatus {
+    Ok,
+    /// Storage has no file for the version
+    Missing,
+    /// The file exists but could not be read to the end
+    Unreadable,
+    SizeMismatch,
+    ChecksumMismatch,
+}
+
+impl ScrubStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScrubStatus::Ok => "ok",
+            ScrubStatus::Missing => "missing",
+            ScrubStatus::Unreadable => "unreadable",
+            ScrubStatus::SizeMismatch => "size_mismatch",
+            ScrubStatus::ChecksumMismatch => "checksum_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for ScrubStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ScrubStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ok" => Ok(ScrubStatus::Ok),
+            "missing" => Ok(ScrubStatus::Missing),
+            "unreadable" => Ok(ScrubStatus::Unreadable),
+            "size_mismatch" => Ok(ScrubStatus::SizeMismatch),
+            "checksum_mismatch" => Ok(ScrubStatus::ChecksumMismatch),
+            other => Err(format!("unknown scrub status '{}'", other)),
+        }
+    }
+}
+
+/// The latest verification of one version of an entry
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScrubResult {
+    pub uuid: Uuid,
+    pub version: u32,
+    pub status: ScrubStatus,
+    /// What was found instead of the recorded size or checksum; `None` when the file is fine
+    pub detail: Option<String>,
+    pub verified_at: DateTime<Utc>,
+}
+
+/// Counters reported while a scrub pass runs and returned when it finishes
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ScrubProgress {
+    pub started_at: DateTime<Utc>,
+    /// Unset until the pass is over
+    pub finished_at: Option<DateTime<Utc>>,
+    pub total_versions: u64,
+    pub verified: u64,
+    pub failed: u64,
+    /// Bytes read back from storage
+    pub read_bytes: u64,
+}
+
+/// Holds reads down to `limit` bytes per second on average; 0 means no limit
+struct Throttle {
+    limit: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(limit: u64) -> Self {
+        Throttle { limit, started: Instant::now(), bytes: 0 }
+    }
+
+    async fn consume(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        if self.limit == 0 {
+            return;
+        }
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.limit as f64);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Size and hex SHA-256 of the file stored under `key`, read no faster than `throttle` allows
+async fn measure(storage: &dyn Storage, key: &str, throttle: &mut Throttle) -> Result<(u64, String), StorageError> {
+    let data = storage.read_file(key).await?;
+    let mut throttled = Box::pin(stream::unfold((data, throttle), |(mut data, throttle)| async move {
+        let chunk = data.next().await?;
+        if let Ok(chunk) = &chunk {
+            throttle.consume(chunk.len()).await;
+        }
+        Some((chunk, (data, throttle)))
+    }));
+    let stored = upload::describe_stream(&mut throttled, "").await?;
+    Ok((stored.size, stored.sha256))
+}
+
+/// Re-reads the file of one version and compares it with the size and checksum recorded for it.
+///
+/// Versions stored before checksums were recorded are only checked for their size.
+async fn verify_version(storage: &dyn Storage, uuid: Uuid, version: &FileVersion, throttle: &mut Throttle) -> ScrubResult {
+    let (status, detail) = match measure(storage, &entry::blob_key(uuid, version.version), throttle).await {
+        Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => (ScrubStatus::Missing, None),
+        Err(e) => (ScrubStatus::Unreadable, Some(e.to_string())),
+        Ok((size, _)) if size != version.file_size => (
+            ScrubStatus::SizeMismatch,
+            Some(format!("expected {} bytes, found {}", version.file_size, size)),
+        ),
+        Ok((_, sha256)) if version.sha256.as_ref().is_some_and(|recorded| *recorded != sha256) => {
+            (ScrubStatus::ChecksumMismatch, Some(format!("found SHA-256 {}", sha256)))
+        }
+        Ok(_) => (ScrubStatus::Ok, None),
+    };
+    ScrubResult { uuid, version: version.version, status, detail, verified_at: Utc::now() }
+}
+
+/// Verifies every stored version last verified before `cutoff`, never-verified ones first, and
+/// records the outcome of each in the database.
+///
+/// Reads are held to `bytes_per_second` (0 for no limit) so a pass does not starve downloads.
+/// `on_progress` is called every couple of seconds and once at the end.
+pub async fn scrub(
+    db: &dyn Database,
+    storage: &dyn Storage,
+    cutoff: DateTime<Utc>,
+    bytes_per_second: u64,
+    mut on_progress: impl FnMut(&ScrubProgress),
+) -> Result<ScrubProgress, DatabaseError> {
+    let due = db.list_scrub_due(cutoff).await?;
+    let mut progress = ScrubProgress { started_at: Utc::now(), total_versions: due.len() as u64, ..ScrubProgress::default() };
+    let mut throttle = Throttle::new(bytes_per_second);
+    let mut reported = Instant::now();
+
+    for (uuid, version) in &due {
+        let read_before = throttle.bytes;
+        let result = verify_version(storage, *uuid, version, &mut throttle).await;
+        let read = throttle.bytes - read_before;
+
+        if result.status != ScrubStatus::Ok {
+            // The version may have been deleted together with its file while this pass ran
+            if !db.list_versions(*uuid).await?.iter().any(|v| v.version == version.version) {
+                continue;
+            }
+            tracing::error!(
+                %uuid,
+                version = version.version,
+                status = %result.status,
+                detail = result.detail.as_deref().unwrap_or(""),
+                "stored file failed verification"
+            );
+            progress.failed += 1;
+        }
+        METRICS.scrub_verified.with_label_values(&[result.status.as_str()]).inc();
+        METRICS.scrub_read_bytes.inc_by(read);
+        db.record_scrub(result).await?;
+
+        progress.verified += 1;
+        progress.read_bytes += read;
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            on_progress(&progress);
+            reported = Instant::now();
+        }
+    }
+
+    progress.finished_at = Some(Utc::now());
+    METRICS.scrub_last_completed.set(Utc::now().timestamp());
+    on_progress(&progress);
+    Ok(progress)
+}
+
+/// Runs scrub passes in the background, one at a time, and remembers how the latest one went
+#[derive(Clone)]
+pub struct Scrubber {
+    db: Arc<Box<dyn Database>>,
+    storage: Arc<Box<dyn Storage>>,
+    bytes_per_second: u64,
+    running: Arc<AtomicBool>,
+    latest: Arc<Mutex<Option<ScrubProgress>>>,
+}
+
+impl Scrubber {
+    pub fn new(db: Arc<Box<dyn Database>>, storage: Arc<Box<dyn Storage>>, bytes_per_second: u64) -> Self {
+        Scrubber {
+            db,
+            storage,
+            bytes_per_second,
+            running: Arc::new(AtomicBool::new(false)),
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Progress of the running pass, or the totals of the last finished one
+    pub fn latest(&self) -> Option<ScrubProgress> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Starts a pass over versions last verified before `cutoff` in the background, unless one is
+    /// already running; returns whether it started
+    pub fn start(&self, cutoff: DateTime<Utc>) -> bool {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let scrubber = self.clone();
+        tokio::spawn(async move {
+            let latest = Arc::clone(&scrubber.latest);
+            let report = |progress: &ScrubProgress| *latest.lock().unwrap() = Some(progress.clone());
+            match scrub(&**scrubber.db, &**scrubber.storage, cutoff, scrubber.bytes_per_second, report).await {
+                Ok(progress) if progress.failed > 0 => {
+                    tracing::warn!(verified = progress.verified, failed = progress.failed, "scrub found damaged files")
+                }
+                Ok(progress) => tracing::info!(verified = progress.verified, bytes = progress.read_bytes, "scrub finished"),
+                Err(e) => tracing::error!(error = %e, "scrub failed"),
+            }
+            scrubber.running.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Every hour, verifies the versions that have gone unverified for longer than `interval`
+    pub fn spawn_schedule(&self, interval: chrono::Duration) {
+        let scrubber = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                ticks.tick().await;
+                // A pass still running from the last tick simply carries on
+                scrubber.start(Utc::now() - interval);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::fixtures::{add_entry, overwrite, scratch_instance};
+    use crate::storage::DeleteFileResult;
+
+    #[tokio::test]
+    async fn test_scrub_records_damaged_files() {
+        let dir = std::env::temp_dir().join(format!("lfs-scrub-{}", Uuid::new_v4()));
+        let (db, storage) = scratch_instance(&dir).await;
+
+        let mut entries = Vec::new();
+        for contents in [&b"intact"[..], b"flipped", b"truncated", b"vanished"] {
+            entries.push(add_entry(&db, &storage, contents).await);
+        }
+        let key = |i: usize| entries[i].blob_key();
+        overwrite(&**storage, &key(1), b"FLIPPED").await;
+        overwrite(&**storage, &key(2), b"trunc").await;
+        assert!(matches!(storage.delete_file(&key(3)).await, DeleteFileResult::Success));
+
+        let first = scrub(&db, &**storage, Utc::now(), 0, |_| {}).await.unwrap();
+        assert_eq!((first.verified, first.failed, first.read_bytes), (4, 3, 18));
+        let statuses: Vec<_> = db.list_scrub_failures().await.unwrap().into_iter().map(|r| (r.uuid, r.status)).collect();
+        assert_eq!(statuses.len(), 3);
+        assert!(statuses.contains(&(entries[1].uuid, ScrubStatus::ChecksumMismatch)));
+        assert!(statuses.contains(&(entries[2].uuid, ScrubStatus::SizeMismatch)));
+        assert!(statuses.contains(&(entries[3].uuid, ScrubStatus::Missing)));
+
+        // Nothing is due again until the cutoff passes its verification time
+        let cutoff = Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(scrub(&db, &**storage, cutoff, 0, |_| {}).await.unwrap().verified, 0);
+
+        // A repaired file clears its failure, and a deleted entry no longer shows up
+        overwrite(&**storage, &key(1), b"flipped").await;
+        db.delete_entry(entries[3].uuid).await.unwrap();
+        let again = scrub(&db, &**storage, Utc::now(), 0, |_| {}).await.unwrap();
+        assert_eq!((again.verified, again.failed), (3, 1));
+        let failures = db.list_scrub_failures().await.unwrap();
+        assert_eq!(failures.iter().map(|r| r.uuid).collect::<Vec<_>>(), vec![entries[2].uuid]);
+        assert_eq!(failures[0].detail.as_deref(), Some("expected 9 bytes, found 5"));
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use actix_web::dev::ServerHandle;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Set once the process has been asked to stop; cloned into everything that must wind down
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown { triggered: Arc::new(watch::channel(false).0) }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered
+    pub async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        // The sender lives in `self`, so the channel cannot close while we wait
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Waits for SIGTERM or Ctrl-C and returns the name of the signal
+async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+/// On the first stop signal, triggers `shutdown` and asks every server to stop gracefully.
+///
+/// The servers stop accepting connections and give in-flight requests their shutdown timeout to
+/// finish. A second signal stops them immediately.
+pub fn spawn_signal_handler(shutdown: Shutdown, servers: Vec<ServerHandle>) {
+    tokio::spawn(async move {
+        let signal = wait_for_signal().await;
+        tracing::info!(signal, "shutting down; waiting for in-flight requests");
+        shutdown.trigger();
+        for server in &servers {
+            // Stopping waits for the drain, so do it in the background to keep listening for signals
+            tokio::spawn(server.clone().stop(true));
+        }
+
+        let signal = wait_for_signal().await;
+        tracing::warn!(signal, "second stop signal received; aborting in-flight requests");
+        for server in &servers {
+            server.stop(false).await;
+        }
+    });
+}
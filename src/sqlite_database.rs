@@ -1,27 +1,30 @@
-use crate::{database::Database, entry::Entry};
+use crate::{database::{Database, DatabaseError, Migration, MigrationStatus, Snapshot, Usage}, entry::{Entry, FileVersion, CLI_SOURCE}};
+use crate::audit::{AuditFilter, AuditRecord};
+use crate::import::ImportRecord;
+use crate::scrub::{ScrubResult, ScrubStatus};
+use crate::stats::{DailyVolume, Download, DownloadStats};
+use crate::webhook::{Webhook, WebhookDelivery, WebhookEvent};
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
-use std::error::Error;
+use rusqlite::{params, Connection};
+use std::time::Duration;
 use uuid::Uuid;
 
-pub struct SqliteDatabase {
-    pool: Pool<SqliteConnectionManager>,
-}
+/// How long a connection waits on a locked database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
-impl SqliteDatabase {
-    /// Constructor to create a new SqliteDatabase with an initialized connection pool
-    /// Also ensures that the `entries` table is created if it does not exist.
-    pub async fn new(database_url: &str) -> Result<Self, Box<dyn Error>> {
-        let manager = SqliteConnectionManager::file(database_url);
-        let pool = Pool::new(manager)?;
+/// Number of prepared statements each connection keeps compiled
+const STATEMENT_CACHE_CAPACITY: usize = 32;
 
-        // Create the table if it doesn't exist
-        let conn = pool.get()?;
-        conn.execute(
-            r#"
+/// Schema history for SQLite, applied in order by `Database::migrate`
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_entries",
+        // IF NOT EXISTS so databases created before migrations existed adopt this version as-is
+        sql: r#"
             CREATE TABLE IF NOT EXISTS entries (
                 uuid TEXT PRIMARY KEY,
                 file_name TEXT NOT NULL,
@@ -29,80 +32,1540 @@ impl SqliteDatabase {
                 source_ip TEXT NOT NULL,
                 timestamp TEXT NOT NULL
             );
-            "#,
-            [],
-        )?;
+        "#,
+    },
+    Migration {
+        version: 2,
+        name: "add_entries_deleted_at",
+        sql: "ALTER TABLE entries ADD COLUMN deleted_at TEXT;",
+    },
+    Migration {
+        version: 3,
+        name: "create_versions",
+        // Every existing file becomes version 1 of its entry; its hash was never recorded
+        sql: r#"
+            ALTER TABLE entries ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+            ALTER TABLE entries ADD COLUMN sha256 TEXT;
+            CREATE TABLE versions (
+                entry_uuid TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                file_size INTEGER NOT NULL,
+                sha256 TEXT,
+                timestamp TEXT NOT NULL,
+                PRIMARY KEY (entry_uuid, version)
+            );
+            INSERT INTO versions (entry_uuid, version, file_size, sha256, timestamp)
+                SELECT uuid, 1, file_size, NULL, timestamp FROM entries;
+        "#,
+    },
+    Migration {
+        version: 4,
+        name: "add_mime_type",
+        // Files stored before sniffing existed are served as opaque downloads
+        sql: r#"
+            ALTER TABLE entries ADD COLUMN mime_type TEXT NOT NULL DEFAULT 'application/octet-stream';
+            ALTER TABLE versions ADD COLUMN mime_type TEXT NOT NULL DEFAULT 'application/octet-stream';
+        "#,
+    },
+    Migration {
+        version: 5,
+        name: "create_webhooks",
+        // `events` is a comma-separated list of event names
+        sql: r#"
+            CREATE TABLE webhooks (
+                id TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                events TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            );
+            CREATE TABLE webhook_deliveries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                webhook_id TEXT NOT NULL,
+                delivery_id TEXT NOT NULL,
+                event TEXT NOT NULL,
+                entry_uuid TEXT,
+                attempt INTEGER NOT NULL,
+                status_code INTEGER,
+                error TEXT,
+                timestamp TEXT NOT NULL
+            );
+            CREATE INDEX webhook_deliveries_by_webhook ON webhook_deliveries (webhook_id, id);
+        "#,
+    },
+    Migration {
+        version: 6,
+        name: "create_imports",
+        sql: r#"
+            CREATE TABLE imports (
+                source_root TEXT NOT NULL,
+                relative_path TEXT NOT NULL,
+                entry_uuid TEXT NOT NULL,
+                file_size INTEGER NOT NULL,
+                modified TEXT NOT NULL,
+                imported_at TEXT NOT NULL,
+                PRIMARY KEY (source_root, relative_path)
+            );
+        "#,
+    },
+    Migration {
+        version: 7,
+        name: "create_scrub_results",
+        // One row per version, replaced each time the scrubber re-reads its file
+        sql: r#"
+            CREATE TABLE scrub_results (
+                entry_uuid TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                detail TEXT,
+                verified_at TEXT NOT NULL,
+                PRIMARY KEY (entry_uuid, version)
+            );
+            CREATE INDEX scrub_results_by_status ON scrub_results (status, verified_at);
+        "#,
+    },
+    Migration {
+        version: 8,
+        name: "create_downloads",
+        sql: r#"
+            CREATE TABLE downloads (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                entry_uuid TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                client_ip TEXT,
+                timestamp TEXT NOT NULL
+            );
+            CREATE INDEX downloads_by_entry ON downloads (entry_uuid, timestamp);
+            CREATE INDEX downloads_by_time ON downloads (timestamp);
+        "#,
+    },
+    Migration {
+        version: 9,
+        name: "create_audit_log",
+        sql: r#"
+            CREATE TABLE audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp TEXT NOT NULL,
+                actor TEXT NOT NULL,
+                client_ip TEXT,
+                action TEXT NOT NULL,
+                entry_uuid TEXT,
+                file_name TEXT,
+                detail TEXT,
+                result TEXT NOT NULL,
+                request_id TEXT
+            );
+            CREATE INDEX audit_log_by_entry ON audit_log (entry_uuid);
+            CREATE INDEX audit_log_by_time ON audit_log (timestamp);
+        "#,
+    },
+    Migration {
+        version: 10,
+        name: "add_download_first_byte",
+        sql: "ALTER TABLE downloads ADD COLUMN first_byte INTEGER NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 11,
+        name: "drop_orphaned_imports",
+        // Purging used to leave the import record behind, so the file could never be imported again
+        sql: "DELETE FROM imports WHERE entry_uuid NOT IN (SELECT uuid FROM entries);",
+    },
+];
+
+/// Columns selected for every `Entry`, in the order `EntryRow::read` expects
+const ENTRY_COLUMNS: &str = "uuid, file_name, file_size, source_ip, timestamp, deleted_at, version, sha256, mime_type";
+
+/// SQLite-backed `Database`.
+///
+/// rusqlite and r2d2 are synchronous, so every query runs on tokio's blocking
+/// thread pool via `with_conn` rather than on the async worker threads.
+pub struct SqliteDatabase {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl From<r2d2::Error> for DatabaseError {
+    fn from(e: r2d2::Error) -> Self {
+        DatabaseError::Unavailable(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(e: rusqlite::Error) -> Self {
+        DatabaseError::Backend(e.to_string())
+    }
+}
+
+/// Raw column values of an `entries` row, decoded separately so malformed data
+/// surfaces as `DatabaseError::CorruptEntry` instead of a panic
+struct EntryRow {
+    uuid: String,
+    file_name: String,
+    file_size: i64,
+    source_ip: String,
+    timestamp: String,
+    deleted_at: Option<String>,
+    version: u32,
+    sha256: Option<String>,
+    mime_type: String,
+}
+
+fn parse_uuid(raw: &str) -> Result<Uuid, DatabaseError> {
+    Uuid::parse_str(raw).map_err(|e| DatabaseError::CorruptEntry {
+        uuid: raw.to_string(),
+        reason: format!("invalid uuid: {}", e),
+    })
+}
+
+impl EntryRow {
+    /// Reads a row selected with `ENTRY_COLUMNS`
+    fn read(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(EntryRow {
+            uuid: row.get(0)?,
+            file_name: row.get(1)?,
+            file_size: row.get(2)?,
+            source_ip: row.get(3)?,
+            timestamp: row.get(4)?,
+            deleted_at: row.get(5)?,
+            version: row.get(6)?,
+            sha256: row.get(7)?,
+            mime_type: row.get(8)?,
+        })
+    }
+
+    fn decode(self) -> Result<Entry, DatabaseError> {
+        let uuid = self.uuid;
+        let corrupt = |reason: String| DatabaseError::CorruptEntry { uuid: uuid.clone(), reason };
+        let parse_time = |field: &str, raw: &str| {
+            DateTime::parse_from_rfc3339(raw)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| corrupt(format!("invalid {} '{}': {}", field, raw, e)))
+        };
+
+        Ok(Entry {
+            uuid: parse_uuid(&uuid)?,
+            file_name: self.file_name,
+            file_size: u64::try_from(self.file_size)
+                .map_err(|_| corrupt(format!("negative file size {}", self.file_size)))?,
+            source_ip: self.source_ip,
+            timestamp: parse_time("timestamp", &self.timestamp)?,
+            deleted_at: self
+                .deleted_at
+                .as_deref()
+                .map(|raw| parse_time("deleted_at", raw))
+                .transpose()?,
+            version: self.version,
+            sha256: self.sha256,
+            mime_type: self.mime_type,
+        })
+    }
+}
+
+/// Columns selected for every `Webhook`, in the order `read_webhook` expects
+const WEBHOOK_COLUMNS: &str = "id, url, secret, events, created_at";
+
+fn parse_time(owner: &str, field: &str, raw: &str) -> Result<DateTime<Utc>, DatabaseError> {
+    DateTime::parse_from_rfc3339(raw)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| DatabaseError::CorruptEntry {
+            uuid: owner.to_string(),
+            reason: format!("invalid {} '{}': {}", field, raw, e),
+        })
+}
+
+fn parse_event(owner: &str, raw: &str) -> Result<WebhookEvent, DatabaseError> {
+    raw.parse().map_err(|reason| DatabaseError::CorruptEntry { uuid: owner.to_string(), reason })
+}
+
+/// Reads and decodes a row selected with `WEBHOOK_COLUMNS`
+fn read_webhook(row: &rusqlite::Row) -> rusqlite::Result<Result<Webhook, DatabaseError>> {
+    let id: String = row.get(0)?;
+    let events: String = row.get(3)?;
+    let created_at: String = row.get(4)?;
+    let (url, secret) = (row.get(1)?, row.get(2)?);
+
+    Ok((|| {
+        Ok(Webhook {
+            id: parse_uuid(&id)?,
+            url,
+            secret,
+            events: events
+                .split(',')
+                .filter(|event| !event.is_empty())
+                .map(|event| parse_event(&id, event))
+                .collect::<Result<_, _>>()?,
+            created_at: parse_time(&id, "created_at", &created_at)?,
+        })
+    })())
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation)
+}
+
+fn insert_version(conn: &Connection, uuid: Uuid, version: &FileVersion) -> rusqlite::Result<usize> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO versions (entry_uuid, version, file_size, sha256, mime_type, timestamp) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    stmt.execute(params![
+        uuid.to_string(),
+        version.version,
+        version.file_size,
+        version.sha256,
+        version.mime_type,
+        version.timestamp.to_rfc3339()
+    ])
+}
+
+/// Inserts the `entries` row alone; the caller owns the transaction
+fn insert_entry_row(conn: &Connection, input: &Entry) -> rusqlite::Result<usize> {
+    conn.prepare_cached(&format!(
+        "INSERT INTO entries ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        ENTRY_COLUMNS
+    ))?
+    .execute(params![
+        input.uuid.to_string(),
+        input.file_name,
+        input.file_size,
+        input.source_ip,
+        input.timestamp.to_rfc3339(),
+        input.deleted_at.map(|t| t.to_rfc3339()),
+        input.version,
+        input.sha256,
+        input.mime_type
+    ])
+}
+
+/// Inserts an entry and the version record for its current version; the caller owns the transaction
+fn insert_entry_rows(conn: &Connection, input: &Entry) -> rusqlite::Result<()> {
+    insert_entry_row(conn, input)?;
+    let current = FileVersion {
+        version: input.version,
+        file_size: input.file_size,
+        sha256: input.sha256.clone(),
+        mime_type: input.mime_type.clone(),
+        timestamp: input.timestamp,
+    };
+    insert_version(conn, input.uuid, &current)?;
+    Ok(())
+}
+
+fn insert_webhook_row(conn: &Connection, webhook: &Webhook) -> rusqlite::Result<usize> {
+    let events: Vec<&str> = webhook.events.iter().map(|event| event.as_str()).collect();
+    conn.prepare_cached("INSERT INTO webhooks (id, url, secret, events, created_at) VALUES (?, ?, ?, ?, ?)")?
+        .execute(params![
+            webhook.id.to_string(),
+            webhook.url,
+            webhook.secret,
+            events.join(","),
+            webhook.created_at.to_rfc3339()
+        ])
+}
+
+/// Columns selected for every `FileVersion`, in the order `read_version` expects
+const VERSION_COLUMNS: &str = "version, file_size, sha256, mime_type, timestamp";
+
+/// Reads and decodes a row selected with `VERSION_COLUMNS`; `uuid` names the entry in errors
+fn read_version(uuid: &str, row: &rusqlite::Row) -> rusqlite::Result<Result<FileVersion, DatabaseError>> {
+    let version: u32 = row.get(0)?;
+    let file_size: i64 = row.get(1)?;
+    let (sha256, mime_type) = (row.get(2)?, row.get(3)?);
+    let timestamp: String = row.get(4)?;
+
+    let corrupt = |reason: String| DatabaseError::CorruptEntry {
+        uuid: uuid.to_string(),
+        reason: format!("version {}: {}", version, reason),
+    };
+    Ok((|| {
+        Ok(FileVersion {
+            version,
+            file_size: u64::try_from(file_size).map_err(|_| corrupt(format!("negative file size {}", file_size)))?,
+            sha256,
+            mime_type,
+            timestamp: DateTime::parse_from_rfc3339(&timestamp)
+                .map_err(|e| corrupt(format!("invalid timestamp '{}': {}", timestamp, e)))?
+                .with_timezone(&Utc),
+        })
+    })())
+}
+
+/// Every recorded version of an entry, oldest first
+fn query_versions(conn: &Connection, uuid: Uuid) -> Result<Vec<FileVersion>, DatabaseError> {
+    let uuid = uuid.to_string();
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM versions WHERE entry_uuid = ? ORDER BY version",
+        VERSION_COLUMNS
+    ))?;
+
+    let mut versions = Vec::new();
+    for row in stmt.query_map(params![uuid], |row| read_version(&uuid, row))? {
+        versions.push(row??);
+    }
+    Ok(versions)
+}
+
+fn insert_delivery_row(conn: &Connection, delivery: &WebhookDelivery) -> rusqlite::Result<usize> {
+    conn.prepare_cached(
+        r#"
+        INSERT INTO webhook_deliveries
+            (webhook_id, delivery_id, event, entry_uuid, attempt, status_code, error, timestamp)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )?
+    .execute(params![
+        delivery.webhook_id.to_string(),
+        delivery.delivery_id.to_string(),
+        delivery.event.as_str(),
+        delivery.entry_uuid.map(|uuid| uuid.to_string()),
+        delivery.attempt,
+        delivery.status_code,
+        delivery.error,
+        delivery.timestamp.to_rfc3339()
+    ])
+}
+
+/// Up to `limit` of a webhook's most recent deliveries, newest first; a negative limit returns all
+fn query_deliveries(conn: &Connection, webhook_id: Uuid, limit: i64) -> Result<Vec<WebhookDelivery>, DatabaseError> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT delivery_id, event, entry_uuid, attempt, status_code, error, timestamp
+        FROM webhook_deliveries WHERE webhook_id = ? ORDER BY id DESC LIMIT ?
+        "#,
+    )?;
+    let rows = stmt.query_map(params![webhook_id.to_string(), limit], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get::<_, String>(6)?,
+        ))
+    })?;
+
+    let mut deliveries = Vec::new();
+    for row in rows {
+        let (delivery_id, event, entry_uuid, attempt, status_code, error, timestamp) = row?;
+        deliveries.push(WebhookDelivery {
+            webhook_id,
+            delivery_id: parse_uuid(&delivery_id)?,
+            event: parse_event(&delivery_id, &event)?,
+            entry_uuid: entry_uuid.as_deref().map(parse_uuid).transpose()?,
+            attempt,
+            status_code,
+            error,
+            timestamp: parse_time(&delivery_id, "timestamp", &timestamp)?,
+        });
+    }
+    Ok(deliveries)
+}
+
+/// Columns selected for every `ImportRecord`, in the order `read_import` expects
+const IMPORT_COLUMNS: &str = "source_root, relative_path, entry_uuid, file_size, modified, imported_at";
+
+fn insert_import_row(conn: &Connection, import: &ImportRecord) -> rusqlite::Result<usize> {
+    conn.prepare_cached(&format!("INSERT INTO imports ({}) VALUES (?, ?, ?, ?, ?, ?)", IMPORT_COLUMNS))?
+        .execute(params![
+            import.source_root,
+            import.relative_path,
+            import.uuid.to_string(),
+            import.file_size,
+            import.modified.to_rfc3339(),
+            import.imported_at.to_rfc3339()
+        ])
+}
+
+/// Reads and decodes a row selected with `IMPORT_COLUMNS`
+fn read_import(row: &rusqlite::Row) -> rusqlite::Result<Result<ImportRecord, DatabaseError>> {
+    let (source_root, relative_path) = (row.get(0)?, row.get(1)?);
+    let uuid: String = row.get(2)?;
+    let file_size: i64 = row.get(3)?;
+    let modified: String = row.get(4)?;
+    let imported_at: String = row.get(5)?;
+
+    Ok((|| {
+        Ok(ImportRecord {
+            source_root,
+            relative_path,
+            uuid: parse_uuid(&uuid)?,
+            file_size: u64::try_from(file_size).map_err(|_| DatabaseError::CorruptEntry {
+                uuid: uuid.clone(),
+                reason: format!("negative imported file_size {}", file_size),
+            })?,
+            modified: parse_time(&uuid, "modified", &modified)?,
+            imported_at: parse_time(&uuid, "imported_at", &imported_at)?,
+        })
+    })())
+}
+
+/// Columns selected for every `ScrubResult`, in the order `read_scrub_result` expects
+const SCRUB_COLUMNS: &str = "entry_uuid, version, status, detail, verified_at";
+
+/// Reads and decodes a row selected with `SCRUB_COLUMNS`
+fn read_scrub_result(row: &rusqlite::Row) -> rusqlite::Result<Result<ScrubResult, DatabaseError>> {
+    let uuid: String = row.get(0)?;
+    let version = row.get(1)?;
+    let status: String = row.get(2)?;
+    let detail = row.get(3)?;
+    let verified_at: String = row.get(4)?;
+
+    Ok((|| {
+        Ok(ScrubResult {
+            uuid: parse_uuid(&uuid)?,
+            version,
+            status: status
+                .parse::<ScrubStatus>()
+                .map_err(|reason| DatabaseError::CorruptEntry { uuid: uuid.clone(), reason })?,
+            detail,
+            verified_at: parse_time(&uuid, "verified_at", &verified_at)?,
+        })
+    })())
+}
+
+/// Columns selected for every `Download`, in the order `read_download` expects
+const DOWNLOAD_COLUMNS: &str = "entry_uuid, version, bytes, client_ip, timestamp, first_byte";
+
+fn insert_download_row(conn: &Connection, download: &Download) -> rusqlite::Result<usize> {
+    conn.prepare_cached(&format!("INSERT INTO downloads ({}) VALUES (?, ?, ?, ?, ?, ?)", DOWNLOAD_COLUMNS))?
+        .execute(params![
+            download.uuid.to_string(),
+            download.version,
+            download.bytes,
+            download.client_ip,
+            download.timestamp.to_rfc3339(),
+            download.first_byte
+        ])
+}
+
+/// Reads and decodes a row selected with `DOWNLOAD_COLUMNS`
+fn read_download(row: &rusqlite::Row) -> rusqlite::Result<Result<Download, DatabaseError>> {
+    let uuid: String = row.get(0)?;
+    let version = row.get(1)?;
+    let bytes: i64 = row.get(2)?;
+    let client_ip = row.get(3)?;
+    let timestamp: String = row.get(4)?;
+    let first_byte: i64 = row.get(5)?;
+
+    Ok((|| {
+        Ok(Download {
+            uuid: parse_uuid(&uuid)?,
+            version,
+            bytes: u64::try_from(bytes).map_err(|_| DatabaseError::CorruptEntry {
+                uuid: uuid.clone(),
+                reason: format!("negative downloaded bytes {}", bytes),
+            })?,
+            client_ip,
+            timestamp: parse_time(&uuid, "download timestamp", &timestamp)?,
+            first_byte: u64::try_from(first_byte).map_err(|_| DatabaseError::CorruptEntry {
+                uuid: uuid.clone(),
+                reason: format!("negative first downloaded byte {}", first_byte),
+            })?,
+        })
+    })())
+}
+
+/// Decodes the count, byte total and latest time of a download log aggregate
+fn download_stats(uuid: Uuid, downloads: i64, served_bytes: i64, last: Option<String>) -> Result<DownloadStats, DatabaseError> {
+    Ok(DownloadStats {
+        uuid,
+        downloads: downloads.max(0) as u64,
+        served_bytes: served_bytes.max(0) as u64,
+        last_accessed: last.map(|last| parse_time(&uuid.to_string(), "download timestamp", &last)).transpose()?,
+    })
+}
+
+/// Columns selected for every `AuditRecord`, in the order `read_audit` expects
+const AUDIT_COLUMNS: &str = "id, timestamp, actor, client_ip, action, entry_uuid, file_name, detail, result, request_id";
+
+/// Inserts `record`, keeping its `id` when `keep_id` is set and letting SQLite assign the next one otherwise
+fn insert_audit_row(conn: &Connection, record: &AuditRecord, keep_id: bool) -> rusqlite::Result<i64> {
+    conn.prepare_cached(&format!("INSERT INTO audit_log ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", AUDIT_COLUMNS))?
+        .execute(params![
+            keep_id.then_some(record.id as i64),
+            record.timestamp.to_rfc3339(),
+            record.actor,
+            record.client_ip,
+            record.action,
+            record.entry_uuid.map(|uuid| uuid.to_string()),
+            record.file_name,
+            record.detail,
+            record.result,
+            record.request_id
+        ])?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Reads and decodes a row selected with `AUDIT_COLUMNS`
+fn read_audit(row: &rusqlite::Row) -> rusqlite::Result<Result<AuditRecord, DatabaseError>> {
+    let id: i64 = row.get(0)?;
+    let timestamp: String = row.get(1)?;
+    let actor = row.get(2)?;
+    let client_ip = row.get(3)?;
+    let action = row.get(4)?;
+    let entry_uuid: Option<String> = row.get(5)?;
+    let file_name = row.get(6)?;
+    let detail = row.get(7)?;
+    let result = row.get(8)?;
+    let request_id = row.get(9)?;
+
+    Ok((|| {
+        Ok(AuditRecord {
+            id: id as u64,
+            timestamp: parse_time(&format!("audit record {}", id), "timestamp", &timestamp)?,
+            actor,
+            client_ip,
+            action,
+            entry_uuid: entry_uuid.as_deref().map(parse_uuid).transpose()?,
+            file_name,
+            detail,
+            result,
+            request_id,
+        })
+    })())
+}
+
+impl SqliteDatabase {
+    /// Constructor to create a new SqliteDatabase with an initialized connection pool.
+    /// The schema is left untouched; call `Database::migrate` before serving requests.
+    pub async fn new(database_url: &str) -> Result<Self, DatabaseError> {
+        // WAL lets readers proceed while a write is in progress; NORMAL sync is durable under WAL
+        let manager = SqliteConnectionManager::file(database_url).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+            Ok(())
+        });
+        let pool = Pool::new(manager)?;
 
         Ok(SqliteDatabase { pool })
     }
+
+    /// Runs `f` with a pooled connection on the blocking thread pool
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, DatabaseError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, DatabaseError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            f(&conn)
+        })
+        .await
+        .map_err(|e| DatabaseError::Backend(format!("database task failed: {}", e)))?
+    }
 }
 
 #[async_trait]
 impl Database for SqliteDatabase {
-    async fn list_uuids(&self) -> Result<Vec<Uuid>, Box<dyn Error>> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare("SELECT uuid FROM entries")?;
-        let rows = stmt.query_map([], |row| {
-            let uuid_str: String = row.get(0)?;
-            Ok(Uuid::parse_str(&uuid_str).unwrap())
-        })?;
-
-        let mut uuids = Vec::new();
-        for uuid in rows {
-            uuids.push(uuid?);
-        }
-        Ok(uuids)
-    }
-
-    async fn get_entry(&self, uuid: Uuid) -> Result<Option<Entry>, Box<dyn Error>> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "SELECT uuid, file_name, file_size, source_ip, timestamp FROM entries WHERE uuid = ?",
-        )?;
-
-        let entry = stmt.query_row(params![uuid.to_string()], |row| {
-            Ok(Entry {
-                uuid: Uuid::parse_str(&row.get::<_, String>(0)?).unwrap(),
-                file_name: row.get(1)?,
-                file_size: row.get(2)?,
-                source_ip: row.get(3)?,
-                timestamp: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)
-                    .unwrap()
-                    .with_timezone(&Utc),
+    async fn ping(&self) -> Result<(), DatabaseError> {
+        self.with_conn(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_uuids(&self) -> Result<Vec<Uuid>, DatabaseError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached("SELECT uuid FROM entries WHERE deleted_at IS NULL")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+            let mut uuids = Vec::new();
+            for uuid in rows {
+                uuids.push(parse_uuid(&uuid?)?);
+            }
+            Ok(uuids)
+        })
+        .await
+    }
+
+    async fn get_entry(&self, uuid: Uuid) -> Result<Option<Entry>, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                &format!("SELECT {} FROM entries WHERE uuid = ?", ENTRY_COLUMNS),
+            )?;
+
+            match stmt.query_row(params![uuid.to_string()], EntryRow::read) {
+                Ok(row) => row.decode().map(Some),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn insert_entry(&self, input: Entry) -> Result<Uuid, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            match insert_entry_rows(&tx, &input) {
+                Ok(_) => {
+                    tx.commit()?;
+                    Ok(input.uuid)
+                }
+                Err(e) if is_constraint_violation(&e) => Err(DatabaseError::DuplicateEntry(input.uuid)),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn delete_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.prepare_cached("DELETE FROM versions WHERE entry_uuid = ?")?
+                .execute(params![uuid.to_string()])?;
+            tx.prepare_cached("DELETE FROM scrub_results WHERE entry_uuid = ?")?
+                .execute(params![uuid.to_string()])?;
+            tx.prepare_cached("DELETE FROM downloads WHERE entry_uuid = ?")?
+                .execute(params![uuid.to_string()])?;
+            tx.prepare_cached("DELETE FROM imports WHERE entry_uuid = ?")?
+                .execute(params![uuid.to_string()])?;
+            let affected = tx
+                .prepare_cached("DELETE FROM entries WHERE uuid = ?")?
+                .execute(params![uuid.to_string()])?;
+            tx.commit()?;
+
+            Ok(affected > 0)
+        })
+        .await
+    }
+
+    async fn add_version(&self, uuid: Uuid, version: FileVersion) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let affected = tx
+                .prepare_cached(
+                    "UPDATE entries SET version = ?, file_size = ?, sha256 = ?, mime_type = ? WHERE uuid = ? AND deleted_at IS NULL",
+                )?
+                .execute(params![
+                    version.version,
+                    version.file_size,
+                    version.sha256,
+                    version.mime_type,
+                    uuid.to_string()
+                ])?;
+            if affected == 0 {
+                return Ok(false);
+            }
+
+            match insert_version(&tx, uuid, &version) {
+                Ok(_) => {
+                    tx.commit()?;
+                    Ok(true)
+                }
+                Err(e) if is_constraint_violation(&e) => Err(DatabaseError::DuplicateEntry(uuid)),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn list_versions(&self, uuid: Uuid) -> Result<Vec<FileVersion>, DatabaseError> {
+        self.with_conn(move |conn| query_versions(conn, uuid)).await
+    }
+
+    async fn delete_version(&self, uuid: Uuid, version: u32) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let affected = tx
+                .prepare_cached(
+                    r#"
+                    DELETE FROM versions
+                    WHERE entry_uuid = ?1 AND version = ?2
+                        AND version != (SELECT version FROM entries WHERE uuid = ?1)
+                    "#,
+                )?
+                .execute(params![uuid.to_string(), version])?;
+            if affected > 0 {
+                tx.prepare_cached("DELETE FROM scrub_results WHERE entry_uuid = ? AND version = ?")?
+                    .execute(params![uuid.to_string(), version])?;
+            }
+            tx.commit()?;
+
+            Ok(affected > 0)
+        })
+        .await
+    }
+
+    async fn trash_entry(&self, uuid: Uuid, deleted_at: DateTime<Utc>) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "UPDATE entries SET deleted_at = ? WHERE uuid = ? AND deleted_at IS NULL",
+            )?;
+            let affected = stmt.execute(params![deleted_at.to_rfc3339(), uuid.to_string()])?;
+
+            Ok(affected > 0)
+        })
+        .await
+    }
+
+    async fn restore_entry(&self, uuid: Uuid) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "UPDATE entries SET deleted_at = NULL WHERE uuid = ? AND deleted_at IS NOT NULL",
+            )?;
+            let affected = stmt.execute(params![uuid.to_string()])?;
+
+            Ok(affected > 0)
+        })
+        .await
+    }
+
+    async fn list_trash(&self) -> Result<Vec<Entry>, DatabaseError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM entries WHERE deleted_at IS NOT NULL ORDER BY deleted_at",
+                ENTRY_COLUMNS
+            ))?;
+            let rows = stmt.query_map([], EntryRow::read)?;
+
+            let mut entries = Vec::new();
+            for row in rows {
+                entries.push(row?.decode()?);
+            }
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn usage(&self) -> Result<Usage, DatabaseError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(
+                r#"
+                SELECT
+                    (SELECT COUNT(*) FROM entries WHERE deleted_at IS NULL),
+                    (SELECT COUNT(*) FROM entries WHERE deleted_at IS NOT NULL),
+                    (SELECT COALESCE(SUM(file_size), 0) FROM versions),
+                    (SELECT COUNT(*) FROM downloads WHERE first_byte = 0),
+                    (SELECT COALESCE(SUM(bytes), 0) FROM downloads)
+                "#,
+            )?;
+            let (entries, trashed_entries, stored_bytes, downloads, served_bytes) = stmt.query_row([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+
+            Ok(Usage {
+                entries: entries as u64,
+                trashed_entries: trashed_entries as u64,
+                stored_bytes: stored_bytes.max(0) as u64,
+                downloads: downloads as u64,
+                served_bytes: served_bytes.max(0) as u64,
             })
-        });
+        })
+        .await
+    }
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| match insert_webhook_row(conn, &webhook) {
+            Ok(_) => Ok(()),
+            Err(e) if is_constraint_violation(&e) => Err(DatabaseError::DuplicateEntry(webhook.id)),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>, DatabaseError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM webhooks ORDER BY created_at",
+                WEBHOOK_COLUMNS
+            ))?;
+            let rows = stmt.query_map([], read_webhook)?;
+
+            let mut webhooks = Vec::new();
+            for row in rows {
+                webhooks.push(row??);
+            }
+            Ok(webhooks)
+        })
+        .await
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Option<Webhook>, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM webhooks WHERE id = ?", WEBHOOK_COLUMNS))?;
+            let mut rows = stmt.query_map(params![id.to_string()], read_webhook)?;
+
+            match rows.next() {
+                Some(row) => Ok(Some(row??)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.prepare_cached("DELETE FROM webhook_deliveries WHERE webhook_id = ?")?
+                .execute(params![id.to_string()])?;
+            let affected = tx
+                .prepare_cached("DELETE FROM webhooks WHERE id = ?")?
+                .execute(params![id.to_string()])?;
+            tx.commit()?;
+
+            Ok(affected > 0)
+        })
+        .await
+    }
+
+    async fn record_delivery(&self, delivery: WebhookDelivery, keep: usize) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            insert_delivery_row(&tx, &delivery)?;
+            tx.prepare_cached(
+                r#"
+                DELETE FROM webhook_deliveries
+                WHERE webhook_id = ?1 AND id NOT IN (
+                    SELECT id FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY id DESC LIMIT ?2
+                )
+                "#,
+            )?
+            .execute(params![delivery.webhook_id.to_string(), keep as i64])?;
+            tx.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_deliveries(&self, webhook_id: Uuid, limit: usize) -> Result<Vec<WebhookDelivery>, DatabaseError> {
+        self.with_conn(move |conn| query_deliveries(conn, webhook_id, limit as i64)).await
+    }
+
+    async fn insert_imported_entry(&self, input: Entry, source: ImportRecord) -> Result<Uuid, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            match insert_entry_rows(&tx, &input).and_then(|_| insert_import_row(&tx, &source)) {
+                Ok(_) => {
+                    tx.commit()?;
+                    Ok(input.uuid)
+                }
+                Err(e) if is_constraint_violation(&e) => Err(DatabaseError::DuplicateEntry(input.uuid)),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn get_import(&self, source_root: &str, relative_path: &str) -> Result<Option<ImportRecord>, DatabaseError> {
+        let (source_root, relative_path) = (source_root.to_string(), relative_path.to_string());
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM imports WHERE source_root = ? AND relative_path = ?",
+                IMPORT_COLUMNS
+            ))?;
+            let mut rows = stmt.query_map(params![source_root, relative_path], read_import)?;
+
+            match rows.next() {
+                Some(row) => Ok(Some(row??)),
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+
+    async fn record_download(&self, download: Download) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            insert_download_row(conn, &download)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn download_stats(&self, uuid: Uuid) -> Result<DownloadStats, DatabaseError> {
+        self.with_conn(move |conn| {
+            let (downloads, served_bytes, last) = conn
+                .prepare_cached(
+                    "SELECT COALESCE(SUM(first_byte = 0), 0), COALESCE(SUM(bytes), 0), MAX(timestamp) FROM downloads WHERE entry_uuid = ?",
+                )?
+                .query_row(params![uuid.to_string()], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            download_stats(uuid, downloads, served_bytes, last)
+        })
+        .await
+    }
+
+    async fn top_downloads(&self, limit: usize) -> Result<Vec<(Entry, DownloadStats)>, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                r#"
+                SELECT {}, d.downloads, d.served_bytes, d.last_accessed FROM entries
+                JOIN (
+                    SELECT entry_uuid, SUM(first_byte = 0) AS downloads, SUM(bytes) AS served_bytes, MAX(timestamp) AS last_accessed
+                    FROM downloads GROUP BY entry_uuid HAVING downloads > 0
+                ) d ON d.entry_uuid = entries.uuid
+                WHERE deleted_at IS NULL
+                ORDER BY d.downloads DESC, d.last_accessed DESC
+                LIMIT ?
+                "#,
+                ENTRY_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![limit as i64], |row| {
+                Ok((EntryRow::read(row)?, row.get(9)?, row.get(10)?, row.get(11)?))
+            })?;
+
+            let mut top = Vec::new();
+            for row in rows {
+                let (entry, downloads, served_bytes, last) = row?;
+                let entry = entry.decode()?;
+                let stats = download_stats(entry.uuid, downloads, served_bytes, last)?;
+                top.push((entry, stats));
+            }
+            Ok(top)
+        })
+        .await
+    }
+
+    async fn daily_volume(&self, since: DateTime<Utc>) -> Result<Vec<DailyVolume>, DatabaseError> {
+        self.with_conn(move |conn| {
+            // Timestamps are stored in UTC, so their first ten characters are the UTC date
+            let mut stmt = conn.prepare_cached(
+                r#"
+                SELECT day, SUM(uploads), SUM(uploaded_bytes), SUM(downloads), SUM(downloaded_bytes) FROM (
+                    SELECT substr(timestamp, 1, 10) AS day, 1 AS uploads, file_size AS uploaded_bytes,
+                        0 AS downloads, 0 AS downloaded_bytes
+                    FROM versions WHERE timestamp >= ?1
+                    UNION ALL
+                    SELECT substr(timestamp, 1, 10), 0, 0, first_byte = 0, bytes FROM downloads WHERE timestamp >= ?1
+                )
+                GROUP BY day ORDER BY day
+                "#,
+            )?;
+            let rows = stmt.query_map(params![since.to_rfc3339()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+
+            let mut days = Vec::new();
+            for row in rows {
+                let (day, uploads, uploaded_bytes, downloads, downloaded_bytes) = row?;
+                days.push(DailyVolume {
+                    day: day.parse().map_err(|e| DatabaseError::Backend(format!("invalid day '{}': {}", day, e)))?,
+                    uploads: uploads.max(0) as u64,
+                    uploaded_bytes: uploaded_bytes.max(0) as u64,
+                    downloads: downloads.max(0) as u64,
+                    downloaded_bytes: downloaded_bytes.max(0) as u64,
+                });
+            }
+            Ok(days)
+        })
+        .await
+    }
+
+    async fn anonymize_client_ips(&self, before: DateTime<Utc>) -> Result<u64, DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let before = before.to_rfc3339();
+            let mut changed = tx
+                .prepare_cached("UPDATE entries SET source_ip = '' WHERE timestamp < ? AND source_ip NOT IN ('', ?)")?
+                .execute(params![before, CLI_SOURCE])?;
+            for table in ["downloads", "audit_log"] {
+                changed += tx
+                    .prepare_cached(&format!(
+                        "UPDATE {} SET client_ip = NULL WHERE timestamp < ? AND client_ip IS NOT NULL",
+                        table
+                    ))?
+                    .execute(params![before])?;
+            }
+            tx.commit()?;
+            Ok(changed as u64)
+        })
+        .await
+    }
+
+    async fn append_audit(&self, record: AuditRecord) -> Result<u64, DatabaseError> {
+        self.with_conn(move |conn| Ok(insert_audit_row(conn, &record, false)? as u64)).await
+    }
+
+    async fn list_audit(&self, filter: AuditFilter, limit: usize) -> Result<Vec<AuditRecord>, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                r#"
+                SELECT {} FROM audit_log
+                WHERE id > ?1
+                    AND (?2 IS NULL OR actor = ?2)
+                    AND (?3 IS NULL OR action = ?3)
+                    AND (?4 IS NULL OR entry_uuid = ?4)
+                    AND (?5 IS NULL OR timestamp >= ?5)
+                    AND (?6 IS NULL OR timestamp < ?6)
+                ORDER BY id
+                LIMIT ?7
+                "#,
+                AUDIT_COLUMNS
+            ))?;
+            let rows = stmt.query_map(
+                params![
+                    filter.after.unwrap_or(0) as i64,
+                    filter.actor,
+                    filter.action,
+                    filter.entry.map(|uuid| uuid.to_string()),
+                    filter.since.map(|since| since.to_rfc3339()),
+                    filter.until.map(|until| until.to_rfc3339()),
+                    limit as i64
+                ],
+                read_audit,
+            )?;
+
+            let mut records = Vec::new();
+            for row in rows {
+                records.push(row??);
+            }
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn list_scrub_due(&self, cutoff: DateTime<Utc>) -> Result<Vec<(Uuid, FileVersion)>, DatabaseError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(&format!(
+                r#"
+                SELECT {}, entry_uuid FROM versions
+                LEFT JOIN scrub_results USING (entry_uuid, version)
+                WHERE verified_at IS NULL OR verified_at < ?
+                ORDER BY verified_at IS NOT NULL, verified_at, entry_uuid, version
+                "#,
+                VERSION_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![cutoff.to_rfc3339()], |row| {
+                let uuid: String = row.get(5)?;
+                Ok((parse_uuid(&uuid), read_version(&uuid, row)?))
+            })?;
+
+            let mut due = Vec::new();
+            for row in rows {
+                let (uuid, version) = row?;
+                due.push((uuid?, version?));
+            }
+            Ok(due)
+        })
+        .await
+    }
+
+    async fn record_scrub(&self, result: ScrubResult) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            conn.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO scrub_results ({}) VALUES (?, ?, ?, ?, ?)",
+                SCRUB_COLUMNS
+            ))?
+            .execute(params![
+                result.uuid.to_string(),
+                result.version,
+                result.status.as_str(),
+                result.detail,
+                result.verified_at.to_rfc3339()
+            ])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_scrub_failures(&self) -> Result<Vec<ScrubResult>, DatabaseError> {
+        self.with_conn(|conn| {
+            // Versions removed since they were verified are left out
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT {} FROM scrub_results JOIN versions USING (entry_uuid, version) WHERE status != 'ok' ORDER BY verified_at",
+                SCRUB_COLUMNS
+            ))?;
+
+            let mut failures = Vec::new();
+            for row in stmt.query_map([], read_scrub_result)? {
+                failures.push(row??);
+            }
+            Ok(failures)
+        })
+        .await
+    }
 
-        match entry {
-            Ok(entry) => Ok(Some(entry)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(Box::new(e)),
+    async fn snapshot(&self) -> Result<Snapshot, DatabaseError> {
+        self.with_conn(|conn| {
+            // Under WAL a read transaction sees the database as of its first read, whatever commits meanwhile
+            let tx = conn.unchecked_transaction()?;
+            let rows = tx
+                .prepare_cached(&format!("SELECT {} FROM entries ORDER BY timestamp, uuid", ENTRY_COLUMNS))?
+                .query_map([], EntryRow::read)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut entries = Vec::with_capacity(rows.len());
+            for row in rows {
+                let entry = row.decode()?;
+                let versions = query_versions(&tx, entry.uuid)?;
+                entries.push((entry, versions));
+            }
+
+            let mut webhooks = Vec::new();
+            let mut stmt = tx.prepare_cached(&format!("SELECT {} FROM webhooks ORDER BY created_at", WEBHOOK_COLUMNS))?;
+            for row in stmt.query_map([], read_webhook)? {
+                webhooks.push(row??);
+            }
+
+            let mut deliveries = Vec::new();
+            for webhook in &webhooks {
+                // A negative limit means none in SQLite
+                let mut log = query_deliveries(&tx, webhook.id, -1)?;
+                log.reverse();
+                deliveries.extend(log);
+            }
+
+            let mut imports = Vec::new();
+            let mut stmt = tx.prepare_cached(&format!("SELECT {} FROM imports ORDER BY source_root, relative_path", IMPORT_COLUMNS))?;
+            for row in stmt.query_map([], read_import)? {
+                imports.push(row??);
+            }
+
+            let mut downloads = Vec::new();
+            let mut stmt = tx.prepare_cached(&format!("SELECT {} FROM downloads ORDER BY id", DOWNLOAD_COLUMNS))?;
+            for row in stmt.query_map([], read_download)? {
+                downloads.push(row??);
+            }
+
+            let mut audit = Vec::new();
+            let mut stmt = tx.prepare_cached(&format!("SELECT {} FROM audit_log ORDER BY id", AUDIT_COLUMNS))?;
+            for row in stmt.query_map([], read_audit)? {
+                audit.push(row??);
+            }
+
+            Ok(Snapshot { entries, webhooks, deliveries, imports, downloads, audit })
+        })
+        .await
+    }
+
+    async fn load_snapshot(&self, snapshot: Snapshot) -> Result<(), DatabaseError> {
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(
+                "DELETE FROM webhook_deliveries; DELETE FROM webhooks; DELETE FROM imports; \
+                 DELETE FROM scrub_results; DELETE FROM downloads; DELETE FROM audit_log; DELETE FROM versions; \
+                 DELETE FROM entries;",
+            )?;
+
+            let duplicate = |uuid: Uuid| {
+                move |e: rusqlite::Error| {
+                    if is_constraint_violation(&e) {
+                        DatabaseError::DuplicateEntry(uuid)
+                    } else {
+                        e.into()
+                    }
+                }
+            };
+            for (entry, versions) in &snapshot.entries {
+                insert_entry_row(&tx, entry).map_err(duplicate(entry.uuid))?;
+                for version in versions {
+                    insert_version(&tx, entry.uuid, version).map_err(duplicate(entry.uuid))?;
+                }
+            }
+            for webhook in &snapshot.webhooks {
+                insert_webhook_row(&tx, webhook).map_err(duplicate(webhook.id))?;
+            }
+            for delivery in &snapshot.deliveries {
+                insert_delivery_row(&tx, delivery)?;
+            }
+            for import in &snapshot.imports {
+                insert_import_row(&tx, import).map_err(duplicate(import.uuid))?;
+            }
+            for download in &snapshot.downloads {
+                insert_download_row(&tx, download)?;
+            }
+            // Records keep their ids so exported logs and paging cursors still line up after a restore
+            for record in &snapshot.audit {
+                insert_audit_row(&tx, record, true)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn migrate(&self) -> Result<Vec<u32>, DatabaseError> {
+        self.with_conn(|conn| {
+            conn.execute(
+                r#"
+                CREATE TABLE IF NOT EXISTS schema_version (
+                    version INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    applied_at TEXT NOT NULL
+                );
+                "#,
+                [],
+            )?;
+
+            let current: u32 = conn.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+                [],
+                |row| row.get(0),
+            )?;
+            let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+            if current > latest {
+                return Err(DatabaseError::Backend(format!(
+                    "database schema version {} is newer than the latest known version {}",
+                    current, latest
+                )));
+            }
+
+            let mut applied = Vec::new();
+            for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+                // Each migration commits together with its version row, so a failure leaves
+                // the database at the previous version rather than half-migrated
+                let tx = conn.unchecked_transaction()?;
+                tx.execute_batch(migration.sql)?;
+                tx.execute(
+                    "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+                    params![migration.version, migration.name, Utc::now().to_rfc3339()],
+                )?;
+                tx.commit()?;
+                applied.push(migration.version);
+            }
+
+            Ok(applied)
+        })
+        .await
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+        self.with_conn(|conn| {
+            let has_table: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+                [],
+                |row| row.get(0),
+            )?;
+
+            let mut applied = std::collections::HashMap::new();
+            if has_table {
+                let mut stmt = conn.prepare("SELECT version, applied_at FROM schema_version")?;
+                let rows = stmt.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)))?;
+                for row in rows {
+                    let (version, applied_at) = row?;
+                    let applied_at = DateTime::parse_from_rfc3339(&applied_at)
+                        .map_err(|e| DatabaseError::Backend(format!(
+                            "invalid applied_at for migration {}: {}", version, e
+                        )))?
+                        .with_timezone(&Utc);
+                    applied.insert(version, applied_at);
+                }
+            }
+
+            Ok(MIGRATIONS
+                .iter()
+                .map(|m| MigrationStatus {
+                    version: m.version,
+                    name: m.name.to_string(),
+                    applied_at: applied.get(&m.version).copied(),
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn close(&self) -> Result<(), DatabaseError> {
+        // Fold the write-ahead log back into the main file so only the .db file is left behind
+        self.with_conn(|conn| {
+            conn.execute_batch("PRAGMA optimize; PRAGMA wal_checkpoint(TRUNCATE);")?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_sqlite_database_conformance() {
+        let path = std::env::temp_dir().join(format!("lfs-db-{}.db", Uuid::new_v4()));
+        let db = migrated_database(&path).await;
+
+        crate::conformance::database_suite(&db).await;
+
+        drop(db);
+        remove_database_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_corrupt_rows_do_not_panic() {
+        let path = std::env::temp_dir().join(format!("lfs-db-{}.db", Uuid::new_v4()));
+        let db = migrated_database(&path).await;
+        let uuid = Uuid::new_v4();
+
+        db.pool.get().unwrap().execute(
+            "INSERT INTO entries (uuid, file_name, file_size, source_ip, timestamp) VALUES (?, 'a', -1, 'x', 'yesterday')",
+            params![uuid.to_string()],
+        ).unwrap();
+        match db.get_entry(uuid).await {
+            Err(DatabaseError::CorruptEntry { .. }) => (),
+            _ => panic!("expected a corrupt entry error"),
         }
+
+        db.pool.get().unwrap().execute(
+            "INSERT INTO entries (uuid, file_name, file_size, source_ip, timestamp) VALUES ('not-a-uuid', 'b', 1, 'x', '2024-01-01T00:00:00Z')",
+            [],
+        ).unwrap();
+        assert!(matches!(db.list_uuids().await, Err(DatabaseError::CorruptEntry { .. })));
+
+        drop(db);
+        remove_database_files(&path);
     }
 
-    async fn insert_entry(&self, input: Entry) -> Result<Uuid, Box<dyn Error>> {
-        let conn = self.pool.get()?;
+    #[tokio::test]
+    async fn test_sqlite_migrations() {
+        let path = std::env::temp_dir().join(format!("lfs-db-{}.db", Uuid::new_v4()));
+        let db = SqliteDatabase::new(path.to_str().unwrap()).await.unwrap();
 
+        let status = db.migration_status().await.unwrap();
+        assert_eq!(status.len(), MIGRATIONS.len());
+        assert!(status.iter().all(|m| m.applied_at.is_none()), "fresh database has applied migrations");
+
+        let applied = db.migrate().await.unwrap();
+        assert_eq!(applied, MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>());
+        assert!(db.migration_status().await.unwrap().iter().all(|m| m.applied_at.is_some()));
+
+        // Re-running is a no-op
+        assert!(db.migrate().await.unwrap().is_empty());
+
+        drop(db);
+        remove_database_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_migrates_legacy_database() {
+        let path = std::env::temp_dir().join(format!("lfs-db-{}.db", Uuid::new_v4()));
+        let uuid = Uuid::new_v4();
+
+        // A database created before schema_version existed
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE entries (uuid TEXT PRIMARY KEY, file_name TEXT NOT NULL, file_size INTEGER NOT NULL, source_ip TEXT NOT NULL, timestamp TEXT NOT NULL);",
+        ).unwrap();
         conn.execute(
-            "INSERT INTO entries (uuid, file_name, file_size, source_ip, timestamp) VALUES (?, ?, ?, ?, ?)",
-            params![
-                input.uuid.to_string(),
-                input.file_name,
-                input.file_size,
-                input.source_ip,
-                input.timestamp.to_rfc3339()
-            ],
-        )?;
-
-        Ok(input.uuid)
-    }
-
-    async fn delete_entry(&self, uuid: Uuid) -> Result<bool, Box<dyn Error>> {
-        let conn = self.pool.get()?;
-        let affected = conn.execute(
-            "DELETE FROM entries WHERE uuid = ?",
+            "INSERT INTO entries VALUES (?, 'old.txt', 3, '10.0.0.1', '2024-01-01T00:00:00+00:00')",
             params![uuid.to_string()],
-        )?;
+        ).unwrap();
+        drop(conn);
+
+        let db = SqliteDatabase::new(path.to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+        let entry = db.get_entry(uuid).await.unwrap().expect("legacy entry lost during migration");
+        assert_eq!(entry.file_name, "old.txt");
+
+        drop(db);
+        remove_database_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_uses_wal_mode() {
+        let path = std::env::temp_dir().join(format!("lfs-db-{}.db", Uuid::new_v4()));
+        let db = migrated_database(&path).await;
+
+        let mode: String = db
+            .with_conn(|conn| Ok(conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(mode.to_lowercase(), "wal");
 
-        Ok(affected > 0)
+        drop(db);
+        remove_database_files(&path);
+    }
+
+    /// Measures concurrent request throughput against a populated database.
+    ///
+    /// Run with `cargo test --release bench_sqlite_concurrent_throughput -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore]
+    async fn bench_sqlite_concurrent_throughput() {
+        const ENTRIES: usize = 1_000;
+        const TASKS: usize = 64;
+        const REQUESTS_PER_TASK: usize = 500;
+
+        let path = std::env::temp_dir().join(format!("lfs-bench-{}.db", Uuid::new_v4()));
+        let db = std::sync::Arc::new(migrated_database(&path).await);
+
+        let mut uuids = Vec::with_capacity(ENTRIES);
+        for i in 0..ENTRIES {
+            let entry = Entry {
+                uuid: Uuid::new_v4(),
+                file_name: format!("file-{}.bin", i),
+                file_size: i as u64,
+                source_ip: "127.0.0.1".to_string(),
+                timestamp: Utc::now(),
+                deleted_at: None,
+                version: 1,
+                sha256: None,
+                mime_type: "application/octet-stream".to_string(),
+            };
+            uuids.push(db.insert_entry(entry).await.unwrap());
+        }
+        let uuids = std::sync::Arc::new(uuids);
+
+        // Mixed load: mostly lookups, with a write every tenth request
+        let start = std::time::Instant::now();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|t| {
+                let db = db.clone();
+                let uuids = uuids.clone();
+                tokio::spawn(async move {
+                    for i in 0..REQUESTS_PER_TASK {
+                        if i % 10 == 0 {
+                            let entry = Entry {
+                                uuid: Uuid::new_v4(),
+                                file_name: format!("task-{}-{}.bin", t, i),
+                                file_size: 1,
+                                source_ip: "127.0.0.1".to_string(),
+                                timestamp: Utc::now(),
+                                deleted_at: None,
+                                version: 1,
+                                sha256: None,
+                                mime_type: "application/octet-stream".to_string(),
+                            };
+                            db.insert_entry(entry).await.unwrap();
+                        } else {
+                            let uuid = uuids[(t * REQUESTS_PER_TASK + i) % uuids.len()];
+                            assert!(db.get_entry(uuid).await.unwrap().is_some());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        let total = TASKS * REQUESTS_PER_TASK;
+        println!(
+            "{} requests from {} concurrent tasks in {:.2?} ({:.0} req/s)",
+            total,
+            TASKS,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+
+        drop(db);
+        remove_database_files(&path);
+    }
+
+    async fn migrated_database(path: &std::path::Path) -> SqliteDatabase {
+        let db = SqliteDatabase::new(path.to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    /// Removes the database file along with any WAL side files
+    fn remove_database_files(path: &std::path::Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
     }
 }
@@ -0,0 +1,98 @@
+use crate::database::Database;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// One response that served a stored file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Download {
+    pub uuid: Uuid,
+    pub version: u32,
+    /// Bytes sent, which is less than the file for range requests
+    pub bytes: u64,
+    /// Only recorded when `stats.record_client_ip` is set, in the form `privacy.ip_mode` allows
+    pub client_ip: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Offset of the first byte sent. Only responses starting at 0 count as downloads, so the
+    /// further ranges a player or download manager fetches only add to the bytes served
+    #[serde(default)]
+    pub first_byte: u64,
+}
+
+/// How often and how recently an entry's files were downloaded
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DownloadStats {
+    pub uuid: Uuid,
+    pub downloads: u64,
+    pub served_bytes: u64,
+    /// Time of the most recent download, if there was one
+    pub last_accessed: Option<DateTime<Utc>>,
+}
+
+/// Uploads and downloads on one UTC day
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyVolume {
+    pub day: NaiveDate,
+    /// New entries and new versions
+    pub uploads: u64,
+    pub uploaded_bytes: u64,
+    pub downloads: u64,
+    pub downloaded_bytes: u64,
+}
+
+/// Offset of the first byte a response sends, taken from its `Content-Range`; 0 for whole files
+pub fn first_byte<B>(response: &HttpResponse<B>) -> u64 {
+    response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Records a download without holding up the response; a failure only costs the statistic
+pub fn record_download(db: &Arc<Box<dyn Database>>, download: Download) {
+    let db = Arc::clone(db);
+    tokio::spawn(async move {
+        if let Err(e) = db.record_download(download).await {
+            tracing::warn!(error = %e, "failed to record download");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_files::NamedFile;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn test_only_ranges_from_the_start_count_as_downloads() {
+        let path = std::env::temp_dir().join(format!("lfs-range-{}", Uuid::new_v4()));
+        std::fs::write(&path, vec![7u8; 1000]).unwrap();
+        let serve = |range: Option<&str>| {
+            let mut req = TestRequest::get();
+            if let Some(range) = range {
+                req = req.insert_header((header::RANGE, range));
+            }
+            NamedFile::open(&path).unwrap().into_response(&req.to_http_request())
+        };
+
+        let whole = serve(None);
+        assert_eq!((whole.status(), first_byte(&whole)), (StatusCode::OK, 0));
+        let head = serve(Some("bytes=0-99"));
+        assert_eq!((head.status(), first_byte(&head)), (StatusCode::PARTIAL_CONTENT, 0));
+        let rest = serve(Some("bytes=100-"));
+        assert_eq!((rest.status(), first_byte(&rest)), (StatusCode::PARTIAL_CONTENT, 100));
+        let tail = serve(Some("bytes=-10"));
+        assert_eq!((tail.status(), first_byte(&tail)), (StatusCode::PARTIAL_CONTENT, 990));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
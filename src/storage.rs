@@ -2,19 +2,66 @@ use async_trait::async_trait;
 use tokio_stream::Stream;
 use bytes::Bytes;
 use std::error::Error;
+use std::fmt;
 use std::pin::Pin;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use crate::config::StorageConfig;
+use crate::local_storage::LocalStorage;
+
+/// Storage-level failures shared by all backends
+#[derive(Debug)]
+pub enum StorageError {
+    /// The incoming data stream failed before it was fully written
+    Stream(io::Error),
+    /// The backend itself failed to read, write or remove data
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Stream(e) => write!(f, "upload stream failed: {}", e),
+            StorageError::Io(e) => write!(f, "storage I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Stream(e) | StorageError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// File contents as they are read or written
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+/// Size and modification time of a stored file
+#[derive(Clone, Copy, Debug)]
+pub struct FileInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
 
 pub enum WriteFileResult {
     Success,
-    Failure(io::Error),
+    Failure(StorageError),
 }
 
 pub enum DeleteFileResult {
     Success,
     NotFound,
-    Failure(io::Error),
+    Failure(StorageError),
 }
 
 #[async_trait]
@@ -26,9 +73,30 @@ pub trait Storage: Send + Sync {
     ) -> WriteFileResult;
 
     fn get_file_path(&self, uuid: &str) -> PathBuf;
+
+    /// Streams the contents of a stored file; a missing file is an error of kind `NotFound`
+    async fn read_file(&self, uuid: &str) -> Result<ByteStream, StorageError>;
+
+    /// Describes a stored file without reading it, or `None` if there is no file under `uuid`
+    async fn file_info(&self, uuid: &str) -> Result<Option<FileInfo>, StorageError>;
     
     async fn delete_file(&self, uuid: &str) -> DeleteFileResult;
 
     /// Returns a list of all files in storage by their filename
-    async fn list_files(&self) -> Result<Vec<String>, Box<dyn Error>>;
+    async fn list_files(&self) -> Result<Vec<String>, StorageError>;
+
+    /// Bytes that can still be written before the backend runs out of space
+    async fn available_space(&self) -> Result<u64, StorageError>;
+}
+
+/// Opens the configured storage backend
+pub fn init_storage(config: &StorageConfig) -> Result<Arc<Box<dyn Storage>>, StorageError> {
+    let path = config
+        .path
+        .clone()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no storage path configured"))?;
+    match config.kind.as_str() {
+        "local" => Ok(Arc::new(Box::new(LocalStorage::new(path)))),
+        other => Err(io::Error::new(io::ErrorKind::Unsupported, format!("unsupported storage type {}", other)).into()),
+    }
 }
@@ -0,0 +1,233 @@
+use crate::entry::{blob_key, Entry};
+use crate::storage::{Storage, StorageError, WriteFileResult};
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use image::{ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Cursor;
+use std::sync::Arc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Thumbnail edge lengths offered when THUMBNAIL_SIZES is not set
+pub const DEFAULT_SIZES: &[u32] = &[128, 256, 512];
+
+/// Source images larger than this are never decoded
+const MAX_SOURCE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Upper bound on memory the decoder may allocate, guarding against decompression bombs
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+
+/// Formats that can be decoded into thumbnails
+const SUPPORTED_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Thumbnail sizes the server generates and serves
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThumbnailConfig {
+    /// Edge lengths in pixels, each between 16 and 2048
+    pub sizes: Vec<u32>,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig { sizes: DEFAULT_SIZES.to_vec() }
+    }
+}
+
+impl ThumbnailConfig {
+    /// Size served when a request does not ask for one: 256 if offered, otherwise the largest
+    pub fn default_size(&self) -> u32 {
+        if self.sizes.contains(&256) {
+            256
+        } else {
+            self.sizes.iter().copied().max().unwrap_or(256)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    /// The entry is not an image type that can be decoded
+    Unsupported(String),
+    /// The source image exceeds the decoding limits
+    TooLarge,
+    /// The source claims a supported type but could not be decoded
+    Decode(image::ImageError),
+    Storage(StorageError),
+}
+
+impl fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThumbnailError::Unsupported(mime) => write!(f, "no thumbnails for files of type {}", mime),
+            ThumbnailError::TooLarge => write!(f, "image is too large to thumbnail"),
+            ThumbnailError::Decode(e) => write!(f, "image could not be decoded: {}", e),
+            ThumbnailError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ThumbnailError {}
+
+/// Whether thumbnails can be generated for files of this MIME type
+pub fn is_supported(mime_type: &str) -> bool {
+    SUPPORTED_TYPES.contains(&mime_type)
+}
+
+/// Storage key of a cached thumbnail; tied to the version so new uploads never serve stale previews
+pub fn thumbnail_key(uuid: Uuid, version: u32, size: u32) -> String {
+    format!("{}.thumb{}", blob_key(uuid, version), size)
+}
+
+/// Reads the whole file stored under `key`, giving up as soon as it exceeds `limit` bytes
+async fn read_capped(storage: &dyn Storage, key: &str, limit: u64) -> Result<Vec<u8>, ThumbnailError> {
+    let mut data = storage.read_file(key).await.map_err(ThumbnailError::Storage)?;
+    let mut contents = Vec::new();
+    while let Some(chunk) = data.try_next().await.map_err(|e| ThumbnailError::Storage(StorageError::Io(e)))? {
+        if (contents.len() + chunk.len()) as u64 > limit {
+            return Err(ThumbnailError::TooLarge);
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    Ok(contents)
+}
+
+/// Decodes the image in `source` and encodes a PNG fitting within `size`×`size`.
+///
+/// Blocking; run it off the async workers.
+fn render(source: Vec<u8>, size: u32) -> Result<Vec<u8>, ThumbnailError> {
+    let mut reader = ImageReader::new(Cursor::new(source))
+        .with_guessed_format()
+        .map_err(|e| ThumbnailError::Storage(StorageError::Io(e)))?;
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => ThumbnailError::TooLarge,
+        e => ThumbnailError::Decode(e),
+    })?;
+
+    // Never upscale images that already fit
+    let thumbnail = if image.width() <= size && image.height() <= size {
+        image
+    } else {
+        image.thumbnail(size, size)
+    };
+
+    let mut png = Vec::new();
+    thumbnail
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(ThumbnailError::Decode)?;
+    Ok(png)
+}
+
+/// Returns the PNG thumbnail of an entry's current version, generating and caching it if needed
+pub async fn get_or_create(storage: &dyn Storage, entry: &Entry, size: u32) -> Result<Vec<u8>, ThumbnailError> {
+    if !is_supported(&entry.mime_type) {
+        return Err(ThumbnailError::Unsupported(entry.mime_type.clone()));
+    }
+
+    let key = thumbnail_key(entry.uuid, entry.version, size);
+    if let Ok(cached) = read_capped(storage, &key, MAX_SOURCE_BYTES).await {
+        // Writes are not atomic, so a thumbnail read while it was being written is a cache miss
+        let (cached, intact) = tokio::task::spawn_blocking(move || {
+            let intact = image::load_from_memory_with_format(&cached, ImageFormat::Png).is_ok();
+            (cached, intact)
+        })
+        .await
+        .map_err(|e| ThumbnailError::Storage(StorageError::Io(std::io::Error::other(e))))?;
+        if intact {
+            return Ok(cached);
+        }
+    }
+
+    let source = read_capped(storage, &entry.blob_key(), MAX_SOURCE_BYTES).await?;
+    let png = tokio::task::spawn_blocking(move || render(source, size))
+        .await
+        .map_err(|e| ThumbnailError::Storage(StorageError::Io(std::io::Error::other(e))))??;
+
+    let data = Box::pin(tokio_stream::iter(vec![Ok(Bytes::from(png.clone()))]));
+    if let WriteFileResult::Failure(e) = storage.write_file(&key, data).await {
+        return Err(ThumbnailError::Storage(e));
+    }
+
+    Ok(png)
+}
+
+/// Pre-generates every configured thumbnail for a freshly uploaded image in the background
+pub fn spawn_generate(storage: Arc<Box<dyn Storage>>, entry: Entry, config: ThumbnailConfig) {
+    if !is_supported(&entry.mime_type) {
+        return;
+    }
+
+    let generate = async move {
+        for size in config.sizes {
+            if let Err(e) = get_or_create(&**storage, &entry, size).await {
+                tracing::warn!(uuid = %entry.uuid, size, error = %e, "failed to generate thumbnail");
+                return;
+            }
+        }
+    };
+    // Keep the uploading request's span so failures can be traced back to it
+    tokio::spawn(generate.in_current_span());
+}
+
+/// Removes every cached thumbnail of one version, including sizes no longer configured
+pub async fn delete_thumbnails(storage: &dyn Storage, uuid: Uuid, version: u32) {
+    let prefix = format!("{}.thumb", blob_key(uuid, version));
+    let Ok(keys) = storage.list_files().await else {
+        return;
+    };
+    for key in keys.iter().filter(|key| key.starts_with(&prefix)) {
+        let _ = storage.delete_file(key).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::fixtures::{overwrite, MemoryStorage};
+    use crate::conformance::sample_entry;
+    use image::{Rgb, RgbImage};
+
+    #[tokio::test]
+    async fn test_thumbnail_generation_and_cache() {
+        let storage = MemoryStorage::default();
+        let entry = Entry { mime_type: "image/jpeg".to_string(), ..sample_entry("wide.jpg") };
+
+        let source = RgbImage::from_fn(400, 200, |x, _| Rgb([(x % 256) as u8, 0, 0]));
+        let mut jpeg = Vec::new();
+        source.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+        overwrite(&storage, &entry.blob_key(), jpeg).await;
+
+        let png = get_or_create(&storage, &entry, 128).await.unwrap();
+        let thumb = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (128, 64), "aspect ratio not preserved");
+        let key = thumbnail_key(entry.uuid, 1, 128);
+        assert!(storage.file_info(&key).await.unwrap().is_some(), "thumbnail not cached");
+
+        // A thumbnail cut short while it was being written is made again
+        overwrite(&storage, &key, b"\x89PNG\r\n").await;
+        assert_eq!(get_or_create(&storage, &entry, 128).await.unwrap(), png);
+
+        // Small images are not upscaled
+        let big = image::load_from_memory(&get_or_create(&storage, &entry, 512).await.unwrap()).unwrap();
+        assert_eq!((big.width(), big.height()), (400, 200));
+
+        let text = Entry { mime_type: "text/plain".to_string(), ..entry.clone() };
+        assert!(matches!(get_or_create(&storage, &text, 128).await, Err(ThumbnailError::Unsupported(_))));
+
+        // A file that claims to be an image but is not one fails cleanly
+        let broken = Entry { uuid: Uuid::new_v4(), ..entry.clone() };
+        overwrite(&storage, &broken.blob_key(), b"not really a jpeg").await;
+        assert!(get_or_create(&storage, &broken, 128).await.is_err());
+
+        // Deleting removes thumbnails of sizes that are no longer configured too
+        overwrite(&storage, &thumbnail_key(entry.uuid, 1, 64), b"stale").await;
+        delete_thumbnails(&storage, entry.uuid, 1).await;
+        assert_eq!(storage.list_files().await.unwrap().len(), 2, "only the two source files remain");
+    }
+}
@@ -0,0 +1,253 @@
+use crate::config::TlsConfig;
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Names a generated self-signed certificate is valid for
+const SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, io::Error),
+    /// The file holds no PEM certificate or private key
+    Pem(PathBuf, rustls_pki_types::pem::Error),
+    /// The key does not match the certificate or uses an unsupported algorithm
+    Rustls(rustls::Error),
+    Generate(rcgen::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            TlsError::Pem(path, e) => write!(f, "{}: {}", path.display(), e),
+            TlsError::Rustls(e) => write!(f, "invalid certificate or key: {}", e),
+            TlsError::Generate(e) => write!(f, "failed to generate self-signed certificate: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Reads a PEM certificate chain and its private key
+pub fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let pem_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| match e {
+            rustls_pki_types::pem::Error::Io(e) => TlsError::Io(path, e),
+            e => TlsError::Pem(path, e),
+        }
+    };
+
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(cert))?;
+    if chain.is_empty() {
+        return Err(TlsError::Pem(cert.to_path_buf(), rustls_pki_types::pem::Error::NoItemsFound));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(pem_error(key))?;
+
+    CertifiedKey::from_der(chain, private_key, &provider()).map_err(TlsError::Rustls)
+}
+
+/// Writes a new self-signed certificate and key to the given paths, creating their directories
+pub fn generate_self_signed(cert: &Path, key: &Path) -> Result<(), TlsError> {
+    let names: Vec<String> = SELF_SIGNED_NAMES.iter().map(|name| name.to_string()).collect();
+    let generated = rcgen::generate_simple_self_signed(names).map_err(TlsError::Generate)?;
+
+    write_pem(cert, &generated.cert.pem(), false)?;
+    write_pem(key, &generated.key_pair.serialize_pem(), true)
+}
+
+fn write_pem(path: &Path, pem: &str, private: bool) -> Result<(), TlsError> {
+    let io_error = |e| TlsError::Io(path.to_path_buf(), e);
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    options.open(path).and_then(|mut file| file.write_all(pem.as_bytes())).map_err(io_error)
+}
+
+/// Serves the current certificate, which can be replaced without restarting the server
+#[derive(Debug)]
+pub struct ReloadableCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    pub fn new(key: CertifiedKey) -> Self {
+        ReloadableCert { current: RwLock::new(Arc::new(key)) }
+    }
+
+    pub fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Loads the configured certificate, generating a self-signed one first if allowed and missing.
+///
+/// Returns the rustls config for the HTTPS listener and the resolver to hand to [`spawn_reload_task`].
+pub fn setup(config: &TlsConfig) -> Result<(ServerConfig, Arc<ReloadableCert>), TlsError> {
+    // `Config::validate` guarantees both paths are set when TLS is enabled
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert.as_path(), key.as_path()),
+        _ => unreachable!("TLS enabled without certificate and key paths"),
+    };
+
+    if config.self_signed && !(cert.exists() && key.exists()) {
+        generate_self_signed(cert, key)?;
+        tracing::warn!(
+            cert = %cert.display(),
+            "generated a self-signed certificate; browsers will warn until it is trusted or replaced"
+        );
+    }
+
+    let resolver = Arc::new(ReloadableCert::new(load_certified_key(cert, key)?));
+    let server_config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Rustls)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    Ok((server_config, resolver))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Watches the certificate and key files and swaps in the new pair whenever either changes.
+///
+/// A pair that fails to load (for instance while only one file has been replaced) is retried on
+/// the next check; the previous certificate keeps being served meanwhile.
+pub fn spawn_reload_task(resolver: Arc<ReloadableCert>, cert: PathBuf, key: PathBuf) {
+    tokio::spawn(async move {
+        let mut loaded = (modified(&cert), modified(&key));
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let current = (modified(&cert), modified(&key));
+            if current == loaded {
+                continue;
+            }
+            match load_certified_key(&cert, &key) {
+                Ok(certified) => {
+                    resolver.replace(certified);
+                    loaded = current;
+                    tracing::info!(cert = %cert.display(), "reloaded TLS certificate");
+                }
+                Err(e) => tracing::warn!(error = %e, "failed to reload TLS certificate; keeping the current one"),
+            }
+        }
+    });
+}
+
+/// Host of a `Host` header value without its port, keeping brackets around IPv6 addresses
+fn host_without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') && (host.starts_with('[') || !host[..colon].contains(':')) => {
+            &host[..colon]
+        }
+        _ => host,
+    }
+}
+
+/// Permanently redirects a plain HTTP request to the same path on the HTTPS port
+pub async fn redirect_to_https(req: HttpRequest, https_port: u16) -> HttpResponse {
+    let info = req.connection_info();
+    let host = host_without_port(info.host());
+    let path = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    HttpResponse::PermanentRedirect().insert_header((header::LOCATION, location)).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    #[test]
+    fn test_self_signed_certificate_loads_and_reloads() {
+        let dir = std::env::temp_dir().join(format!("lfs-tls-{}", uuid::Uuid::new_v4()));
+        let (cert, key) = (dir.join("certs/cert.pem"), dir.join("certs/key.pem"));
+        let config = TlsConfig { cert: Some(cert.clone()), key: Some(key.clone()), self_signed: true, ..Default::default() };
+
+        let (_, resolver) = setup(&config).unwrap();
+        let first = resolver.current.read().unwrap().cert[0].clone();
+
+        // Existing files are reused rather than regenerated
+        let (_, resolver) = setup(&config).unwrap();
+        assert_eq!(resolver.current.read().unwrap().cert[0], first);
+
+        generate_self_signed(&cert, &key).unwrap();
+        resolver.replace(load_certified_key(&cert, &key).unwrap());
+        assert_ne!(resolver.current.read().unwrap().cert[0], first, "certificate not replaced");
+
+        // A key that does not belong to the certificate is rejected
+        let other = dir.join("other");
+        generate_self_signed(&other.join("cert.pem"), &other.join("key.pem")).unwrap();
+        assert!(matches!(load_certified_key(&cert, &other.join("key.pem")), Err(TlsError::Rustls(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_http_requests_redirect_to_https() {
+        let app = init_service(
+            App::new().default_service(web::to(|req: HttpRequest| redirect_to_https(req, 8443))),
+        )
+        .await;
+
+        for (host, expected) in [
+            ("files.lan:8080", "https://files.lan:8443/file/x?inline=1"),
+            ("[fe80::1]:8080", "https://[fe80::1]:8443/file/x?inline=1"),
+            ("files.lan", "https://files.lan:8443/file/x?inline=1"),
+        ] {
+            let request = TestRequest::get()
+                .uri("/file/x?inline=1")
+                .insert_header((header::HOST, host))
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status(), 308);
+            assert_eq!(response.headers().get(header::LOCATION).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::admin::CommandError;
+use crate::database::Database;
+use crate::entry;
+use crate::storage::{Storage, StorageError};
+use crate::upload;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Counters reported while a transfer runs and returned when it finishes
+#[derive(Clone, Debug, Default)]
+pub struct TransferProgress {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    pub copied: u64,
+    /// Files already in the target from an earlier run
+    pub skipped: u64,
+    /// Bytes of copied and skipped files
+    pub done_bytes: u64,
+    /// Entries and webhooks written to the target database once every file was in place
+    pub entries: u64,
+    pub webhooks: u64,
+}
+
+impl TransferProgress {
+    pub fn done_files(&self) -> u64 {
+        self.copied + self.skipped
+    }
+}
+
+/// Size and hex SHA-256 of the file stored under `key`
+async fn checksum(storage: &dyn Storage, key: &str) -> Result<(u64, String), StorageError> {
+    let stored = upload::describe_stream(&mut storage.read_file(key).await?, "").await?;
+    Ok((stored.size, stored.sha256))
+}
+
+fn mismatch(key: &str, reason: impl Into<String>) -> CommandError {
+    CommandError::Mismatch { key: key.to_string(), reason: reason.into() }
+}
+
+/// Copies every stored file and then every database row from one pair of backends to another.
+///
+/// Files come first, each read back from the target and checked against the source and the
+/// checksum recorded for it. Only once all of them are in place is the target database replaced
+/// with a snapshot of the source in a single transaction, so the target never refers to a file it
+/// does not have. Files already in the target with the right size are skipped, which makes an
+/// interrupted transfer resumable and a repeated one cheap; `verify` re-hashes them instead.
+pub async fn transfer(
+    source_db: &dyn Database,
+    source_storage: &dyn Storage,
+    target_db: &dyn Database,
+    target_storage: &Arc<Box<dyn Storage>>,
+    verify: bool,
+    mut on_progress: impl FnMut(&TransferProgress),
+) -> Result<TransferProgress, CommandError> {
+    let snapshot = source_db.snapshot().await?;
+    let versions: Vec<_> = snapshot
+        .entries
+        .iter()
+        .flat_map(|(entry, versions)| versions.iter().map(move |version| (entry::blob_key(entry.uuid, version.version), version)))
+        .collect();
+
+    let mut progress = TransferProgress {
+        total_files: versions.len() as u64,
+        total_bytes: versions.iter().map(|(_, version)| version.file_size).sum(),
+        ..TransferProgress::default()
+    };
+    let mut reported = Instant::now();
+    let storage_error = |key: &str, e: StorageError| mismatch(key, format!("cannot be read: {}", e));
+
+    for (key, version) in &versions {
+        let present = matches!(target_storage.file_info(key).await, Ok(Some(info)) if info.size == version.file_size);
+        let already_copied = if present && verify {
+            let wanted = match &version.sha256 {
+                Some(sha256) => sha256.clone(),
+                None => checksum(source_storage, key).await.map_err(|e| storage_error(key, e))?.1,
+            };
+            checksum(&***target_storage, key).await.map_err(|e| storage_error(key, e))?.1 == wanted
+        } else {
+            present
+        };
+
+        if already_copied {
+            progress.skipped += 1;
+        } else {
+            let mut data = match source_storage.read_file(key).await {
+                Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(mismatch(key, "missing from the source storage; run `lfs fsck`"))
+                }
+                result => result.map_err(|e| storage_error(key, e))?,
+            };
+            let stored = upload::store_stream(&mut data, target_storage, key, "", 0).await?;
+
+            let problem = if stored.size != version.file_size || version.sha256.as_ref().is_some_and(|sha| *sha != stored.sha256) {
+                Some(format!("source does not match the size or SHA-256 recorded for version {}", version.version))
+            } else {
+                match checksum(&***target_storage, key).await {
+                    Ok(copy) if copy == (stored.size, stored.sha256) => None,
+                    Ok(_) => Some("copy read back from the target differs from the source".to_string()),
+                    Err(e) => Some(format!("copy cannot be read back from the target: {}", e)),
+                }
+            };
+            if let Some(reason) = problem {
+                let _ = target_storage.delete_file(key).await;
+                return Err(mismatch(key, reason));
+            }
+            progress.copied += 1;
+        }
+        progress.done_bytes += version.file_size;
+
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            on_progress(&progress);
+            reported = Instant::now();
+        }
+    }
+
+    progress.entries = snapshot.entries.len() as u64;
+    progress.webhooks = snapshot.webhooks.len() as u64;
+    target_db.load_snapshot(snapshot).await?;
+    on_progress(&progress);
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conformance::fixtures::{add_entry, overwrite, scratch_instance, MemoryStorage};
+    use crate::conformance::read;
+    use crate::entry::FileVersion;
+    use chrono::Utc;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    /// Opens a storage backend in a scratch directory
+    type Backend = fn(&Path) -> Arc<Box<dyn Storage>>;
+
+    async fn check_transfer(source: Backend, target: Backend) {
+        let dir = std::env::temp_dir().join(format!("lfs-transfer-{}", Uuid::new_v4()));
+        let (source_db, _) = scratch_instance(&dir.join("source")).await;
+        let (target_db, _) = scratch_instance(&dir.join("target")).await;
+        let (source, target) = (source(&dir.join("source")), target(&dir.join("target")));
+        let first = add_entry(&source_db, &source, b"first").await;
+        let second = add_entry(&source_db, &source, b"second").await;
+        let run = |verify| transfer(&source_db, &**source, &target_db, &target, verify, |_| {});
+
+        let done = run(false).await.unwrap();
+        assert_eq!((done.copied, done.skipped, done.entries, done.total_bytes), (2, 0, 2, 11));
+        assert_eq!(target_db.get_entry(second.uuid).await.unwrap().unwrap().file_size, 6);
+        assert_eq!(read(&**target, &first.blob_key()).await, b"first");
+
+        // A second run copies only what changed, and picks up rows written in between
+        let third = add_entry(&source_db, &source, b"third").await;
+        source_db.delete_entry(first.uuid).await.unwrap();
+        let again = run(false).await.unwrap();
+        assert_eq!((again.copied, again.skipped), (1, 1));
+        assert!(target_db.get_entry(first.uuid).await.unwrap().is_none());
+        assert!(target_db.get_entry(third.uuid).await.unwrap().is_some());
+
+        // Verifying catches a damaged copy that still has the right size
+        overwrite(&**target, &third.blob_key(), b"THIRD").await;
+        assert_eq!(run(false).await.unwrap().copied, 0);
+        assert_eq!(run(true).await.unwrap().copied, 1);
+        assert_eq!(read(&**target, &third.blob_key()).await, b"third");
+
+        // A source file that no longer matches its record is not copied, and the target keeps its rows
+        let revised = FileVersion { version: 2, file_size: 3, sha256: None, mime_type: "text/plain".to_string(), timestamp: Utc::now() };
+        source_db.add_version(second.uuid, revised).await.unwrap();
+        overwrite(&**source, &entry::blob_key(second.uuid, 2), b"too long").await;
+        assert!(matches!(run(false).await, Err(CommandError::Mismatch { .. })));
+        assert!(target.file_info(&entry::blob_key(second.uuid, 2)).await.unwrap().is_none());
+        assert_eq!(target_db.get_entry(second.uuid).await.unwrap().unwrap().version, 1);
+
+        drop((source_db, target_db));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn local(dir: &Path) -> Arc<Box<dyn Storage>> {
+        Arc::new(Box::new(crate::local_storage::LocalStorage::new(dir.join("storage"))))
+    }
+
+    fn memory(_: &Path) -> Arc<Box<dyn Storage>> {
+        Arc::new(Box::new(MemoryStorage::default()))
+    }
+
+    #[tokio::test]
+    async fn test_transfer_resumes_and_verifies() {
+        check_transfer(local, local).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_between_storage_backends() {
+        check_transfer(local, memory).await;
+        check_transfer(memory, local).await;
+    }
+}
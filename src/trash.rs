@@ -0,0 +1,271 @@
+use crate::audit::{self, AuditRecord};
+use crate::database::Database;
+use crate::entry::{blob_key, Entry};
+use crate::error::ApiError;
+use crate::events::{Event, EventBus};
+use crate::storage::{DeleteFileResult, Storage};
+use crate::metrics::METRICS;
+use crate::thumbnail;
+use crate::webhook::{WebhookDispatcher, WebhookEvent};
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the background task looks for expired trash
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Permanently removes an entry from the database and its file from storage.
+///
+/// Each blob is removed before the rows that point at it, older versions first, so the database
+/// stays the source of truth: if storage fails part way the entry is left in the trash with only
+/// the versions whose files still exist, and purging it again finishes the job.
+pub async fn purge_entry(
+    db: &dyn Database,
+    storage: &dyn Storage,
+    entry: Entry,
+) -> Result<(), ApiError> {
+    let uuid = entry.uuid;
+
+    for version in db.list_versions(uuid).await? {
+        if version.version == entry.version {
+            continue;
+        }
+        // A file already missing from storage is as good as deleted
+        if let DeleteFileResult::Failure(e) = storage.delete_file(&blob_key(uuid, version.version)).await {
+            return Err(e.into());
+        }
+        db.delete_version(uuid, version.version).await?;
+        thumbnail::delete_thumbnails(storage, uuid, version.version).await;
+    }
+
+    if let DeleteFileResult::Failure(e) = storage.delete_file(&entry.blob_key()).await {
+        return Err(e.into());
+    }
+    if !db.delete_entry(uuid).await? {
+        return Err(ApiError::Internal("Database entry not removed".to_string()));
+    }
+    thumbnail::delete_thumbnails(storage, uuid, entry.version).await;
+    METRICS.deletes.with_label_values(&["purge"]).inc();
+
+    Ok(())
+}
+
+/// Purges every trashed entry deleted more than `retention` ago, returning how many were removed.
+///
+/// An entry that cannot be purged is logged and skipped rather than stopping the whole sweep.
+pub async fn purge_expired(
+    db: &dyn Database,
+    storage: &dyn Storage,
+    retention: chrono::Duration,
+    events: &EventBus,
+    webhooks: &WebhookDispatcher,
+) -> Result<usize, ApiError> {
+    let cutoff = Utc::now() - retention;
+    let mut purged = 0;
+
+    for entry in db.list_trash().await? {
+        if entry.deleted_at.is_some_and(|at| at < cutoff) {
+            if let Err(e) = purge_entry(db, storage, entry.clone()).await {
+                // Left in the trash, so the next pass tries again
+                tracing::error!(uuid = %entry.uuid, error = %e, "failed to purge expired entry");
+                continue;
+            }
+            tracing::info!(uuid = %entry.uuid, size = entry.file_size, "expired entry purged");
+            let record = AuditRecord {
+                entry_uuid: Some(entry.uuid),
+                file_name: Some(entry.file_name.clone()),
+                ..AuditRecord::new(audit::SYSTEM_ACTOR, "entry.expire")
+            };
+            audit::append(db, record).await;
+            events.publish(Event::Purged { uuid: entry.uuid });
+            webhooks.notify(WebhookEvent::Expired, &entry);
+            purged += 1;
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Periodically purges expired trash for the lifetime of the server
+pub fn spawn_purge_task(
+    db: Arc<Box<dyn Database>>,
+    storage: Arc<Box<dyn Storage>>,
+    retention: chrono::Duration,
+    events: EventBus,
+    webhooks: WebhookDispatcher,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_expired(&**db, &**storage, retention, &events, &webhooks).await {
+                tracing::error!(error = %e, "failed to purge expired trash");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_storage::LocalStorage;
+    use crate::sqlite_database::SqliteDatabase;
+    use crate::entry::FileVersion;
+    use crate::storage::WriteFileResult;
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_purge_expired_only_removes_old_trash() {
+        let dir = std::env::temp_dir().join(format!("lfs-trash-{}", Uuid::new_v4()));
+        let storage = FailingStorage { inner: LocalStorage::new(dir.join("storage")), fail_key: Default::default() };
+        let db: Arc<Box<dyn Database>> =
+            Arc::new(Box::new(SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap()));
+        db.migrate().await.unwrap();
+
+        let mut uuids = Vec::new();
+        for days_ago in [40, 5, 50] {
+            let entry = Entry {
+                uuid: Uuid::new_v4(),
+                file_name: format!("{}-days.txt", days_ago),
+                file_size: 2,
+                source_ip: "127.0.0.1".to_string(),
+                timestamp: Utc::now(),
+                deleted_at: None,
+                version: 1,
+                sha256: None,
+                mime_type: "text/plain".to_string(),
+            };
+            let data = Box::pin(tokio_stream::iter(vec![Ok(Bytes::from_static(b"hi"))]));
+            assert!(matches!(
+                storage.write_file(&entry.uuid.to_string(), data).await,
+                WriteFileResult::Success
+            ));
+            db.insert_entry(entry.clone()).await.unwrap();
+            db.trash_entry(entry.uuid, Utc::now() - chrono::Duration::days(days_ago)).await.unwrap();
+            uuids.push(entry.uuid);
+        }
+
+        // One expired entry cannot be purged; the other still is
+        *storage.fail_key.lock().unwrap() = Some(uuids[2].to_string());
+
+        let events = EventBus::new();
+        let mut subscriber = events.subscribe();
+        let webhooks = WebhookDispatcher::new(Arc::clone(&db), Default::default());
+        let purged = purge_expired(&**db, &storage, chrono::Duration::days(30), &events, &webhooks)
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(subscriber.try_recv().unwrap(), Event::Purged { uuid: uuids[0] });
+        assert!(db.get_entry(uuids[0]).await.unwrap().is_none());
+        assert!(!storage.get_file_path(&uuids[0].to_string()).exists());
+        assert!(db.get_entry(uuids[1]).await.unwrap().is_some());
+        assert!(storage.get_file_path(&uuids[1].to_string()).exists());
+        assert!(db.list_trash().await.unwrap().iter().any(|entry| entry.uuid == uuids[2]));
+        assert!(subscriber.try_recv().is_err());
+
+        drop((db, webhooks));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Local storage that refuses to delete one chosen key
+    struct FailingStorage {
+        inner: LocalStorage,
+        fail_key: std::sync::Mutex<Option<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for FailingStorage {
+        async fn write_file(&self, uuid: &str, data: crate::storage::ByteStream) -> WriteFileResult {
+            self.inner.write_file(uuid, data).await
+        }
+
+        fn get_file_path(&self, uuid: &str) -> std::path::PathBuf {
+            self.inner.get_file_path(uuid)
+        }
+
+        async fn read_file(&self, uuid: &str) -> Result<crate::storage::ByteStream, crate::storage::StorageError> {
+            self.inner.read_file(uuid).await
+        }
+
+        async fn file_info(
+            &self,
+            uuid: &str,
+        ) -> Result<Option<crate::storage::FileInfo>, crate::storage::StorageError> {
+            self.inner.file_info(uuid).await
+        }
+
+        async fn delete_file(&self, uuid: &str) -> DeleteFileResult {
+            if self.fail_key.lock().unwrap().as_deref() == Some(uuid) {
+                return DeleteFileResult::Failure(std::io::Error::other("injected failure").into());
+            }
+            self.inner.delete_file(uuid).await
+        }
+
+        async fn list_files(&self) -> Result<Vec<String>, crate::storage::StorageError> {
+            self.inner.list_files().await
+        }
+
+        async fn available_space(&self) -> Result<u64, crate::storage::StorageError> {
+            self.inner.available_space().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_storage_failure_keeps_entry_consistent() {
+        let dir = std::env::temp_dir().join(format!("lfs-trash-{}", Uuid::new_v4()));
+        let storage = FailingStorage { inner: LocalStorage::new(dir.join("storage")), fail_key: Default::default() };
+        let db = SqliteDatabase::new(dir.join("db.sqlite").to_str().unwrap()).await.unwrap();
+        db.migrate().await.unwrap();
+
+        let uuid = Uuid::new_v4();
+        for version in 1..=3 {
+            let data = Box::pin(tokio_stream::iter(vec![Ok(Bytes::from_static(b"hi"))]));
+            assert!(matches!(storage.write_file(&blob_key(uuid, version), data).await, WriteFileResult::Success));
+            let file = FileVersion {
+                version,
+                file_size: 2,
+                sha256: None,
+                mime_type: "text/plain".to_string(),
+                timestamp: Utc::now(),
+            };
+            if version == 1 {
+                db.insert_entry(Entry {
+                    uuid,
+                    file_name: "notes.txt".to_string(),
+                    file_size: 2,
+                    source_ip: "127.0.0.1".to_string(),
+                    timestamp: file.timestamp,
+                    deleted_at: None,
+                    version: 1,
+                    sha256: None,
+                    mime_type: file.mime_type,
+                })
+                .await
+                .unwrap();
+            } else {
+                assert!(db.add_version(uuid, file).await.unwrap());
+            }
+        }
+        let entry = db.get_entry(uuid).await.unwrap().unwrap();
+
+        // Storage fails part way through the older versions
+        *storage.fail_key.lock().unwrap() = Some(blob_key(uuid, 2));
+        assert!(matches!(purge_entry(&db, &storage, entry.clone()).await, Err(ApiError::Storage(_))));
+        let versions: Vec<u32> = db.list_versions(uuid).await.unwrap().iter().map(|v| v.version).collect();
+        assert_eq!(versions, vec![2, 3], "only versions whose files are gone may lose their rows");
+        assert_eq!(db.get_entry(uuid).await.unwrap().unwrap().version, 3);
+        assert!(!storage.get_file_path(&blob_key(uuid, 1)).exists());
+        assert!(storage.get_file_path(&blob_key(uuid, 2)).exists());
+        assert!(storage.get_file_path(&entry.blob_key()).exists());
+
+        // Purging again finishes the job
+        *storage.fail_key.lock().unwrap() = None;
+        purge_entry(&db, &storage, entry.clone()).await.unwrap();
+        assert!(db.get_entry(uuid).await.unwrap().is_none());
+        assert!(storage.list_files().await.unwrap().is_empty());
+
+        drop(db);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
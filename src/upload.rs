@@ -0,0 +1,291 @@
+use crate::content_type;
+use crate::error::ApiError;
+use crate::shutdown::Shutdown;
+use crate::storage::{Storage, StorageError, WriteFileResult};
+use actix_multipart::Field;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Size and checksum of a file that was streamed into storage
+pub struct StoredFile {
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+    /// MIME type sniffed from the leading bytes, falling back to the file name
+    pub mime_type: String,
+}
+
+/// Size, SHA-256 and leading bytes of a file, taken chunk by chunk as it passes through
+struct Fingerprint {
+    hasher: Sha256,
+    size: u64,
+    head: Vec<u8>,
+}
+
+impl Fingerprint {
+    fn new() -> Self {
+        Fingerprint { hasher: Sha256::new(), size: 0, head: Vec::with_capacity(content_type::SNIFF_LEN) }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        if self.head.len() < content_type::SNIFF_LEN {
+            let take = chunk.len().min(content_type::SNIFF_LEN - self.head.len());
+            self.head.extend_from_slice(&chunk[..take]);
+        }
+    }
+
+    fn finish(self, file_name: &str) -> StoredFile {
+        StoredFile {
+            size: self.size,
+            sha256: hex::encode(self.hasher.finalize()),
+            mime_type: content_type::detect(&self.head, file_name),
+        }
+    }
+}
+
+/// Measures, hashes and sniffs everything `data` yields the way [`store_stream`] does, without
+/// storing it
+pub async fn describe_stream<S>(data: &mut S, file_name: &str) -> io::Result<StoredFile>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let mut fingerprint = Fingerprint::new();
+    while let Some(chunk) = data.try_next().await? {
+        fingerprint.update(&chunk);
+    }
+    Ok(fingerprint.finish(file_name))
+}
+
+/// Streams a multipart field into storage under `key`; see [`store_stream`]
+pub async fn store_field(
+    field: &mut Field,
+    storage: &Arc<Box<dyn Storage>>,
+    key: &str,
+    file_name: &str,
+    max_size: u64,
+) -> Result<StoredFile, ApiError> {
+    store_stream(field, storage, key, file_name, max_size).await
+}
+
+/// Streams `data` into storage under `key`, measuring, hashing and sniffing it on the way through.
+///
+/// Uploads larger than `max_size` bytes are rejected (0 means unlimited). Any partial file is
+/// removed again if the upload fails.
+pub async fn store_stream<S, E>(
+    data: &mut S,
+    storage: &Arc<Box<dyn Storage>>,
+    key: &str,
+    file_name: &str,
+    max_size: u64,
+) -> Result<StoredFile, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    let (tx, rx) = mpsc::channel::<Result<Bytes, io::Error>>(1024);
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    let pinned_stream = Box::pin(stream);
+
+    let storage_clone = Arc::clone(storage);
+    let key_for_storage = key.to_string();
+
+    let storage_handle = tokio::spawn(async move {
+        storage_clone.write_file(&key_for_storage, pinned_stream).await
+    });
+
+    let mut fingerprint = Fingerprint::new();
+
+    // Process the incoming data in the current task
+    while let Some(chunk) = data.next().await {
+        match chunk {
+            Ok(data) => {
+                fingerprint.update(&data);
+                if max_size > 0 && fingerprint.size > max_size {
+                    drop(tx);
+                    let _ = storage_handle.await;
+                    let _ = storage.delete_file(key).await;
+                    return Err(ApiError::UploadTooLarge(max_size));
+                }
+                if tx.send(Ok(data)).await.is_err() {
+                    // The writer stopped early; its own error explains why
+                    break;
+                }
+            }
+            Err(e) => {
+                let io_error = io::Error::other(format!("Upload stream error: {}", e));
+                let _ = tx.send(Err(io_error)).await;
+                let _ = storage_handle.await;
+                let _ = storage.delete_file(key).await;
+                return Err(ApiError::Storage(StorageError::Stream(io::Error::other(e.to_string()))));
+            }
+        }
+    }
+
+    drop(tx);
+
+    match storage_handle.await {
+        Ok(WriteFileResult::Success) => Ok(fingerprint.finish(file_name)),
+        Ok(WriteFileResult::Failure(e)) => {
+            // Clean up any partial file if storage write fails
+            let _ = storage.delete_file(key).await;
+            Err(e.into())
+        }
+        Err(e) => {
+            // Clean up any partial file if task fails
+            let _ = storage.delete_file(key).await;
+            Err(ApiError::Internal(format!("Task error: {}", e)))
+        }
+    }
+}
+
+/// Entries that currently have a new version being uploaded.
+///
+/// Version numbers are picked before the upload starts, so two concurrent revisions of the
+/// same entry would race for the same storage key; the second one is rejected instead.
+#[derive(Default)]
+pub struct RevisionLocks {
+    active: Mutex<HashSet<Uuid>>,
+}
+
+/// Releases the entry's revision lock when dropped
+pub struct RevisionGuard<'a> {
+    locks: &'a RevisionLocks,
+    uuid: Uuid,
+}
+
+impl RevisionLocks {
+    /// Claims the entry for a revision upload, or returns `None` if one is already running
+    pub fn try_lock(&self, uuid: Uuid) -> Option<RevisionGuard<'_>> {
+        let mut active = self.active.lock().unwrap();
+        if active.insert(uuid) {
+            Some(RevisionGuard { locks: self, uuid })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for RevisionGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.active.lock().unwrap().remove(&self.uuid);
+    }
+}
+
+/// Storage keys of uploads still being written, so shutdown can wait for them and remove leftovers
+pub struct ActiveUploads {
+    shutdown: Shutdown,
+    keys: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Marks an upload as in progress until dropped.
+///
+/// Dropping it without calling `finish` (the request failed or was cancelled mid-transfer)
+/// deletes whatever was written under the key.
+pub struct UploadGuard {
+    keys: Arc<Mutex<HashSet<String>>>,
+    storage: Arc<Box<dyn Storage>>,
+    key: String,
+    finished: bool,
+}
+
+impl ActiveUploads {
+    pub fn new(shutdown: Shutdown) -> Self {
+        ActiveUploads { shutdown, keys: Arc::default() }
+    }
+
+    /// Registers an upload to `key`, or refuses it once shutdown has begun
+    pub fn begin(&self, key: &str, storage: &Arc<Box<dyn Storage>>) -> Result<UploadGuard, ApiError> {
+        if self.shutdown.is_triggered() {
+            return Err(ApiError::ShuttingDown);
+        }
+        self.keys.lock().unwrap().insert(key.to_string());
+        Ok(UploadGuard { keys: Arc::clone(&self.keys), storage: Arc::clone(storage), key: key.to_string(), finished: false })
+    }
+
+    /// Deletes the files of uploads that never finished, returning how many there were
+    pub async fn remove_unfinished(&self, storage: &dyn Storage) -> usize {
+        let keys: Vec<String> = self.keys.lock().unwrap().drain().collect();
+        for key in &keys {
+            let _ = storage.delete_file(key).await;
+        }
+        keys.len()
+    }
+}
+
+impl UploadGuard {
+    /// The upload was stored and recorded; its file must be kept
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            self.keys.lock().unwrap().remove(&self.key);
+            return;
+        }
+
+        // Without a runtime the key stays registered for `remove_unfinished` to clean up
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (keys, storage, key) = (Arc::clone(&self.keys), Arc::clone(&self.storage), self.key.clone());
+        runtime.spawn(async move {
+            let _ = storage.delete_file(&key).await;
+            keys.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_storage::LocalStorage;
+
+    fn write(storage: &LocalStorage, key: &str) {
+        std::fs::write(storage.get_file_path(key), b"partial").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_uploads_are_removed() {
+        let dir = std::env::temp_dir().join(format!("lfs-uploads-{}", Uuid::new_v4()));
+        let local = LocalStorage::new(dir.clone());
+        let storage: Arc<Box<dyn Storage>> = Arc::new(Box::new(LocalStorage::new(dir.clone())));
+        let shutdown = Shutdown::default();
+        let uploads = ActiveUploads::new(shutdown.clone());
+
+        let kept = uploads.begin("kept", &storage).unwrap();
+        write(&local, "kept");
+        kept.finish();
+
+        // A request dropped mid-transfer cleans up after itself in the background
+        let dropped = uploads.begin("dropped", &storage).unwrap();
+        write(&local, "dropped");
+        drop(dropped);
+        while uploads.keys.lock().unwrap().contains("dropped") {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!local.get_file_path("dropped").exists(), "partial file left behind");
+
+        // Uploads still registered at shutdown are removed, and new ones are refused
+        let _cut_off = uploads.begin("cut-off", &storage).unwrap();
+        write(&local, "cut-off");
+        shutdown.trigger();
+        assert!(matches!(uploads.begin("late", &storage), Err(ApiError::ShuttingDown)));
+        assert_eq!(uploads.remove_unfinished(&**storage).await, 1);
+        assert!(!local.get_file_path("cut-off").exists());
+        assert!(local.get_file_path("kept").exists(), "finished upload removed");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use crate::database::Database;
+use crate::entry::Entry;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Header carrying the hex HMAC-SHA256 of the request body, prefixed with `sha256=`
+pub const SIGNATURE_HEADER: &str = "X-LFS-Signature";
+
+/// How long a receiver has to answer one delivery attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Attempts kept in each webhook's delivery log; older ones are dropped
+pub const DELIVERY_LOG_LEN: usize = 200;
+
+/// Something that happened to an entry that webhooks can subscribe to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "entry.uploaded")]
+    Uploaded,
+    #[serde(rename = "entry.deleted")]
+    Deleted,
+    #[serde(rename = "entry.expired")]
+    Expired,
+    /// Sent by the test-fire endpoint; cannot be subscribed to
+    #[serde(rename = "ping")]
+    Ping,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Uploaded => "entry.uploaded",
+            WebhookEvent::Deleted => "entry.deleted",
+            WebhookEvent::Expired => "entry.expired",
+            WebhookEvent::Ping => "ping",
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "entry.uploaded" => Ok(WebhookEvent::Uploaded),
+            "entry.deleted" => Ok(WebhookEvent::Deleted),
+            "entry.expired" => Ok(WebhookEvent::Expired),
+            "ping" => Ok(WebhookEvent::Ping),
+            other => Err(format!("unknown webhook event '{}'", other)),
+        }
+    }
+}
+
+/// A receiver that is notified of entry events
+#[derive(Clone, Debug, Serialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Key used to sign payloads; only returned when the webhook is created
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The outcome of one attempt to deliver an event to a webhook
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub webhook_id: Uuid,
+    /// Shared by every retry of the same event, and sent to the receiver as the payload `id`
+    pub delivery_id: Uuid,
+    pub event: WebhookEvent,
+    pub entry_uuid: Option<Uuid>,
+    pub attempt: u32,
+    /// HTTP status returned by the receiver; `None` if no response arrived
+    pub status_code: Option<u16>,
+    /// Why the attempt failed, if it did
+    pub error: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl WebhookDelivery {
+    pub fn succeeded(&self) -> bool {
+        self.status_code.is_some_and(|code| (200..300).contains(&code))
+    }
+}
+
+/// JSON body POSTed to receivers
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: Uuid,
+    event: WebhookEvent,
+    timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry: Option<&'a Entry>,
+}
+
+/// How often and how patiently failed deliveries are retried
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubled after every further failure
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_attempts: 5, base_delay: Duration::from_secs(2) }
+    }
+}
+
+/// Signature of `body` under `secret`, in the form sent in `SIGNATURE_HEADER`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Random signing secret for webhooks created without one
+pub fn generate_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Sends signed event payloads to subscribed webhooks and records every attempt
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    db: Arc<Box<dyn Database>>,
+    client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl WebhookDispatcher {
+    pub fn new(db: Arc<Box<dyn Database>>, retry: RetryPolicy) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("HTTP client configuration is valid");
+        WebhookDispatcher { db, client, retry }
+    }
+
+    /// Notifies every webhook subscribed to `event` in the background, retrying failures.
+    ///
+    /// The returned handle completes once every delivery has succeeded or run out of attempts.
+    pub fn notify(&self, event: WebhookEvent, entry: &Entry) -> tokio::task::JoinHandle<()> {
+        let dispatcher = self.clone();
+        let entry = entry.clone();
+
+        let dispatch = async move {
+            let webhooks = match dispatcher.db.list_webhooks().await {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!(%event, uuid = %entry.uuid, error = %e, "failed to load webhooks");
+                    return;
+                }
+            };
+
+            let deliveries = webhooks
+                .into_iter()
+                .filter(|webhook| webhook.events.contains(&event))
+                .map(|webhook| dispatcher.deliver(webhook, event, Some(&entry)));
+            join_all(deliveries).await;
+        };
+        tokio::spawn(dispatch.in_current_span())
+    }
+
+    /// Sends a single `ping` to the webhook without retrying, returning the recorded attempt
+    pub async fn test_fire(&self, webhook: &Webhook) -> WebhookDelivery {
+        let delivery_id = Uuid::new_v4();
+        let body = payload(delivery_id, WebhookEvent::Ping, None);
+        let delivery = self.attempt(webhook, delivery_id, WebhookEvent::Ping, None, body, 1).await;
+        self.record(&delivery).await;
+        delivery
+    }
+
+    /// Delivers one event, backing off exponentially between failed attempts
+    async fn deliver(&self, webhook: Webhook, event: WebhookEvent, entry: Option<&Entry>) {
+        let delivery_id = Uuid::new_v4();
+        let body = payload(delivery_id, event, entry);
+        let entry_uuid = entry.map(|entry| entry.uuid);
+        let mut delay = self.retry.base_delay;
+
+        for attempt in 1..=self.retry.max_attempts {
+            let delivery = self.attempt(&webhook, delivery_id, event, entry_uuid, body.clone(), attempt).await;
+            self.record(&delivery).await;
+            if delivery.succeeded() {
+                return;
+            }
+            if attempt < self.retry.max_attempts {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        tracing::warn!(
+            %event,
+            %delivery_id,
+            url = %webhook.url,
+            attempts = self.retry.max_attempts,
+            "giving up on webhook delivery"
+        );
+    }
+
+    async fn attempt(
+        &self,
+        webhook: &Webhook,
+        delivery_id: Uuid,
+        event: WebhookEvent,
+        entry_uuid: Option<Uuid>,
+        body: Vec<u8>,
+        attempt: u32,
+    ) -> WebhookDelivery {
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-LFS-Event", event.as_str())
+            .header("X-LFS-Delivery", delivery_id.to_string())
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, &body))
+            .body(body)
+            .send()
+            .await;
+
+        let (status_code, error) = match response {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("receiver responded with {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        WebhookDelivery {
+            webhook_id: webhook.id,
+            delivery_id,
+            event,
+            entry_uuid,
+            attempt,
+            status_code,
+            error,
+            timestamp: Utc::now(),
+        }
+    }
+
+    async fn record(&self, delivery: &WebhookDelivery) {
+        if let Err(e) = self.db.record_delivery(delivery.clone(), DELIVERY_LOG_LEN).await {
+            tracing::warn!(delivery_id = %delivery.delivery_id, error = %e, "failed to record webhook delivery");
+        }
+    }
+}
+
+fn payload(id: Uuid, event: WebhookEvent, entry: Option<&Entry>) -> Vec<u8> {
+    serde_json::to_vec(&Payload { id, event, timestamp: Utc::now(), entry }).expect("payloads always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_database::SqliteDatabase;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+
+    /// Requests seen by the test receiver: signature header and body
+    type Received = web::Data<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// Fails the first request it gets, then accepts everything
+    async fn receiver(req: HttpRequest, body: web::Bytes, received: Received) -> HttpResponse {
+        let signature = req.headers().get(SIGNATURE_HEADER).unwrap().to_str().unwrap().to_string();
+        let mut received = received.lock().unwrap();
+        received.push((signature, body.to_vec()));
+        if received.len() == 1 {
+            HttpResponse::InternalServerError().finish()
+        } else {
+            HttpResponse::NoContent().finish()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_delivery_signing_and_retries() {
+        let received: Received = web::Data::new(Mutex::new(Vec::new()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app_data = received.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(app_data.clone()).route("/hook", web::post().to(receiver))
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        actix_web::rt::spawn(server);
+
+        let path = std::env::temp_dir().join(format!("lfs-webhook-{}.db", Uuid::new_v4()));
+        let db: Arc<Box<dyn Database>> = Arc::new(Box::new(SqliteDatabase::new(path.to_str().unwrap()).await.unwrap()));
+        db.migrate().await.unwrap();
+
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url,
+            secret: generate_secret(),
+            events: vec![WebhookEvent::Uploaded],
+            created_at: Utc::now(),
+        };
+        db.insert_webhook(webhook.clone()).await.unwrap();
+
+        let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(10) };
+        let dispatcher = WebhookDispatcher::new(Arc::clone(&db), retry);
+        let entry = Entry {
+            uuid: Uuid::new_v4(),
+            file_name: "build.zip".to_string(),
+            file_size: 42,
+            source_ip: "127.0.0.1".to_string(),
+            timestamp: Utc::now(),
+            deleted_at: None,
+            version: 1,
+            sha256: None,
+            mime_type: "application/zip".to_string(),
+        };
+
+        // Unsubscribed events are not sent
+        dispatcher.notify(WebhookEvent::Deleted, &entry).await.unwrap();
+        assert!(received.lock().unwrap().is_empty());
+
+        // The first attempt fails, the retry succeeds with an identical, correctly signed body
+        dispatcher.notify(WebhookEvent::Uploaded, &entry).await.unwrap();
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[0].1, received[1].1, "retries must resend the same payload");
+            let (signature, body) = &received[1];
+            assert_eq!(signature, &sign(&webhook.secret, body));
+            let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(payload["event"], "entry.uploaded");
+            assert_eq!(payload["entry"]["uuid"], entry.uuid.to_string());
+        }
+
+        let log = db.list_deliveries(webhook.id, 10).await.unwrap();
+        assert_eq!(log.iter().map(|d| (d.attempt, d.status_code)).collect::<Vec<_>>(), vec![(2, Some(204)), (1, Some(500))]);
+        assert!(log[0].succeeded() && !log[1].succeeded());
+
+        let ping = dispatcher.test_fire(&webhook).await;
+        assert!(ping.succeeded());
+        assert_eq!(ping.event, WebhookEvent::Ping);
+        assert_eq!(db.list_deliveries(webhook.id, 10).await.unwrap().len(), 3);
+
+        drop((db, dispatcher));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}